@@ -0,0 +1,341 @@
+use anyhow::{Context, Error, Result};
+use aws_lambda_events::dynamodb::{
+    attributes::AttributeValue as StreamAttributeValue, Event, EventRecord, StreamRecord,
+    StreamViewType,
+};
+use aws_sdk_dynamodb::{
+    model::{AttributeValue, KeysAndAttributes},
+    Client, Endpoint, Region,
+};
+use dynamodb_email_indexer::{
+    attribute_helper::AttributeHelper, audit_report::AuditReport, commit_payload::unix_now,
+    email_index_schema::EmailIndexSchema, email_index_writer::EmailIndexWriter,
+    index_partitioning::Partitioning, index_storage::IndexStorage, merge_settings::MergeSettings,
+    writer_request::WriterRequest,
+};
+use log::{info, warn};
+use serde_json::json;
+use std::{collections::HashMap, path::PathBuf, time::Duration};
+use structopt::StructOpt;
+use tantivy::chrono::{DateTime, Utc};
+use ulid::Ulid;
+
+const BATCH_GET_ITEM_SIZE: usize = 100;
+const REPAIR_BATCH_SIZE: usize = 1000;
+const UNPROCESSED_KEYS_BACKOFF: Duration = Duration::from_millis(100);
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "audit")]
+struct Opt {
+    /// DynamoDB table name
+    #[structopt(short, long)]
+    table_name: String,
+
+    /// Index storage directory, defaults to the storage INDEX_STORAGE configures
+    #[structopt(short, long)]
+    index_path: Option<PathBuf>,
+
+    /// DynamoDB endpoint, for example http://localhost:8000 for DynamoDB Local
+    #[structopt(short, long)]
+    endpoint_url: Option<String>,
+
+    /// AWS region
+    #[structopt(short, long)]
+    region: Option<String>,
+
+    /// Indexes the emails the audit finds missing or stale again, and removes the ones the table
+    /// no longer has
+    #[structopt(long)]
+    repair: bool,
+
+    /// Rebuilds the index from every table item into a new generation and makes it current,
+    /// instead of auditing, for example after a schema change
+    #[structopt(long, conflicts_with = "repair")]
+    reindex: bool,
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    std::env::set_var("RUST_LOG", "audit=info");
+    env_logger::init();
+
+    let options = Opt::from_args();
+
+    let mut config_loader = aws_config::from_env();
+    if let Some(region) = options.region {
+        config_loader = config_loader.region(Region::new(region));
+    }
+    let shared_config = config_loader.load().await;
+
+    let mut ddb_config = aws_sdk_dynamodb::config::Builder::from(&shared_config);
+    if let Some(endpoint_url) = &options.endpoint_url {
+        let uri = endpoint_url.parse().context("endpoint url is not valid")?;
+        ddb_config = ddb_config.endpoint_resolver(Endpoint::immutable(uri));
+    }
+    let ddb = Client::from_conf(ddb_config.build());
+
+    let storage = match options.index_path {
+        Some(index_path) => IndexStorage::Path(index_path),
+        None => IndexStorage::from_env(&shared_config)?,
+    };
+    let email_index_schema = EmailIndexSchema::new()
+        .with_partitioning(Partitioning::from_env()?)
+        .with_storage(storage)
+        .with_current_generation()?;
+
+    if options.reindex {
+        // NOTE: Changes made while reindexing only reach the generation in use, so audit with --repair afterwards
+        let generation = unix_now().to_string();
+        let email_index_schema = email_index_schema.with_generation(Some(generation.clone()));
+        let email_index_writer = EmailIndexWriter::new(email_index_schema.clone())?
+            .with_merge_settings(MergeSettings::from_env()?);
+
+        reindex(&ddb, &options.table_name, &email_index_writer).await?;
+        email_index_schema.make_current_generation()?;
+        info!("reindexed into generation {generation}");
+        return Ok(());
+    }
+
+    let table_timestamps = scan_table(&ddb, &options.table_name).await?;
+    let index_schema = email_index_schema.clone();
+    let index_timestamps =
+        tokio::task::spawn_blocking(move || AuditReport::index_timestamps(&index_schema)).await??;
+    let report = AuditReport::new(&table_timestamps, &index_timestamps);
+
+    info!(
+        "audit report:\n{}",
+        serde_json::to_string_pretty(&json!(&report))?
+    );
+
+    if options.repair && !report.is_clean() {
+        let email_index_writer = EmailIndexWriter::new(email_index_schema)?
+            .with_merge_settings(MergeSettings::from_env()?);
+        repair(&ddb, &options.table_name, &report, &email_index_writer).await?;
+    }
+
+    Ok(())
+}
+
+async fn scan_table(ddb: &Client, table_name: &str) -> Result<HashMap<String, Option<i64>>> {
+    let mut timestamps: HashMap<String, Option<i64>> = HashMap::new();
+    let mut exclusive_start_key: Option<HashMap<String, AttributeValue>> = None;
+
+    loop {
+        // NOTE: timestamp is a reserved word
+        let response = ddb
+            .scan()
+            .table_name(table_name)
+            .projection_expression("#id, #timestamp")
+            .expression_attribute_names("#id", "id")
+            .expression_attribute_names("#timestamp", "timestamp")
+            .set_exclusive_start_key(exclusive_start_key)
+            .send()
+            .await?;
+
+        for attributes in response.items().unwrap_or_default() {
+            let id = AttributeHelper::parse_string(attributes, "id")?;
+            let timestamp = AttributeHelper::parse_optional_int_64(attributes, "timestamp")?;
+            timestamps.insert(id, timestamp);
+        }
+
+        info!("scanned {} table items", timestamps.len());
+
+        exclusive_start_key = response.last_evaluated_key().cloned();
+        if exclusive_start_key.is_none() {
+            break;
+        }
+    }
+
+    Ok(timestamps)
+}
+
+async fn repair(
+    ddb: &Client,
+    table_name: &str,
+    report: &AuditReport,
+    email_index_writer: &EmailIndexWriter,
+) -> Result<()> {
+    // NOTE: Items missing from the index or with a stale timestamp are re-indexed from the table
+    for batch in report.stale_ids().chunks(BATCH_GET_ITEM_SIZE) {
+        let items = batch_get_items(ddb, table_name, batch).await?;
+        let read_at = Utc::now();
+        let records = items
+            .iter()
+            .map(|attributes| repair_record("MODIFY", convert(attributes), HashMap::new(), read_at))
+            .collect();
+        write(email_index_writer, records).await?;
+    }
+
+    // NOTE: Documents whose item is gone only need the id to be removed
+    let read_at = Utc::now();
+    let records: Vec<EventRecord> = report
+        .missing_from_table
+        .iter()
+        .map(|id| {
+            let old_image =
+                HashMap::from([("id".to_string(), StreamAttributeValue::String(id.clone()))]);
+            repair_record("REMOVE", HashMap::new(), old_image, read_at)
+        })
+        .collect();
+    for batch in records.chunks(REPAIR_BATCH_SIZE) {
+        write(email_index_writer, batch.to_vec()).await?;
+    }
+
+    Ok(())
+}
+
+async fn batch_get_items(
+    ddb: &Client,
+    table_name: &str,
+    ids: &[String],
+) -> Result<Vec<HashMap<String, AttributeValue>>> {
+    let keys: Vec<HashMap<String, AttributeValue>> = ids
+        .iter()
+        .map(|id| HashMap::from([("id".to_owned(), AttributeValue::S(id.to_owned()))]))
+        .collect();
+    let mut request_items = HashMap::from([(
+        table_name.to_string(),
+        KeysAndAttributes::builder().set_keys(Some(keys)).build(),
+    )]);
+    let mut items: Vec<HashMap<String, AttributeValue>> = vec![];
+    let mut backoff = UNPROCESSED_KEYS_BACKOFF;
+
+    loop {
+        let response = ddb
+            .batch_get_item()
+            .set_request_items(Some(request_items))
+            .send()
+            .await?;
+
+        if let Some(responses) = response.responses() {
+            items.extend(responses.get(table_name).cloned().unwrap_or_default());
+        }
+
+        // NOTE: DynamoDB hands back the keys it didn't get to when throttled or over the response size limit
+        request_items = response.unprocessed_keys().cloned().unwrap_or_default();
+        if request_items.is_empty() {
+            break;
+        }
+
+        warn!("retrying unprocessed keys in {backoff:?}");
+        tokio::time::sleep(backoff).await;
+        backoff *= 2;
+    }
+
+    Ok(items)
+}
+
+async fn reindex(
+    ddb: &Client,
+    table_name: &str,
+    email_index_writer: &EmailIndexWriter,
+) -> Result<()> {
+    let mut num_items = 0;
+    let mut exclusive_start_key: Option<HashMap<String, AttributeValue>> = None;
+
+    loop {
+        let response = ddb
+            .scan()
+            .table_name(table_name)
+            .set_exclusive_start_key(exclusive_start_key)
+            .send()
+            .await?;
+
+        // NOTE: Each scan page is at most 1 MB, so it is written as one batch
+        let read_at = Utc::now();
+        let records: Vec<EventRecord> = response
+            .items()
+            .unwrap_or_default()
+            .iter()
+            .map(|attributes| repair_record("INSERT", convert(attributes), HashMap::new(), read_at))
+            .collect();
+        num_items += records.len();
+        write(email_index_writer, records).await?;
+
+        info!("reindexed {num_items} table items");
+
+        exclusive_start_key = response.last_evaluated_key().cloned();
+        if exclusive_start_key.is_none() {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+async fn write(email_index_writer: &EmailIndexWriter, records: Vec<EventRecord>) -> Result<()> {
+    if records.is_empty() {
+        return Ok(());
+    }
+
+    let result = email_index_writer
+        .handle(WriterRequest::Stream(Event { records }))
+        .await?;
+    info!("wrote {result}");
+
+    Ok(())
+}
+
+/// Builds the change record of an item read from the table at `read_at`, which orders it after
+/// every change indexed before the read. It has no sequence number, so it leaves the index
+/// position and `indexed_through` alone.
+fn repair_record(
+    event_name: &str,
+    new_image: HashMap<String, StreamAttributeValue>,
+    old_image: HashMap<String, StreamAttributeValue>,
+    read_at: DateTime<Utc>,
+) -> EventRecord {
+    let image = if new_image.is_empty() {
+        &old_image
+    } else {
+        &new_image
+    };
+    let keys: HashMap<String, StreamAttributeValue> = image
+        .iter()
+        .filter(|(name, _)| name.as_str() == "id")
+        .map(|(name, value)| (name.clone(), value.clone()))
+        .collect();
+
+    EventRecord {
+        aws_region: String::new(),
+        change: StreamRecord {
+            approximate_creation_date_time: read_at,
+            keys,
+            new_image,
+            old_image,
+            sequence_number: None,
+            size_bytes: 0,
+            stream_view_type: Some(StreamViewType::NewAndOldImages),
+        },
+        event_id: Ulid::new().to_string(),
+        event_name: event_name.to_string(),
+        event_source: "aws:dynamodb".to_string(),
+        event_version: "1.1".to_string(),
+        event_source_arn: "audit".to_string(),
+        user_identity: None,
+    }
+}
+
+fn convert(attributes: &HashMap<String, AttributeValue>) -> HashMap<String, StreamAttributeValue> {
+    let mut image: HashMap<String, StreamAttributeValue> = HashMap::new();
+
+    for (name, value) in attributes {
+        let value = match value {
+            AttributeValue::S(value) => StreamAttributeValue::String(value.clone()),
+            AttributeValue::Ss(values) => StreamAttributeValue::StringSet(values.clone()),
+            AttributeValue::N(value) => match value.parse() {
+                Ok(number) => StreamAttributeValue::Number(number),
+                Err(_) => continue,
+            },
+            AttributeValue::Bool(value) => StreamAttributeValue::Boolean(*value),
+            AttributeValue::Null(_) => StreamAttributeValue::Null,
+            _ => {
+                warn!("skipping unsupported attribute {name}");
+                continue;
+            }
+        };
+        image.insert(name.clone(), value);
+    }
+
+    image
+}
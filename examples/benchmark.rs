@@ -8,7 +8,9 @@ use aws_sigv4::http_request::{
     sign, PayloadChecksumKind, SignableRequest, SignatureLocation, SigningParams, SigningSettings,
 };
 use dynamodb_email_indexer::{
-    email::Email, search_request::SearchRequest, search_response::SearchResponse,
+    email::Email,
+    search_request::{SearchMode, SearchRequest},
+    search_response::SearchResponse,
 };
 use fake::{
     faker::{
@@ -18,7 +20,6 @@ use fake::{
     },
     Fake,
 };
-use http;
 use log::{debug, info};
 use serde_json::{json, Value};
 use std::time::SystemTime;
@@ -108,8 +109,9 @@ async fn main() -> Result<(), Error> {
         &access_key,
         &secret_key,
         SearchRequest {
-            limit: Some(1),
             query: Some("*".to_string()),
+            mode: Some(SearchMode::Count),
+            ..Default::default()
         },
     )
     .await?;
@@ -149,11 +151,11 @@ async fn main() -> Result<(), Error> {
 
         let email = Email {
             id: Ulid::new().to_string(),
-            timestamp: Utc::now().timestamp(),
-            subject: Sentence(1..5).fake(),
-            body: Paragraph(1..3).fake::<String>(),
-            to: to,
-            ttl: ttl,
+            timestamp: Some(Utc::now().timestamp()),
+            subject: Some(Sentence(1..5).fake()),
+            body: Some(Paragraph(1..3).fake::<String>()),
+            to: Some(to),
+            ttl: Some(ttl),
         };
 
         debug!("email {:?}", email);
@@ -190,8 +192,9 @@ async fn main() -> Result<(), Error> {
             &access_key,
             &secret_key,
             SearchRequest {
-                limit: Some(1),
                 query: Some("*".to_string()),
+                mode: Some(SearchMode::Count),
+                ..Default::default()
             },
         )
         .await?;
@@ -218,8 +221,8 @@ async fn main() -> Result<(), Error> {
 
 async fn search_docs(
     url: &str,
-    access_key: &String,
-    secret_key: &String,
+    access_key: &str,
+    secret_key: &str,
     search_request: SearchRequest,
 ) -> Result<SearchResponse> {
     let body = json!(&search_request).to_string();
@@ -268,5 +271,5 @@ async fn search_docs(
 
     let text = response.text().await?;
     println!("Error searching docs {}", text);
-    return Err(anyhow::anyhow!(text));
+    Err(anyhow::anyhow!(text))
 }
@@ -28,8 +28,8 @@ fn main() -> anyhow::Result<()> {
         let func = *function;
         sh.copy_file(func, "bootstrap")?;
         cmd!(sh, "zip {func}.zip bootstrap").run()?;
-        sh.remove_path(format!("{func}"))?;
-        sh.remove_path(format!("bootstrap"))?;
+        sh.remove_path(func)?;
+        sh.remove_path("bootstrap")?;
     }
 
     Ok(())
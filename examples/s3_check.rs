@@ -0,0 +1,111 @@
+use anyhow::{Context, Error, Result};
+use dynamodb_email_indexer::{
+    dynamodb_lock_table::DynamoDbLockTable, email_index_schema::EmailIndexSchema,
+    index_storage::IndexStorage, lock_table::LockTable, object_store::ObjectStore,
+    s3_store::S3Store, segment_cache::SegmentCache,
+};
+use log::info;
+use std::{path::Path, sync::Arc, time::Instant};
+use structopt::StructOpt;
+use tantivy::{collector::Count, doc, query::QueryParser};
+use ulid::Ulid;
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "s3_check")]
+struct Opt {
+    /// S3 bucket, which must already exist
+    #[structopt(short, long)]
+    bucket: String,
+
+    /// Key prefix to create the test index under, a unique suffix is added and removed afterwards
+    #[structopt(short, long, default_value = "s3-check")]
+    prefix: String,
+
+    /// S3 endpoint, for example http://localhost:9000 for MinIO
+    #[structopt(short, long)]
+    endpoint_url: Option<String>,
+
+    /// DynamoDB lock table with a `lock_id` string partition key, which must already exist
+    #[structopt(short, long)]
+    lock_table: String,
+
+    /// Number of documents to index
+    #[structopt(short, long, default_value = "1000")]
+    num_docs: usize,
+}
+
+/// Round trips an index through S3: writes documents under a lease from the lock table, then
+/// searches them with an empty cache.
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    std::env::set_var("RUST_LOG", "s3_check=info");
+    env_logger::init();
+
+    let options = Opt::from_args();
+    let start = Instant::now();
+
+    let run_id = Ulid::new().to_string();
+    let config = aws_config::load_from_env().await;
+    let store: Arc<dyn ObjectStore> = Arc::new(S3Store::new(
+        &config,
+        &options.bucket,
+        &format!("{}/{}", options.prefix, run_id),
+        options.endpoint_url.as_deref(),
+    )?);
+
+    let lock_table: Arc<dyn LockTable> =
+        Arc::new(DynamoDbLockTable::new(&config, &options.lock_table)?);
+
+    let cache_path = std::env::temp_dir().join(format!("s3-check-{run_id}"));
+    let email_index_schema = schema(&store, &lock_table, &cache_path.join("writer"))?;
+    let fields = &email_index_schema.fields;
+
+    let index = email_index_schema.ensure_index()?;
+    let mut index_writer = index.writer(50_000_000)?;
+    for i in 0..options.num_docs {
+        index_writer.add_document(doc!(
+            fields.id => format!("s3-check-{i}"),
+            fields.timestamp => i as i64,
+            fields.subject => format!("s3 check {i}"),
+            fields.ttl => i64::MAX,
+        ))?;
+    }
+    index_writer.commit()?;
+    index_writer.wait_merging_threads()?;
+    info!("indexed {} docs: {:?}", options.num_docs, start.elapsed());
+
+    // NOTE: A separate cache makes the reader download every segment, like a cold lambda would
+    let email_index_schema = schema(&store, &lock_table, &cache_path.join("reader"))?;
+    let index = email_index_schema.ensure_index()?;
+    let searcher = index.reader()?.searcher();
+    let query_parser = QueryParser::for_index(&index, vec![email_index_schema.fields.subject]);
+    let count = searcher.search(&query_parser.parse_query("check")?, &Count)?;
+    info!("searched {} docs: {:?}", count, start.elapsed());
+
+    std::fs::remove_dir_all(&cache_path).context("Error removing cache")?;
+    for object in store.list("")? {
+        store.delete(&object.key)?;
+    }
+
+    if count != options.num_docs {
+        return Err(anyhow::anyhow!(
+            "expected {} docs but found {}",
+            options.num_docs,
+            count
+        ));
+    }
+
+    Ok(())
+}
+
+fn schema(
+    store: &Arc<dyn ObjectStore>,
+    lock_table: &Arc<dyn LockTable>,
+    cache_path: &Path,
+) -> Result<EmailIndexSchema> {
+    Ok(EmailIndexSchema::new().with_storage(IndexStorage::S3 {
+        store: store.clone(),
+        lock_table: Some(lock_table.clone()),
+        cache: Arc::new(SegmentCache::new(cache_path, u64::MAX)?),
+    }))
+}
@@ -3,7 +3,6 @@ use aws_sigv4::http_request::{
     sign, PayloadChecksumKind, SignableRequest, SignatureLocation, SigningParams, SigningSettings,
 };
 use dynamodb_email_indexer::{search_request::SearchRequest, search_response::SearchResponse};
-use http;
 use log::info;
 use serde_json::{json, Value};
 use std::time::Instant;
@@ -72,6 +71,7 @@ async fn main() -> Result<(), Error> {
         SearchRequest {
             limit: Some(limit),
             query: Some(query.to_string()),
+            ..Default::default()
         },
     )
     .await?;
@@ -87,8 +87,8 @@ async fn main() -> Result<(), Error> {
 
 async fn search_docs(
     url: &str,
-    access_key: &String,
-    secret_key: &String,
+    access_key: &str,
+    secret_key: &str,
     search_request: SearchRequest,
 ) -> Result<SearchResponse> {
     let body = json!(&search_request).to_string();
@@ -137,5 +137,5 @@ async fn search_docs(
 
     let text = response.text().await?;
     println!("Error searching docs {}", text);
-    return Err(anyhow::anyhow!(text));
+    Err(anyhow::anyhow!(text))
 }
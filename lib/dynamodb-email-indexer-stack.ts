@@ -1,9 +1,10 @@
 import * as cdk from "aws-cdk-lib";
 import * as dynamodb from "aws-cdk-lib/aws-dynamodb";
-import * as ec2 from "aws-cdk-lib/aws-ec2";
-import * as efs from "aws-cdk-lib/aws-efs";
+import * as events from "aws-cdk-lib/aws-events";
+import * as events_targets from "aws-cdk-lib/aws-events-targets";
 import * as lambda from "aws-cdk-lib/aws-lambda";
 import * as event_sources from "aws-cdk-lib/aws-lambda-event-sources";
+import * as s3 from "aws-cdk-lib/aws-s3";
 import * as sqs from "aws-cdk-lib/aws-sqs";
 import * as constructs from "constructs";
 
@@ -11,16 +12,6 @@ export class DynamodbEmailIndexerStack extends cdk.Stack {
   constructor(scope: constructs.Construct, id: string, props?: cdk.StackProps) {
     super(scope, id, props);
 
-    const vpc = new ec2.Vpc(this, "VPC", {
-      natGateways: 0,
-      maxAzs: 2,
-      gatewayEndpoints: {
-        dynamodb: {
-          service: ec2.GatewayVpcEndpointAwsService.DYNAMODB, // NOTE: So we can talk to Dynamo from the index_reader lambda function
-        },
-      },
-    });
-
     const emailTable = new dynamodb.Table(this, "EmailTable", {
       partitionKey: {
         name: "id",
@@ -32,29 +23,34 @@ export class DynamodbEmailIndexerStack extends cdk.Stack {
       removalPolicy: cdk.RemovalPolicy.DESTROY,
     });
 
-    const fileSystem = new efs.FileSystem(this, "Efs", {
-      vpc,
-      encrypted: true,
+    const indexBucket = new s3.Bucket(this, "IndexBucket", {
+      encryption: s3.BucketEncryption.S3_MANAGED,
+      blockPublicAccess: s3.BlockPublicAccess.BLOCK_ALL,
       removalPolicy: cdk.RemovalPolicy.DESTROY,
+      autoDeleteObjects: true,
     });
 
-    const accessPoint = fileSystem.addAccessPoint("AccessPoint", {
-      path: "/export/lambda",
-      createAcl: {
-        ownerUid: "1001",
-        ownerGid: "1001",
-        permissions: "750",
-      },
-      posixUser: {
-        uid: "1001",
-        gid: "1001",
+    // NOTE: S3 can't lock files, so writers take a lease on each index's writer lock here instead
+    const indexLockTable = new dynamodb.Table(this, "IndexLockTable", {
+      partitionKey: {
+        name: "lock_id",
+        type: dynamodb.AttributeType.STRING,
       },
+      billingMode: dynamodb.BillingMode.PAY_PER_REQUEST,
+      timeToLiveAttribute: "expires_at",
+      removalPolicy: cdk.RemovalPolicy.DESTROY,
     });
 
-    const lambdaFilesystem = lambda.FileSystem.fromEfsAccessPoint(
-      accessPoint,
-      "/mnt/msg"
-    );
+    // NOTE: Documents are hashed to a shard by id, so changing this on an existing index sends updates and deletes to the wrong shard; reindex instead
+    const indexShards = "1";
+
+    const indexEnvironment = {
+      INDEX_STORAGE: "s3",
+      INDEX_BUCKET: indexBucket.bucketName,
+      INDEX_LOCK_TABLE: indexLockTable.tableName,
+      INDEX_SHARDS: indexShards,
+      RUST_LOG: "info",
+    };
 
     const emailIndexWriterFunction = new lambda.Function(
       this,
@@ -65,13 +61,7 @@ export class DynamodbEmailIndexerStack extends cdk.Stack {
         handler: "rust-runtime",
         memorySize: 2048,
         runtime: lambda.Runtime.PROVIDED_AL2,
-        filesystem: lambdaFilesystem,
-        reservedConcurrentExecutions: 1, // NOTE: Tantivy can only have a single index writer, so we need to make this a singleton function
-        vpc: vpc,
-        environment: {
-          EFS_MOUNT_PATH: lambdaFilesystem.config.localMountPath,
-          RUST_LOG: "info",
-        },
+        environment: indexEnvironment,
         onFailure: new event_sources.SqsDlq(
           new sqs.Queue(this, "EmailIndexWriterFunctionDLQ", {
             removalPolicy: cdk.RemovalPolicy.DESTROY,
@@ -80,6 +70,9 @@ export class DynamodbEmailIndexerStack extends cdk.Stack {
       }
     );
 
+    indexBucket.grantReadWrite(emailIndexWriterFunction);
+    indexLockTable.grantReadWriteData(emailIndexWriterFunction);
+
     emailIndexWriterFunction.addEventSource(
       new event_sources.DynamoEventSource(emailTable, {
         enabled: true,
@@ -88,7 +81,7 @@ export class DynamodbEmailIndexerStack extends cdk.Stack {
         maxBatchingWindow: cdk.Duration.seconds(30),
         bisectBatchOnError: false,
         retryAttempts: 0,
-        parallelizationFactor: 1, // NOTE: Tantivy can only have a single index writer, so we cannot index in parallel
+        parallelizationFactor: 1, // NOTE: Concurrent batches wait on the writer lock of each index shard, so raise INDEX_SHARDS as well when raising this
         reportBatchItemFailures: true,
         tumblingWindow: undefined,
         maxRecordAge: undefined,
@@ -100,6 +93,44 @@ export class DynamodbEmailIndexerStack extends cdk.Stack {
       })
     );
 
+    // NOTE: Purging and merging a whole index can take far longer than a stream batch, so maintenance runs in its own function from the same code
+    const emailIndexMaintenanceFunction = new lambda.Function(
+      this,
+      "EmailIndexMaintenanceFunction",
+      {
+        timeout: cdk.Duration.minutes(15),
+        code: lambda.Code.fromAsset("./build/email_index_writer.zip"),
+        handler: "rust-runtime",
+        memorySize: 2048,
+        runtime: lambda.Runtime.PROVIDED_AL2,
+        environment: indexEnvironment,
+        retryAttempts: 0,
+      }
+    );
+
+    indexBucket.grantReadWrite(emailIndexMaintenanceFunction);
+    indexLockTable.grantReadWriteData(emailIndexMaintenanceFunction);
+
+    // NOTE: DynamoDB TTL deletions can be delayed or lost, so purge expired documents from the index directly
+    new events.Rule(this, "EmailIndexPurgeExpiredRule", {
+      schedule: events.Schedule.rate(cdk.Duration.hours(6)),
+      targets: [
+        new events_targets.LambdaFunction(emailIndexMaintenanceFunction, {
+          event: events.RuleTargetInput.fromObject({ command: "purge_expired" }),
+        }),
+      ],
+    });
+
+    // NOTE: Merging down segments and vacuuming deletes means fewer files for readers to download and search, so do it at night while stream batches are few
+    new events.Rule(this, "EmailIndexOptimizeRule", {
+      schedule: events.Schedule.cron({ minute: "0", hour: "3" }),
+      targets: [
+        new events_targets.LambdaFunction(emailIndexMaintenanceFunction, {
+          event: events.RuleTargetInput.fromObject({ command: "optimize" }),
+        }),
+      ],
+    });
+
     const emailIndexReaderFunction = new lambda.Function(
       this,
       "EmailIndexReaderFunction",
@@ -109,17 +140,15 @@ export class DynamodbEmailIndexerStack extends cdk.Stack {
         handler: "rust-runtime",
         memorySize: 2048,
         runtime: lambda.Runtime.PROVIDED_AL2,
-        filesystem: lambdaFilesystem,
-        vpc: vpc,
         environment: {
-          EFS_MOUNT_PATH: lambdaFilesystem.config.localMountPath,
-          RUST_LOG: "info",
+          ...indexEnvironment,
           TABLE_NAME: emailTable.tableName,
         },
       }
     );
 
     emailTable.grantReadData(emailIndexReaderFunction);
+    indexBucket.grantRead(emailIndexReaderFunction);
 
     // TODO: Using escape hatch until CDK support for function urls
     // https://github.com/aws/aws-cdk/pull/19817
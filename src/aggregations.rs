@@ -0,0 +1,168 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use tantivy::{
+    chrono::{Datelike, NaiveDate, NaiveDateTime},
+    collector::{Collector, SegmentCollector},
+    fastfield::{DynamicFastFieldReader, FastFieldReader},
+    schema::Field,
+    DocId, Score, SegmentOrdinal, SegmentReader, TantivyError,
+};
+
+#[derive(Deserialize, Serialize, Default, Clone)]
+pub struct AggregationsRequest {
+    pub date_histogram: Option<DateHistogramRequest>,
+    pub terms: Option<TermsRequest>,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct DateHistogramRequest {
+    pub interval: DateInterval,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct TermsRequest {
+    pub field: TermsField,
+    pub size: Option<usize>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum DateInterval {
+    Hour,
+    Day,
+    Week,
+    Month,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum TermsField {
+    Recipient,
+    Domain,
+}
+
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+pub struct AggregationsResponse {
+    pub date_histogram: Option<Vec<DateHistogramBucket>>,
+    pub terms: Option<Vec<TermsBucket>>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DateHistogramBucket {
+    pub key: i64,
+    pub doc_count: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TermsBucket {
+    pub key: String,
+    pub doc_count: u64,
+}
+
+const HOUR: i64 = 60 * 60;
+const DAY: i64 = 24 * HOUR;
+const WEEK: i64 = 7 * DAY;
+// NOTE: 1970-01-01 was a Thursday, so shift by 4 days to start weeks on a Monday
+const WEEK_OFFSET: i64 = 4 * DAY;
+
+impl DateInterval {
+    /// Returns the unix timestamp of the start of the bucket the timestamp (in seconds) falls in.
+    pub fn bucket(&self, timestamp: i64) -> Result<i64> {
+        let bucket = match self {
+            DateInterval::Hour => timestamp - timestamp.rem_euclid(HOUR),
+            DateInterval::Day => timestamp - timestamp.rem_euclid(DAY),
+            DateInterval::Week => timestamp - (timestamp - WEEK_OFFSET).rem_euclid(WEEK),
+            DateInterval::Month => {
+                let date = NaiveDateTime::from_timestamp_opt(timestamp, 0)
+                    .with_context(|| format!("timestamp {timestamp} is out of range"))?
+                    .date();
+                NaiveDate::from_ymd(date.year(), date.month(), 1)
+                    .and_hms(0, 0, 0)
+                    .timestamp()
+            }
+        };
+
+        Ok(bucket)
+    }
+}
+
+/// Counts matching documents per `DateInterval` bucket of an i64 fast field.
+pub struct DateHistogramCollector {
+    field: Field,
+    interval: DateInterval,
+}
+
+impl DateHistogramCollector {
+    pub fn new(field: Field, interval: DateInterval) -> Self {
+        DateHistogramCollector { field, interval }
+    }
+}
+
+impl Collector for DateHistogramCollector {
+    type Fruit = BTreeMap<i64, u64>;
+    type Child = DateHistogramSegmentCollector;
+
+    fn for_segment(
+        &self,
+        _segment_local_id: SegmentOrdinal,
+        segment: &SegmentReader,
+    ) -> tantivy::Result<Self::Child> {
+        let reader = segment.fast_fields().i64(self.field)?;
+
+        Ok(DateHistogramSegmentCollector {
+            reader,
+            interval: self.interval,
+            buckets: BTreeMap::new(),
+            error: None,
+        })
+    }
+
+    fn requires_scoring(&self) -> bool {
+        false
+    }
+
+    fn merge_fruits(
+        &self,
+        segment_fruits: Vec<(BTreeMap<i64, u64>, Option<String>)>,
+    ) -> tantivy::Result<Self::Fruit> {
+        let mut buckets = BTreeMap::new();
+
+        for (segment_buckets, error) in segment_fruits {
+            if let Some(error) = error {
+                return Err(TantivyError::InvalidArgument(error));
+            }
+
+            for (key, count) in segment_buckets {
+                *buckets.entry(key).or_insert(0) += count;
+            }
+        }
+
+        Ok(buckets)
+    }
+}
+
+pub struct DateHistogramSegmentCollector {
+    reader: DynamicFastFieldReader<i64>,
+    interval: DateInterval,
+    buckets: BTreeMap<i64, u64>,
+    error: Option<String>,
+}
+
+impl SegmentCollector for DateHistogramSegmentCollector {
+    // NOTE: collect can't fail, so keep the first error and report it when the fruits are merged
+    type Fruit = (BTreeMap<i64, u64>, Option<String>);
+
+    fn collect(&mut self, doc: DocId, _score: Score) {
+        match self.interval.bucket(self.reader.get(doc)) {
+            Ok(key) => *self.buckets.entry(key).or_insert(0) += 1,
+            Err(error) => {
+                self.error.get_or_insert_with(|| error.to_string());
+            }
+        }
+    }
+
+    fn harvest(self) -> Self::Fruit {
+        (self.buckets, self.error)
+    }
+}
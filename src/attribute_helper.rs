@@ -8,10 +8,8 @@ impl AttributeHelper {
         attributes: &HashMap<String, AttributeValue>,
         attribute_name: &str,
     ) -> anyhow::Result<String> {
-        if let Some(attr) = attributes.get(attribute_name) {
-            if let AttributeValue::S(value) = attr {
-                return Ok(value.clone());
-            }
+        if let Some(AttributeValue::S(value)) = attributes.get(attribute_name) {
+            return Ok(value.clone());
         }
 
         Err(anyhow::anyhow!("{attribute_name} missing"))
@@ -21,26 +19,54 @@ impl AttributeHelper {
         attributes: &HashMap<String, AttributeValue>,
         attribute_name: &str,
     ) -> anyhow::Result<i64> {
-        if let Some(attr) = attributes.get(attribute_name) {
-            if let AttributeValue::S(value) = attr {
-                let result: i64 = value.parse()?;
-                return Ok(result);
-            }
+        // NOTE: DynamoDB TTL only works on numbers, so ttl is usually a number rather than a string
+        match attributes.get(attribute_name) {
+            Some(AttributeValue::S(value) | AttributeValue::N(value)) => Ok(value.parse()?),
+            _ => Err(anyhow::anyhow!("{attribute_name} missing")),
         }
-
-        Err(anyhow::anyhow!("{attribute_name} missing"))
     }
 
     pub fn parse_string_array(
         attributes: &HashMap<String, AttributeValue>,
         attribute_name: &str,
     ) -> anyhow::Result<Vec<String>> {
-        if let Some(attr) = attributes.get(attribute_name) {
-            if let AttributeValue::Ss(values) = attr {
-                return Ok(values.clone());
-            }
+        if let Some(AttributeValue::Ss(values)) = attributes.get(attribute_name) {
+            return Ok(values.clone());
         };
 
         Err(anyhow::anyhow!("{attribute_name} missing"))
     }
+
+    pub fn parse_optional_string(
+        attributes: &HashMap<String, AttributeValue>,
+        attribute_name: &str,
+    ) -> anyhow::Result<Option<String>> {
+        if !attributes.contains_key(attribute_name) {
+            return Ok(None);
+        }
+
+        Self::parse_string(attributes, attribute_name).map(Some)
+    }
+
+    pub fn parse_optional_int_64(
+        attributes: &HashMap<String, AttributeValue>,
+        attribute_name: &str,
+    ) -> anyhow::Result<Option<i64>> {
+        if !attributes.contains_key(attribute_name) {
+            return Ok(None);
+        }
+
+        Self::parse_int_64(attributes, attribute_name).map(Some)
+    }
+
+    pub fn parse_optional_string_array(
+        attributes: &HashMap<String, AttributeValue>,
+        attribute_name: &str,
+    ) -> anyhow::Result<Option<Vec<String>>> {
+        if !attributes.contains_key(attribute_name) {
+            return Ok(None);
+        }
+
+        Self::parse_string_array(attributes, attribute_name).map(Some)
+    }
 }
@@ -0,0 +1,106 @@
+use crate::email_index_schema::{EmailIndexSchema, TOMBSTONE_TTL};
+use anyhow::{Context, Result};
+use log::warn;
+use serde::Serialize;
+use std::collections::{BTreeSet, HashMap};
+use tantivy::fastfield::FastFieldReader;
+
+/// The differences between the emails in the table and the documents in the index.
+#[derive(Serialize, Default, Debug)]
+pub struct AuditReport {
+    pub table_num_items: usize,
+    pub index_num_docs: usize,
+    pub missing_from_index: BTreeSet<String>,
+    pub missing_from_table: BTreeSet<String>,
+    pub timestamp_mismatch: BTreeSet<String>,
+}
+
+impl AuditReport {
+    /// Compares the timestamps of the table items with the ones of the indexed emails, both keyed
+    /// by id.
+    pub fn new(
+        table_timestamps: &HashMap<String, Option<i64>>,
+        index_timestamps: &HashMap<String, i64>,
+    ) -> AuditReport {
+        let mut report = AuditReport {
+            table_num_items: table_timestamps.len(),
+            index_num_docs: index_timestamps.len(),
+            ..Default::default()
+        };
+
+        for (id, timestamp) in table_timestamps {
+            match index_timestamps.get(id) {
+                None => {
+                    report.missing_from_index.insert(id.clone());
+                }
+                Some(indexed_timestamp) => {
+                    if Some(*indexed_timestamp) != *timestamp {
+                        report.timestamp_mismatch.insert(id.clone());
+                    }
+                }
+            }
+        }
+
+        for id in index_timestamps.keys() {
+            if !table_timestamps.contains_key(id) {
+                report.missing_from_table.insert(id.clone());
+            }
+        }
+
+        report
+    }
+
+    /// The ids to index again from the table.
+    pub fn stale_ids(&self) -> Vec<String> {
+        self.missing_from_index
+            .union(&self.timestamp_mismatch)
+            .cloned()
+            .collect()
+    }
+
+    pub fn is_clean(&self) -> bool {
+        self.missing_from_index.is_empty()
+            && self.missing_from_table.is_empty()
+            && self.timestamp_mismatch.is_empty()
+    }
+
+    /// Reads the timestamp of every email in the index. Tombstones of removed emails are left
+    /// out, as the table no longer has them either.
+    pub fn index_timestamps(email_index_schema: &EmailIndexSchema) -> Result<HashMap<String, i64>> {
+        let fields = &email_index_schema.fields;
+        let mut timestamps: HashMap<String, i64> = HashMap::new();
+
+        for key in email_index_schema.partition_keys()? {
+            let index = email_index_schema.open_partition(&key)?;
+            let searcher = index.reader()?.searcher();
+
+            for segment_reader in searcher.segment_readers() {
+                let store_reader = segment_reader.get_store_reader()?;
+                let fast_fields = segment_reader.fast_fields();
+                let timestamp_reader = fast_fields.i64(fields.timestamp)?;
+                let ttl_reader = fast_fields.i64(fields.ttl)?;
+
+                for doc_id in segment_reader.doc_ids_alive() {
+                    if ttl_reader.get(doc_id) == TOMBSTONE_TTL {
+                        continue;
+                    }
+
+                    let doc = store_reader.get(doc_id)?;
+                    let id = doc
+                        .get_first(fields.id)
+                        .and_then(|value| value.as_text())
+                        .context("Documents should have a id value")?;
+
+                    if timestamps
+                        .insert(id.to_string(), timestamp_reader.get(doc_id))
+                        .is_some()
+                    {
+                        warn!("{id} is indexed more than once");
+                    }
+                }
+            }
+        }
+
+        Ok(timestamps)
+    }
+}
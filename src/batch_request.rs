@@ -0,0 +1,7 @@
+use crate::search_request::SearchRequest;
+use serde::{Deserialize, Serialize};
+
+#[derive(Deserialize, Serialize, Default)]
+pub struct BatchRequest {
+    pub requests: Vec<SearchRequest>,
+}
@@ -0,0 +1,39 @@
+use crate::commit_payload::CommitPayload;
+use crate::search_response::SearchResponse;
+
+use serde::Deserialize;
+use serde::Serialize;
+
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct BatchResponse {
+    pub error: Option<String>,
+    pub index_num_docs: Option<u64>,
+    pub version: Option<String>,
+    pub indexed_through: Option<i64>,
+    pub lag_seconds: Option<i64>,
+    pub responses: Vec<SearchResponse>,
+}
+
+impl BatchResponse {
+    pub fn error(error: &str) -> Self {
+        BatchResponse {
+            error: Some(error.to_string()),
+            ..Default::default()
+        }
+    }
+
+    pub fn success(
+        total: u64,
+        commit_payload: &CommitPayload,
+        responses: Vec<SearchResponse>,
+    ) -> Self {
+        BatchResponse {
+            error: None,
+            index_num_docs: Some(total),
+            version: commit_payload.sequence_number.clone(),
+            indexed_through: commit_payload.indexed_through,
+            lag_seconds: commit_payload.lag_seconds(),
+            responses,
+        }
+    }
+}
@@ -0,0 +1,247 @@
+use anyhow::{Error, Result};
+use dynamodb_email_indexer::{
+    email_index_schema::{schema_fingerprint, EmailIndexSchema},
+    index_partitioning::Partitioning,
+    index_storage::IndexStorage,
+};
+use log::{info, warn};
+use std::{collections::HashSet, io::Write, path::PathBuf};
+use structopt::StructOpt;
+use tantivy::{Directory, Index};
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "index_admin")]
+struct Opt {
+    /// Index directory, defaults to the storage INDEX_STORAGE configures
+    #[structopt(short, long)]
+    index_dir: Option<PathBuf>,
+
+    #[structopt(subcommand)]
+    command: Command,
+}
+
+#[derive(StructOpt, Debug)]
+enum Command {
+    /// Validates meta.json, segment checksums and reports orphan files, exiting with an error if
+    /// any partition has problems
+    Validate,
+    /// Deletes orphan files left behind by crashed writers
+    Gc,
+    /// Rewrites meta.json without the segments that are missing or fail their checksum
+    Rollback,
+}
+
+const META_FILES: [&str; 4] = [
+    "meta.json",
+    ".managed.json",
+    ".tantivy-meta.lock",
+    ".tantivy-writer.lock",
+];
+
+struct Report {
+    corrupted_segments: HashSet<String>,
+    orphan_files: Vec<PathBuf>,
+}
+
+impl Report {
+    fn is_valid(&self) -> bool {
+        self.corrupted_segments.is_empty() && self.orphan_files.is_empty()
+    }
+}
+
+/// Validates and repairs every partition of the index. The partitioning is read from the same
+/// env vars as the functions.
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    std::env::set_var("RUST_LOG", "index_admin=info");
+    env_logger::init();
+
+    let options = Opt::from_args();
+
+    let storage = match options.index_dir {
+        Some(index_dir) => IndexStorage::Path(index_dir),
+        None => IndexStorage::from_env(&aws_config::load_from_env().await)?,
+    };
+    let email_index_schema = EmailIndexSchema::new()
+        .with_partitioning(Partitioning::from_env()?)
+        .with_storage(storage)
+        .with_current_generation()?;
+
+    let mut rolled_back = false;
+    let mut invalid_partitions: Vec<String> = vec![];
+
+    for key in email_index_schema.partition_keys()? {
+        info!("checking partition {key}");
+
+        // NOTE: Opening the index parses meta.json, so a truncated or invalid meta.json fails here
+        let index = match email_index_schema.open_partition_unchecked(&key) {
+            Ok(index) => index,
+            Err(error) if matches!(options.command, Command::Validate) => {
+                warn!("partition {key} can't be opened, meta.json may be corrupt: {error:?}");
+                invalid_partitions.push(key);
+                continue;
+            }
+            Err(error) => {
+                return Err(error.context(format!(
+                    "Error opening partition {key}, meta.json may be corrupt"
+                )))
+            }
+        };
+
+        let metas = index.load_metas()?;
+        let fingerprint = schema_fingerprint(&metas.schema);
+        let fingerprint_matches = fingerprint == email_index_schema.fingerprint();
+        if !fingerprint_matches {
+            warn!(
+                "partition {key} schema fingerprint {} does not match expected {}",
+                fingerprint,
+                email_index_schema.fingerprint()
+            );
+        }
+
+        match options.command {
+            Command::Validate => {
+                let report = validate(&email_index_schema, &key, &index)?;
+                if report.is_valid() && fingerprint_matches {
+                    info!("partition {key} is valid");
+                } else {
+                    invalid_partitions.push(key);
+                }
+            }
+            Command::Gc => gc(&email_index_schema, &key, &index).await?,
+            Command::Rollback => rolled_back |= rollback(&email_index_schema, &key, &index)?,
+        }
+    }
+
+    // NOTE: The dropped documents are no longer indexed, so the index is not through any stream position
+    if rolled_back {
+        email_index_schema.reset_checkpoint()?;
+        warn!("reset the index checkpoint, re-index the dropped documents with the audit tool");
+    }
+
+    if !invalid_partitions.is_empty() {
+        return Err(anyhow::anyhow!(
+            "Partitions {} have problems",
+            invalid_partitions.join(", ")
+        ));
+    }
+
+    Ok(())
+}
+
+async fn gc(email_index_schema: &EmailIndexSchema, key: &str, index: &Index) -> Result<()> {
+    // NOTE: Taking the writer lock makes sure no writer is committing while we delete files, and the orphans
+    // are only listed once we hold it, as a writer may have committed them since
+    let index_writer = index.writer(50_000_000)?;
+    let report = validate(email_index_schema, key, index)?;
+
+    let result = index_writer.garbage_collect_files().await?;
+    info!("tantivy deleted {} files", result.deleted_files.len());
+
+    let directory = index.directory();
+    for path in &report.orphan_files {
+        if directory.exists(path)? {
+            directory.delete(path)?;
+            info!("deleted orphan file {:?}", path);
+        }
+    }
+
+    Ok(())
+}
+
+/// Drops the corrupted segments, returning whether there were any.
+fn rollback(email_index_schema: &EmailIndexSchema, key: &str, index: &Index) -> Result<bool> {
+    let index_writer = index.writer(50_000_000)?;
+    let report = validate(email_index_schema, key, index)?;
+
+    if report.corrupted_segments.is_empty() {
+        info!("no corrupted segments, nothing to roll back");
+        return Ok(false);
+    }
+
+    let mut metas = index.load_metas()?;
+
+    let num_docs_before: u32 = metas.segments.iter().map(|s| s.num_docs()).sum();
+    metas.segments.retain(|segment_meta| {
+        !report
+            .corrupted_segments
+            .contains(&segment_meta.id().uuid_string())
+    });
+    let num_docs_after: u32 = metas.segments.iter().map(|s| s.num_docs()).sum();
+
+    // NOTE: The commit no longer has every stream record the payload claims it has
+    metas.payload = None;
+
+    let mut buffer = serde_json::to_vec_pretty(&metas)?;
+    writeln!(&mut buffer)?;
+    index
+        .directory()
+        .atomic_write(std::path::Path::new("meta.json"), &buffer)?;
+
+    drop(index_writer);
+
+    info!(
+        "dropped {} segments, {} documents need to be re-indexed",
+        report.corrupted_segments.len(),
+        num_docs_before - num_docs_after
+    );
+
+    Ok(true)
+}
+
+fn validate(email_index_schema: &EmailIndexSchema, key: &str, index: &Index) -> Result<Report> {
+    let mut corrupted_segments: HashSet<String> = HashSet::new();
+    let mut active_files: HashSet<PathBuf> = HashSet::new();
+
+    for segment_meta in index.searchable_segment_metas()? {
+        let segment_id = segment_meta.id().uuid_string();
+
+        for path in segment_meta.list_files() {
+            if !index.directory().exists(&path)? {
+                warn!("segment {segment_id} is missing {:?}", path);
+                corrupted_segments.insert(segment_id.clone());
+            }
+            active_files.insert(path);
+        }
+    }
+
+    // NOTE: Checksum validation fails outright on missing files, so only run it once they are gone
+    if corrupted_segments.is_empty() {
+        for path in index.validate_checksum()? {
+            warn!("checksum mismatch {:?}", path);
+            let file_name = path.to_string_lossy().to_string();
+            if let Some((segment_id, _)) = file_name.split_once('.') {
+                corrupted_segments.insert(segment_id.to_string());
+            }
+        }
+    } else {
+        warn!("skipping checksum validation until missing segments are rolled back");
+    }
+
+    let mut orphan_files: Vec<PathBuf> = vec![];
+    for file_name in email_index_schema.partition_files(key)? {
+        if active_files.contains(&file_name) {
+            continue;
+        }
+        if META_FILES
+            .iter()
+            .any(|name| file_name == PathBuf::from(name))
+        {
+            continue;
+        }
+
+        warn!("orphan file {:?}", file_name);
+        orphan_files.push(file_name);
+    }
+
+    info!(
+        "{} corrupted segments, {} orphan files",
+        corrupted_segments.len(),
+        orphan_files.len()
+    );
+
+    Ok(Report {
+        corrupted_segments,
+        orphan_files,
+    })
+}
@@ -0,0 +1,91 @@
+use anyhow::{Context, Error, Result};
+use dynamodb_email_indexer::{
+    email_index_schema::EmailIndexSchema, index_partitioning::Partitioning, index_snapshot,
+    index_storage::IndexStorage,
+};
+use log::info;
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter},
+    path::PathBuf,
+    time::Instant,
+};
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "snapshot")]
+struct Opt {
+    /// Index directory, defaults to the storage configured by INDEX_STORAGE
+    #[structopt(short, long)]
+    index_dir: Option<PathBuf>,
+
+    #[structopt(subcommand)]
+    command: Command,
+}
+
+#[derive(StructOpt, Debug)]
+enum Command {
+    /// Writes the latest commit of every partition to an archive
+    Export {
+        /// Archive to write, for example index.snapshot.gz
+        #[structopt(short, long)]
+        output: PathBuf,
+    },
+    /// Restores an archive into a new generation of the index and makes it current
+    Restore {
+        /// Archive to read
+        #[structopt(short = "f", long)]
+        input: PathBuf,
+    },
+    /// Prints the manifest of an archive
+    Inspect {
+        /// Archive to read
+        #[structopt(short = "f", long)]
+        input: PathBuf,
+    },
+}
+
+/// Exports and restores index snapshots, for backups and to seed other environments. The
+/// partitioning is read from the same env vars as the functions.
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    std::env::set_var("RUST_LOG", "snapshot=info,dynamodb_email_indexer=info");
+    env_logger::init();
+
+    let options = Opt::from_args();
+    let start = Instant::now();
+
+    let storage = match options.index_dir {
+        Some(index_dir) => IndexStorage::Path(index_dir),
+        None => IndexStorage::from_env(&aws_config::load_from_env().await)?,
+    };
+    let email_index_schema = EmailIndexSchema::new()
+        .with_partitioning(Partitioning::from_env()?)
+        .with_storage(storage)
+        .with_current_generation()?;
+
+    let manifest = match options.command {
+        Command::Export { output } => {
+            // NOTE: Write to a temp file so a failed export never leaves a partial archive behind
+            let temp_output = output.with_extension("partial");
+            let file = File::create(&temp_output)
+                .with_context(|| format!("Error creating {temp_output:?}"))?;
+            let manifest = index_snapshot::export(&email_index_schema, BufWriter::new(file))?;
+            std::fs::rename(&temp_output, &output)?;
+            manifest
+        }
+        Command::Restore { input } => {
+            let file = File::open(&input).with_context(|| format!("Error opening {input:?}"))?;
+            index_snapshot::restore(&email_index_schema, BufReader::new(file))?
+        }
+        Command::Inspect { input } => {
+            let file = File::open(&input).with_context(|| format!("Error opening {input:?}"))?;
+            index_snapshot::read_manifest(BufReader::new(file))?
+        }
+    };
+
+    println!("{}", serde_json::to_string_pretty(&manifest)?);
+    info!("done in {:?}", start.elapsed());
+
+    Ok(())
+}
@@ -0,0 +1,42 @@
+use crate::env_helper::EnvHelper;
+use anyhow::Result;
+use std::time::Duration;
+
+/// Sizes of the reader's caches. A size of 0 disables a cache.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct CacheSettings {
+    /// Search results per normalized request, valid until the next reload.
+    pub query_cache_size: usize,
+    /// Emails hydrated from the table.
+    pub email_cache_size: usize,
+    /// How long a hydrated email is served from the cache, so updates in the table show up late
+    /// by at most this long.
+    pub email_cache_ttl: Duration,
+}
+
+impl Default for CacheSettings {
+    fn default() -> Self {
+        CacheSettings {
+            query_cache_size: 256,
+            email_cache_size: 1024,
+            email_cache_ttl: Duration::from_secs(30),
+        }
+    }
+}
+
+impl CacheSettings {
+    /// Reads `QUERY_CACHE_SIZE`, `EMAIL_CACHE_SIZE` and `EMAIL_CACHE_TTL_MS`, all optional.
+    pub fn from_env() -> Result<CacheSettings> {
+        let default = CacheSettings::default();
+
+        Ok(CacheSettings {
+            query_cache_size: EnvHelper::parse("QUERY_CACHE_SIZE")?
+                .unwrap_or(default.query_cache_size),
+            email_cache_size: EnvHelper::parse("EMAIL_CACHE_SIZE")?
+                .unwrap_or(default.email_cache_size),
+            email_cache_ttl: EnvHelper::parse("EMAIL_CACHE_TTL_MS")?
+                .map(Duration::from_millis)
+                .unwrap_or(default.email_cache_ttl),
+        })
+    }
+}
@@ -0,0 +1,113 @@
+use crate::contact_directory::ContactDirectory;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    cmp::Ordering,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tantivy::Index;
+
+/// Metadata the writer stores alongside every index commit.
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+pub struct CommitPayload {
+    /// The sequence number of the latest stream record applied to the index.
+    pub sequence_number: Option<String>,
+    /// The approximate creation time (unix seconds) of the newest stream record applied. Stream
+    /// shards are read independently, so records of a slower stream shard created before it may
+    /// still be on their way. It measures lag, `SearchRequest::min_version` waits for a write.
+    pub indexed_through: Option<i64>,
+    /// When the commit was made (unix seconds).
+    pub committed_at: Option<i64>,
+    /// The contacts of the partition's emails. Only partition commits have them, and not the ones
+    /// made before contacts were kept.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub contacts: Option<ContactDirectory>,
+}
+
+impl CommitPayload {
+    pub fn load(index: &Index) -> Result<CommitPayload> {
+        let metas = index.load_metas().context("Error loading index metas")?;
+        CommitPayload::parse(metas.payload.as_deref())
+    }
+
+    pub fn parse(payload: Option<&str>) -> Result<CommitPayload> {
+        match payload {
+            Some(payload) => {
+                serde_json::from_str(payload).context("Error parsing index commit payload")
+            }
+            None => Ok(CommitPayload::default()),
+        }
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        let json = serde_json::to_string(self)?;
+        Ok(json)
+    }
+
+    /// Combines the payloads of several partitions, keeping the newest values.
+    pub fn merge(&mut self, other: &CommitPayload) {
+        if let Some(sequence_number) = &other.sequence_number {
+            self.observe_sequence_number(sequence_number);
+        }
+        if let Some(indexed_through) = other.indexed_through {
+            self.observe_creation_time(indexed_through);
+        }
+        if other.committed_at > self.committed_at {
+            self.committed_at = other.committed_at;
+        }
+    }
+
+    pub fn observe_sequence_number(&mut self, sequence_number: &str) {
+        let is_newer = match &self.sequence_number {
+            Some(current) => {
+                compare_sequence_numbers(sequence_number, current) == Ordering::Greater
+            }
+            None => true,
+        };
+
+        if is_newer {
+            self.sequence_number = Some(sequence_number.to_string());
+        }
+    }
+
+    pub fn observe_creation_time(&mut self, timestamp: i64) {
+        if self
+            .indexed_through
+            .is_none_or(|current| timestamp > current)
+        {
+            self.indexed_through = Some(timestamp);
+        }
+    }
+
+    /// Seconds between the newest applied stream record being written and it being committed. Unlike the
+    /// time since `indexed_through` this doesn't grow while the table is idle.
+    pub fn lag_seconds(&self) -> Option<i64> {
+        match (self.indexed_through, self.committed_at) {
+            (Some(indexed_through), Some(committed_at)) => {
+                Some((committed_at - indexed_through).max(0))
+            }
+            _ => None,
+        }
+    }
+
+    /// Seconds since the last commit.
+    pub fn commit_age_seconds(&self) -> Option<i64> {
+        self.committed_at
+            .map(|committed_at| (unix_now() - committed_at).max(0))
+    }
+}
+
+/// Stream sequence numbers are decimal strings that can be wider than a u128, so compare them
+/// by length before comparing digits.
+pub fn compare_sequence_numbers(a: &str, b: &str) -> Ordering {
+    let a = a.trim_start_matches('0');
+    let b = b.trim_start_matches('0');
+    a.len().cmp(&b.len()).then_with(|| a.cmp(b))
+}
+
+pub fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs() as i64)
+        .unwrap_or_default()
+}
@@ -0,0 +1,263 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use tantivy::{
+    fastfield::FastFieldReader,
+    schema::{Facet, Field, IndexRecordOption},
+    DocId, DocSet, Searcher, SegmentReader, TERMINATED,
+};
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Contact {
+    pub address: String,
+    pub name: Option<String>,
+    pub message_count: u64,
+    pub last_seen: i64,
+}
+
+/// The contacts of the emails in a partition. The writer keeps them up to date as it adds and
+/// deletes documents, and commits them in the partition's `CommitPayload`.
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+pub struct ContactDirectory {
+    contacts: HashMap<String, Contact>,
+    /// When the current name of each contact was last seen.
+    name_seen: HashMap<String, i64>,
+}
+
+impl ContactDirectory {
+    /// Reads the contacts of the live documents of a segment. Each email has a `/address` facet
+    /// and, if the mailbox has a display name, a `/address/name` facet. Only needed for partitions
+    /// committed before their contacts were.
+    pub fn from_segment(
+        segment_reader: &SegmentReader,
+        contact: Field,
+        timestamp: Field,
+    ) -> Result<Self> {
+        let mut contact_directory = ContactDirectory::default();
+
+        let inverted_index = segment_reader.inverted_index(contact)?;
+        let timestamps = segment_reader.fast_fields().i64(timestamp)?;
+        let alive_bitset = segment_reader.alive_bitset();
+
+        let mut stream = inverted_index.terms().stream()?;
+        while stream.advance() {
+            let mut postings = inverted_index
+                .read_postings_from_terminfo(stream.value(), IndexRecordOption::Basic)?;
+
+            let mut message_count = 0_u64;
+            let mut last_seen = i64::MIN;
+            let mut doc = postings.doc();
+            while doc != TERMINATED {
+                if alive_bitset.is_none_or(|alive_bitset| alive_bitset.is_alive(doc)) {
+                    message_count += 1;
+                    last_seen = last_seen.max(timestamps.get(doc));
+                }
+                doc = postings.advance();
+            }
+
+            if message_count == 0 {
+                continue;
+            }
+
+            let facet = Facet::from_encoded(stream.key().to_vec())?;
+            match facet.to_path().as_slice() {
+                [address] if !address.is_empty() => {
+                    contact_directory.observe(address, None, message_count, last_seen)
+                }
+                [address, name] => contact_directory.observe(address, Some(name), 0, last_seen),
+                _ => {}
+            }
+        }
+
+        Ok(contact_directory)
+    }
+
+    /// Reads the contacts of the live documents of every segment of a searcher.
+    pub fn from_searcher(searcher: &Searcher, contact: Field, timestamp: Field) -> Result<Self> {
+        let mut contact_directory = ContactDirectory::default();
+        for segment_reader in searcher.segment_readers() {
+            contact_directory.merge(&ContactDirectory::from_segment(
+                segment_reader,
+                contact,
+                timestamp,
+            )?);
+        }
+        Ok(contact_directory)
+    }
+
+    /// Counts an email with the contact facets, seen at the timestamp.
+    pub fn add_email<'a>(&mut self, facets: impl IntoIterator<Item = &'a Facet>, timestamp: i64) {
+        let mut counted: HashSet<String> = HashSet::new();
+        for facet in facets {
+            let (address, name) = match facet.to_path().as_slice() {
+                [address] if !address.is_empty() => (address.to_string(), None),
+                [address, name] => (address.to_string(), Some(name.to_string())),
+                _ => continue,
+            };
+
+            // NOTE: Mailboxes of an email can share an address, which only counts once
+            let message_count = counted.insert(address.clone()) as u64;
+            self.observe(&address, name.as_deref(), message_count, timestamp);
+        }
+    }
+
+    /// Stops counting an email with the contact facets. When a contact was last seen and its
+    /// display name can't be rolled back, so they stay until the contact has no emails left.
+    pub fn remove_email<'a>(&mut self, facets: impl IntoIterator<Item = &'a Facet>) {
+        let addresses: HashSet<String> = facets
+            .into_iter()
+            .filter_map(|facet| facet.to_path().first().map(|address| address.to_string()))
+            .collect();
+
+        for address in addresses {
+            let message_count = match self.contacts.get_mut(&address) {
+                Some(contact) => {
+                    contact.message_count = contact.message_count.saturating_sub(1);
+                    contact.message_count
+                }
+                None => continue,
+            };
+
+            if message_count == 0 {
+                self.contacts.remove(&address);
+                self.name_seen.remove(&address);
+            }
+        }
+    }
+
+    /// Stops counting the email of a document in a segment. Its facet ords only hold the facet
+    /// of each mailbox, not the `/address` parent of a `/address/name` one.
+    pub fn remove_segment_doc(
+        &mut self,
+        segment_reader: &SegmentReader,
+        contact: Field,
+        doc: DocId,
+    ) -> Result<()> {
+        let mut facet_reader = segment_reader.facet_reader(contact)?;
+        let mut facet_ords = vec![];
+        facet_reader.facet_ords(doc, &mut facet_ords);
+
+        let mut facets = vec![];
+        for facet_ord in facet_ords {
+            let mut facet = Facet::root();
+            facet_reader.facet_from_ord(facet_ord, &mut facet)?;
+            facets.push(facet);
+        }
+        self.remove_email(&facets);
+
+        Ok(())
+    }
+
+    /// Adds the contacts of another partition.
+    pub fn merge(&mut self, other: &ContactDirectory) {
+        for (address, contact) in &other.contacts {
+            self.observe(address, None, contact.message_count, contact.last_seen);
+            if let Some(name) = &contact.name {
+                self.observe(address, Some(name), 0, other.name_seen[address]);
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.contacts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.contacts.is_empty()
+    }
+
+    /// Records messages seen by an address, and the display name it had when last seen.
+    fn observe(&mut self, address: &str, name: Option<&str>, message_count: u64, last_seen: i64) {
+        let contact = self
+            .contacts
+            .entry(address.to_string())
+            .or_insert_with(|| Contact {
+                address: address.to_string(),
+                name: None,
+                message_count: 0,
+                last_seen,
+            });
+
+        contact.message_count += message_count;
+        contact.last_seen = contact.last_seen.max(last_seen);
+
+        if let Some(name) = name {
+            let name_seen = self
+                .name_seen
+                .entry(address.to_string())
+                .or_insert(i64::MIN);
+            if last_seen >= *name_seen {
+                *name_seen = last_seen;
+                contact.name = Some(name.to_string());
+            }
+        }
+    }
+
+    /// Finds contacts whose address or any word of their display name starts with the prefix,
+    /// most frequently seen first.
+    pub fn search(&self, prefix: &str, limit: usize) -> Vec<Contact> {
+        let prefix = prefix.to_lowercase();
+
+        let mut contacts: Vec<Contact> = self
+            .contacts
+            .values()
+            .filter(|contact| {
+                if contact.address.starts_with(&prefix) {
+                    return true;
+                }
+
+                match &contact.name {
+                    Some(name) => name
+                        .to_lowercase()
+                        .split_whitespace()
+                        .any(|word| word.starts_with(&prefix)),
+                    None => false,
+                }
+            })
+            .cloned()
+            .collect();
+
+        contacts.sort_by(|a, b| {
+            b.message_count
+                .cmp(&a.message_count)
+                .then(b.last_seen.cmp(&a.last_seen))
+                .then(a.address.cmp(&b.address))
+        });
+        contacts.truncate(limit);
+        contacts
+    }
+}
+
+/// The facet an email gets for a mailbox, `/address` or `/address/name`.
+pub fn contact_facet(mailbox: &str) -> Option<Facet> {
+    let (name, address) = parse_mailbox(mailbox);
+    if address.is_empty() {
+        return None;
+    }
+
+    match name {
+        Some(name) => Some(Facet::from_path(vec![address, name])),
+        None => Some(Facet::from_path(vec![address])),
+    }
+}
+
+/// Splits a mailbox such as `"Jane Doe" <jane.doe@example.com>` into its display name and
+/// lowercased address.
+pub fn parse_mailbox(mailbox: &str) -> (Option<String>, String) {
+    let mailbox = mailbox.trim();
+
+    if let (Some(start), Some(end)) = (mailbox.rfind('<'), mailbox.rfind('>')) {
+        if start < end {
+            let address = mailbox[start + 1..end].trim().to_lowercase();
+            let name = mailbox[..start].trim().trim_matches('"').trim();
+            let name = if name.is_empty() {
+                None
+            } else {
+                Some(name.to_string())
+            };
+            return (name, address);
+        }
+    }
+
+    (None, mailbox.to_lowercase())
+}
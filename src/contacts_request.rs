@@ -0,0 +1,7 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Deserialize, Serialize, Default)]
+pub struct ContactsRequest {
+    pub prefix: Option<String>,
+    pub limit: Option<usize>,
+}
@@ -0,0 +1,28 @@
+use crate::contact_directory::Contact;
+
+use serde::Deserialize;
+use serde::Serialize;
+
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct ContactsResponse {
+    pub total_contacts: Option<usize>,
+    pub contacts: Option<Vec<Contact>>,
+    pub error: Option<String>,
+}
+
+impl ContactsResponse {
+    pub fn error(error: &str) -> Self {
+        ContactsResponse {
+            error: Some(error.to_string()),
+            ..Default::default()
+        }
+    }
+
+    pub fn success(total: usize, contacts: Vec<Contact>) -> Self {
+        ContactsResponse {
+            total_contacts: Some(total),
+            contacts: Some(contacts),
+            error: None,
+        }
+    }
+}
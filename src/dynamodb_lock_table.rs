@@ -0,0 +1,108 @@
+use crate::commit_payload::unix_now;
+use crate::lock_table::LockTable;
+use crate::runtime_bridge::RuntimeBridge;
+use anyhow::{Context, Result};
+use aws_sdk_dynamodb::{
+    error::{DeleteItemErrorKind, PutItemErrorKind},
+    model::AttributeValue,
+    types::SdkError,
+    Client,
+};
+use std::{fmt, time::Duration};
+
+/// Lock leases kept in a DynamoDB table with a `lock_id` string partition key. Leases are taken
+/// with conditional puts, and `expires_at` can be the table's TTL attribute to clean up leases
+/// of crashed writers.
+pub struct DynamoDbLockTable {
+    client: Client,
+    table_name: String,
+    runtime: RuntimeBridge,
+}
+
+impl fmt::Debug for DynamoDbLockTable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DynamoDbLockTable")
+            .field("table_name", &self.table_name)
+            .finish()
+    }
+}
+
+impl DynamoDbLockTable {
+    /// Must be called on the runtime that will run the requests.
+    pub fn new(shared_config: &aws_types::SdkConfig, table_name: &str) -> Result<Self> {
+        Ok(DynamoDbLockTable {
+            client: Client::new(shared_config),
+            table_name: table_name.to_string(),
+            runtime: RuntimeBridge::current()?,
+        })
+    }
+}
+
+impl LockTable for DynamoDbLockTable {
+    fn try_acquire(&self, key: &str, owner: &str, lease: Duration) -> Result<bool> {
+        let now = unix_now();
+        let request = self
+            .client
+            .put_item()
+            .table_name(&self.table_name)
+            .item("lock_id", AttributeValue::S(key.to_string()))
+            .item("owner", AttributeValue::S(owner.to_string()))
+            .item(
+                "expires_at",
+                AttributeValue::N((now + lease.as_secs() as i64).to_string()),
+            )
+            .condition_expression(
+                "attribute_not_exists(lock_id) OR expires_at < :now OR #owner = :owner",
+            )
+            // NOTE: OWNER is a DynamoDB reserved word
+            .expression_attribute_names("#owner", "owner")
+            .expression_attribute_values(":now", AttributeValue::N(now.to_string()))
+            .expression_attribute_values(":owner", AttributeValue::S(owner.to_string()));
+
+        self.runtime
+            .block_on(async move {
+                match request.send().await {
+                    Ok(_) => Ok(true),
+                    Err(SdkError::ServiceError { err, .. })
+                        if matches!(
+                            err.kind,
+                            PutItemErrorKind::ConditionalCheckFailedException(_)
+                        ) =>
+                    {
+                        Ok(false)
+                    }
+                    Err(error) => Err(anyhow::Error::from(error)),
+                }
+            })?
+            .with_context(|| format!("Error taking the lease on {key}"))
+    }
+
+    fn release(&self, key: &str, owner: &str) -> Result<()> {
+        let request = self
+            .client
+            .delete_item()
+            .table_name(&self.table_name)
+            .key("lock_id", AttributeValue::S(key.to_string()))
+            .condition_expression("#owner = :owner")
+            .expression_attribute_names("#owner", "owner")
+            .expression_attribute_values(":owner", AttributeValue::S(owner.to_string()));
+
+        // NOTE: A lease that expired and was taken over is no longer ours to release
+        self.runtime
+            .block_on(async move {
+                match request.send().await {
+                    Ok(_) => Ok(()),
+                    Err(SdkError::ServiceError { err, .. })
+                        if matches!(
+                            err.kind,
+                            DeleteItemErrorKind::ConditionalCheckFailedException(_)
+                        ) =>
+                    {
+                        Ok(())
+                    }
+                    Err(error) => Err(anyhow::Error::from(error)),
+                }
+            })?
+            .with_context(|| format!("Error releasing the lease on {key}"))
+    }
+}
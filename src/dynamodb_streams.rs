@@ -0,0 +1,170 @@
+use crate::commit_payload::compare_sequence_numbers;
+use crate::stream_records::parse_change_record;
+use anyhow::{Context, Result};
+use aws_lambda_events::dynamodb::EventRecord;
+use aws_sdk_dynamodbstreams::{
+    model::{AttributeValue, Record, ShardIteratorType},
+    Client, Region,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::{cmp::Ordering, collections::HashMap};
+
+const MAX_GET_RECORDS_CALLS: usize = 100;
+
+/// The position of a batch of stream records that a DynamoDB stream event source failed to
+/// process, as sent to its on-failure destination.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct StreamBatchInfo {
+    pub stream_arn: String,
+    pub shard_id: String,
+    pub start_sequence_number: String,
+    pub end_sequence_number: String,
+    pub batch_size: Option<usize>,
+}
+
+/// Reads failed batches back from their DynamoDB stream shard.
+pub struct DynamoDbStreams {
+    shared_config: aws_types::SdkConfig,
+}
+
+impl DynamoDbStreams {
+    pub fn new(shared_config: &aws_types::SdkConfig) -> Result<DynamoDbStreams> {
+        Ok(DynamoDbStreams {
+            shared_config: shared_config.clone(),
+        })
+    }
+
+    /// Returns the records of the batch in stream order.
+    pub async fn read_batch(&self, batch_info: &StreamBatchInfo) -> Result<Vec<EventRecord>> {
+        // NOTE: Stream ARNs look like arn:aws:dynamodb:<region>:<account>:table/<table>/stream/<label>, and the
+        // stream has to be read in its own region
+        let region = batch_info
+            .stream_arn
+            .split(':')
+            .nth(3)
+            .context("Invalid stream ARN")?;
+        let config = aws_sdk_dynamodbstreams::config::Builder::from(&self.shared_config)
+            .region(Region::new(region.to_string()))
+            .build();
+        let client = Client::from_conf(config);
+
+        let output = client
+            .get_shard_iterator()
+            .stream_arn(&batch_info.stream_arn)
+            .shard_id(&batch_info.shard_id)
+            .shard_iterator_type(ShardIteratorType::AtSequenceNumber)
+            .sequence_number(&batch_info.start_sequence_number)
+            .send()
+            .await
+            .with_context(|| format!("Error getting an iterator for {}", batch_info.shard_id))?;
+        let mut shard_iterator = output.shard_iterator;
+
+        let mut records: Vec<EventRecord> = vec![];
+        for _ in 0..MAX_GET_RECORDS_CALLS {
+            let iterator = match shard_iterator {
+                Some(iterator) => iterator,
+                None => return Ok(records),
+            };
+
+            let output = client
+                .get_records()
+                .shard_iterator(iterator)
+                .limit(1000)
+                .send()
+                .await
+                .with_context(|| format!("Error getting records of {}", batch_info.shard_id))?;
+
+            for record in output.records.unwrap_or_default() {
+                let record =
+                    parse_change_record(record_json(record), Some(&batch_info.stream_arn))?;
+                let sequence_number = record.change.sequence_number.clone().unwrap_or_default();
+
+                if compare_sequence_numbers(&sequence_number, &batch_info.end_sequence_number)
+                    == Ordering::Greater
+                {
+                    return Ok(records);
+                }
+                records.push(record);
+            }
+
+            shard_iterator = output.next_shard_iterator;
+        }
+
+        Err(anyhow::anyhow!(
+            "Reading {} of {} didn't reach {}",
+            batch_info.shard_id,
+            batch_info.stream_arn,
+            batch_info.end_sequence_number
+        ))
+    }
+}
+
+/// Converts a record to the JSON a DynamoDB stream event source delivers.
+fn record_json(record: Record) -> Value {
+    let mut change = json!({
+        "eventID": record.event_id,
+        "eventName": record.event_name.as_ref().map(|event_name| event_name.as_str()),
+        "eventVersion": record.event_version,
+        "eventSource": record.event_source,
+        "awsRegion": record.aws_region,
+    });
+
+    if let Some(stream_record) = record.dynamodb {
+        change["dynamodb"] = json!({
+            "ApproximateCreationDateTime": stream_record
+                .approximate_creation_date_time
+                .map(|created_at| created_at.secs()),
+            "Keys": attributes_json(stream_record.keys),
+            "NewImage": attributes_json(stream_record.new_image),
+            "OldImage": attributes_json(stream_record.old_image),
+            "SequenceNumber": stream_record.sequence_number,
+            "SizeBytes": stream_record.size_bytes,
+            "StreamViewType": stream_record
+                .stream_view_type
+                .as_ref()
+                .map(|stream_view_type| stream_view_type.as_str()),
+        });
+    }
+
+    // NOTE: TTL deletions are told apart by their user identity
+    if let Some(user_identity) = record.user_identity {
+        change["userIdentity"] = json!({
+            "principalId": user_identity.principal_id,
+            "type": user_identity.r#type,
+        });
+    }
+
+    change
+}
+
+fn attributes_json(attributes: Option<HashMap<String, AttributeValue>>) -> Value {
+    let attributes: Map<String, Value> = attributes
+        .unwrap_or_default()
+        .into_iter()
+        .map(|(name, value)| (name, attribute_json(value)))
+        .collect();
+
+    Value::Object(attributes)
+}
+
+fn attribute_json(value: AttributeValue) -> Value {
+    match value {
+        AttributeValue::B(blob) => json!({ "B": base64::encode(blob.as_ref()) }),
+        AttributeValue::Bool(value) => json!({ "BOOL": value }),
+        AttributeValue::Bs(blobs) => json!({
+            "BS": blobs.iter().map(|blob| base64::encode(blob.as_ref())).collect::<Vec<_>>()
+        }),
+        AttributeValue::L(values) => {
+            json!({ "L": values.into_iter().map(attribute_json).collect::<Vec<_>>() })
+        }
+        AttributeValue::M(values) => json!({ "M": attributes_json(Some(values)) }),
+        AttributeValue::N(value) => json!({ "N": value }),
+        AttributeValue::Ns(values) => json!({ "NS": values }),
+        AttributeValue::Null(value) => json!({ "NULL": value }),
+        AttributeValue::S(value) => json!({ "S": value }),
+        AttributeValue::Ss(values) => json!({ "SS": values }),
+        _ => json!({ "NULL": true }),
+    }
+}
@@ -3,46 +3,64 @@ use aws_sdk_dynamodb::model::AttributeValue;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
-#[derive(Serialize, Deserialize, Debug)]
+/// An email item. Every attribute apart from `id` is optional so a search can project a subset
+/// of the item.
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
 pub struct Email {
     pub id: String,
-    pub timestamp: i64,
-    pub subject: String,
-    pub body: String,
-    pub to: Vec<String>,
-    pub ttl: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub subject: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub body: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub to: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ttl: Option<i64>,
 }
 
 impl Email {
+    pub const ATTRIBUTE_NAMES: [&'static str; 6] =
+        ["id", "timestamp", "subject", "body", "to", "ttl"];
+
     pub fn attributes(self) -> HashMap<String, AttributeValue> {
-        HashMap::from([
-            ("id".into(), AttributeValue::S(self.id)),
-            (
-                "timestamp".into(),
-                AttributeValue::S(self.timestamp.to_string()),
-            ),
-            ("subject".into(), AttributeValue::S(self.subject)),
-            ("body".into(), AttributeValue::S(self.body)),
-            ("to".into(), AttributeValue::Ss(self.to)),
-            ("ttl".into(), AttributeValue::S(self.ttl.to_string())),
-        ])
+        let mut attributes = HashMap::from([("id".into(), AttributeValue::S(self.id))]);
+
+        if let Some(timestamp) = self.timestamp {
+            attributes.insert("timestamp".into(), AttributeValue::S(timestamp.to_string()));
+        }
+        if let Some(subject) = self.subject {
+            attributes.insert("subject".into(), AttributeValue::S(subject));
+        }
+        if let Some(body) = self.body {
+            attributes.insert("body".into(), AttributeValue::S(body));
+        }
+        if let Some(to) = self.to {
+            attributes.insert("to".into(), AttributeValue::Ss(to));
+        }
+        if let Some(ttl) = self.ttl {
+            attributes.insert("ttl".into(), AttributeValue::S(ttl.to_string()));
+        }
+
+        attributes
     }
 
     pub fn from(attributes: &HashMap<String, AttributeValue>) -> anyhow::Result<Email> {
         let id = AttributeHelper::parse_string(attributes, "id")?;
-        let timestamp = AttributeHelper::parse_int_64(attributes, "timestamp")?;
-        let subject = AttributeHelper::parse_string(attributes, "subject")?;
-        let body = AttributeHelper::parse_string(attributes, "body")?;
-        let to = AttributeHelper::parse_string_array(attributes, "to")?;
-        let ttl = AttributeHelper::parse_int_64(attributes, "ttl")?;
+        let timestamp = AttributeHelper::parse_optional_int_64(attributes, "timestamp")?;
+        let subject = AttributeHelper::parse_optional_string(attributes, "subject")?;
+        let body = AttributeHelper::parse_optional_string(attributes, "body")?;
+        let to = AttributeHelper::parse_optional_string_array(attributes, "to")?;
+        let ttl = AttributeHelper::parse_optional_int_64(attributes, "ttl")?;
 
         let email = Email {
-            id: id,
-            timestamp: timestamp,
-            body: body,
-            subject: subject,
-            to: to,
-            ttl: ttl,
+            id,
+            timestamp,
+            body,
+            subject,
+            to,
+            ttl,
         };
 
         Ok(email)
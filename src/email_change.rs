@@ -0,0 +1,52 @@
+use crate::email_index_schema::{EmailIndexFields, TOMBSTONE_TTL};
+use anyhow::Result;
+use tantivy::{
+    collector::DocSetCollector, fastfield::FastFieldReader, query::TermQuery,
+    schema::IndexRecordOption, Searcher, Term,
+};
+
+/// A change applied to an email, ordered by the approximate time it was made in the table and
+/// then by the item's `version`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct EmailChange {
+    /// The approximate creation time of the stream record (unix milliseconds).
+    pub changed_at: i64,
+    pub version: i64,
+    /// Whether the email was removed, leaving a tombstone in the index.
+    pub removed: bool,
+}
+
+impl EmailChange {
+    /// Changes made at the same time with the same version can't be told apart, so neither is
+    /// older than the other.
+    pub fn is_older_than(&self, other: &EmailChange) -> bool {
+        (self.changed_at, self.version) < (other.changed_at, other.version)
+    }
+
+    /// Looks up the changes indexed for an email in a partition, including the tombstone of its
+    /// removal.
+    pub fn indexed(
+        searcher: &Searcher,
+        fields: &EmailIndexFields,
+        id: &str,
+    ) -> Result<Vec<EmailChange>> {
+        let query = TermQuery::new(
+            Term::from_field_text(fields.id, id),
+            IndexRecordOption::Basic,
+        );
+        let mut changes: Vec<EmailChange> = vec![];
+
+        for doc_address in searcher.search(&query, &DocSetCollector)? {
+            let fast_fields = searcher
+                .segment_reader(doc_address.segment_ord)
+                .fast_fields();
+            changes.push(EmailChange {
+                changed_at: fast_fields.i64(fields.changed_at)?.get(doc_address.doc_id),
+                version: fast_fields.i64(fields.version)?.get(doc_address.doc_id),
+                removed: fast_fields.i64(fields.ttl)?.get(doc_address.doc_id) == TOMBSTONE_TTL,
+            });
+        }
+
+        Ok(changes)
+    }
+}
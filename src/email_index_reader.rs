@@ -0,0 +1,1353 @@
+use crate::aggregations::{
+    AggregationsResponse, DateHistogramBucket, DateHistogramCollector, DateInterval, TermsBucket,
+    TermsField,
+};
+use crate::batch_request::BatchRequest;
+use crate::batch_response::BatchResponse;
+use crate::cache_settings::CacheSettings;
+use crate::commit_payload::{unix_now, CommitPayload};
+use crate::contact_directory::ContactDirectory;
+use crate::contacts_request::ContactsRequest;
+use crate::contacts_response::ContactsResponse;
+use crate::email::Email;
+use crate::email_change::EmailChange;
+use crate::email_index_schema::{
+    schema_fingerprint, EmailIndexFields, EmailIndexSchema, TOMBSTONE_TTL,
+};
+use crate::http_request::HttpRequest;
+use crate::http_response::HttpResponse;
+use crate::index_partitioning::fnv1a;
+use crate::index_warmer::IndexWarmer;
+use crate::reader_request::ReaderRequest;
+use crate::reader_response::ReaderResponse;
+use crate::reload_policy::ReloadPolicy;
+use crate::search_request::{MinVersion, SearchMode, SearchRequest, SearchSort};
+use crate::search_response::{SearchHit, SearchResponse};
+use crate::stats_response::{SegmentStats, StatsResponse};
+use crate::status_response::StatusResponse;
+use crate::suggest_request::{SuggestRequest, MIN_PREFIX_CHARS};
+use crate::suggest_response::{SuggestResponse, Suggestion};
+use crate::ttl_cache::TtlCache;
+use anyhow::{Context, Result};
+use aws_sdk_dynamodb::{
+    model::{AttributeValue, KeysAndAttributes},
+    Client,
+};
+use log::{error, info, warn};
+use serde_json::json;
+use std::{
+    collections::{BTreeMap, HashMap},
+    ops::Bound,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    time::{Duration, Instant},
+};
+use tantivy::{
+    collector::{Count, FacetCollector, MultiCollector, TopDocs},
+    directory::{WatchCallback, WatchHandle},
+    fastfield::FastFieldReader,
+    query::{BooleanQuery, Occur, Query, QueryParser, RangeQuery},
+    schema::{Facet, Field, IndexRecordOption},
+    tokenizer::TokenizerManager,
+    Directory, DocAddress, DocId, DocSet, Executor, Index, IndexReader, LeasedItem, Opstamp, Score,
+    Searcher, SegmentId, SegmentReader, Warmer,
+};
+
+const WATCH_POLL_INTERVAL: Duration = Duration::from_millis(100);
+// NOTE: Cached searches also expire, since the ttl filter moves on between reloads
+const QUERY_CACHE_TTL: Duration = Duration::from_secs(60);
+const MIN_VERSION_TIMEOUT: Duration = Duration::from_secs(5);
+const MIN_VERSION_POLL_INTERVAL: Duration = Duration::from_millis(100);
+// NOTE: Restores don't commit to the partitions being watched, so readers reloading on commit poll for them
+const GENERATION_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+pub const MIN_VERSION_TIMEOUT_ERROR: &str = "timed out waiting for the index to reach min_version";
+
+struct Partition {
+    key: String,
+    index: Index,
+    index_reader: IndexReader,
+    /// Held here, as the index reader only keeps a weak reference.
+    _warmer: Arc<dyn Warmer>,
+    /// The searcher as of the last reload, shared by every snapshot until the next one, as the
+    /// reader only has a few searchers of each generation to lease.
+    searcher: Arc<LeasedItem<Searcher>>,
+    /// The segments the searcher was last reloaded with.
+    segment_keys: Vec<SegmentKey>,
+    /// The contacts the writer committed, as of the searcher or a newer commit.
+    contacts: Arc<ContactDirectory>,
+    _watch_handle: Option<WatchHandle>,
+}
+
+/// The searchers of every partition as of one reload. Requests hold on to it for as long as they
+/// run, so a reload never changes the index under a request.
+struct ReaderSnapshot {
+    /// Increases with every reload that changed a searcher.
+    generation: u64,
+    /// The schema of the index generation the partitions were opened from.
+    email_index_schema: Arc<EmailIndexSchema>,
+    /// A hash of the segments of every partition, which identifies the documents searched.
+    segments_hash: u64,
+    keys: Vec<String>,
+    searchers: Vec<Arc<LeasedItem<Searcher>>>,
+    /// The emails in the index, leaving out tombstones.
+    num_docs: u64,
+    commit_payload: CommitPayload,
+    /// The contacts of every partition.
+    contacts: Arc<ContactDirectory>,
+    /// The partition versions the snapshot was loaded at, only tracked when reloading on request.
+    partition_versions: Option<Vec<(String, Option<String>)>>,
+    loaded_at: Instant,
+}
+
+/// Identifies the documents of a segment, which only change when its deletes do.
+type SegmentKey = (SegmentId, Option<Opstamp>);
+
+/// The DynamoDB table search results are hydrated from.
+struct EmailTable {
+    ddb: Client,
+    table_name: String,
+}
+
+/// Serves reader requests from the index partitions of an `EmailIndexSchema`. Requests run
+/// concurrently against the latest snapshot, which `spawn_reloader` keeps fresh. The reader never
+/// writes to the storage, so an index the writer hasn't created yet reads as empty.
+pub struct EmailIndexReader {
+    email_index_schema: EmailIndexSchema,
+    query_parser: QueryParser,
+    email_table: Option<EmailTable>,
+    reload_policy: ReloadPolicy,
+    /// Set by the directory watches when reloading on commit, and by requests waiting for a write.
+    changed: Arc<AtomicBool>,
+    search_executor: Executor,
+    partitions: Mutex<Vec<Partition>>,
+    snapshot: RwLock<Arc<ReaderSnapshot>>,
+    generation_checked_at: Mutex<Instant>,
+    query_cache: TtlCache<SearchResponse>,
+    email_cache: TtlCache<Email>,
+}
+
+impl EmailIndexReader {
+    pub fn new(email_index_schema: EmailIndexSchema) -> Result<EmailIndexReader> {
+        // NOTE: Partitions share the schema and the default tokenizers, so one parser serves them all
+        let query_parser = QueryParser::new(
+            email_index_schema.schema.clone(),
+            email_index_schema.default_fields(),
+            TokenizerManager::default(),
+        );
+
+        let cache_settings = CacheSettings::default();
+        let snapshot_schema = Arc::new(email_index_schema.clone());
+        let email_index_reader = EmailIndexReader {
+            email_index_schema,
+            query_parser,
+            email_table: None,
+            reload_policy: ReloadPolicy::default(),
+            changed: Arc::new(AtomicBool::new(false)),
+            search_executor: Executor::multi_thread(
+                std::thread::available_parallelism()
+                    .map(|num_threads| num_threads.get())
+                    .unwrap_or(1),
+                "partition-search-",
+            )?,
+            partitions: Mutex::new(vec![]),
+            snapshot: RwLock::new(Arc::new(ReaderSnapshot {
+                generation: 0,
+                email_index_schema: snapshot_schema,
+                segments_hash: 0,
+                keys: vec![],
+                searchers: vec![],
+                num_docs: 0,
+                commit_payload: CommitPayload::default(),
+                contacts: Arc::new(ContactDirectory::default()),
+                partition_versions: None,
+                loaded_at: Instant::now(),
+            })),
+            generation_checked_at: Mutex::new(Instant::now()),
+            query_cache: TtlCache::new(cache_settings.query_cache_size, QUERY_CACHE_TTL),
+            email_cache: TtlCache::new(
+                cache_settings.email_cache_size,
+                cache_settings.email_cache_ttl,
+            ),
+        };
+        email_index_reader.reload()?;
+
+        Ok(email_index_reader)
+    }
+
+    /// Hydrates `full` mode search results from the table. Without a table only `ids` and `count`
+    /// searches are supported.
+    pub fn with_table(self, ddb: Client, table_name: &str) -> Self {
+        EmailIndexReader {
+            email_table: Some(EmailTable {
+                ddb,
+                table_name: table_name.to_string(),
+            }),
+            ..self
+        }
+    }
+
+    /// Replaces the caches, dropping whatever they hold.
+    pub fn with_cache_settings(self, cache_settings: CacheSettings) -> Self {
+        EmailIndexReader {
+            query_cache: TtlCache::new(cache_settings.query_cache_size, QUERY_CACHE_TTL),
+            email_cache: TtlCache::new(
+                cache_settings.email_cache_size,
+                cache_settings.email_cache_ttl,
+            ),
+            ..self
+        }
+    }
+
+    /// Reopens the partitions, so set it before the reader is shared.
+    pub fn with_reload_policy(self, reload_policy: ReloadPolicy) -> Result<Self> {
+        let email_index_reader = EmailIndexReader {
+            reload_policy,
+            ..self
+        };
+
+        // NOTE: Reopen the partitions so they are watched when reloading on commit
+        email_index_reader
+            .partitions
+            .lock()
+            .expect("partitions lock poisoned")
+            .clear();
+        email_index_reader.reload()?;
+
+        Ok(email_index_reader)
+    }
+
+    /// Reloads the reader in the background according to the reload policy on a thread of its
+    /// own, until the reader is dropped. Does nothing when reloading on request.
+    pub fn spawn_reloader(email_index_reader: &Arc<EmailIndexReader>) {
+        if email_index_reader.reload_policy == ReloadPolicy::OnRequest {
+            return;
+        }
+        let weak_reader = Arc::downgrade(email_index_reader);
+
+        std::thread::spawn(move || loop {
+            std::thread::sleep(WATCH_POLL_INTERVAL);
+
+            let email_index_reader = match weak_reader.upgrade() {
+                Some(email_index_reader) => email_index_reader,
+                None => break,
+            };
+
+            let requested = email_index_reader.changed.swap(false, Ordering::SeqCst);
+            let should_reload = match email_index_reader.reload_policy {
+                ReloadPolicy::Interval(reload_interval) => {
+                    requested
+                        || email_index_reader.snapshot().loaded_at.elapsed() >= reload_interval
+                }
+                _ => requested,
+            };
+            if should_reload {
+                if let Err(error) = email_index_reader.reload() {
+                    warn!("reload failed {error:?}");
+                }
+            }
+        });
+    }
+
+    /// Serves a request from an HTTP event source. Failures the caller can't fix are logged with
+    /// the request id, which the 500 response carries so the two can be matched up.
+    pub async fn handle_http(self: &Arc<Self>, request: &HttpRequest) -> HttpResponse {
+        let request_id = request.request_id.as_deref().unwrap_or_default();
+
+        let response = match request.reader_request() {
+            Ok(reader_request) => match self.handle(reader_request).await {
+                Ok(response) => HttpResponse::from_reader_response(&response),
+                Err(error) => {
+                    error!("request {request_id} failed {error:?}");
+                    HttpResponse::json(
+                        500,
+                        &json!({ "error": "internal error", "request_id": request_id }),
+                    )
+                }
+            },
+            Err(response) => response,
+        };
+
+        response.with_header("x-request-id", request_id)
+    }
+
+    pub async fn handle(self: &Arc<Self>, request: ReaderRequest) -> Result<ReaderResponse> {
+        self.blocking(|reader| reader.reload_if_needed()).await?;
+
+        let response = match request {
+            ReaderRequest::Search(request) => {
+                if !self.wait_for_versions(request.min_version.iter()).await? {
+                    let response = SearchResponse::error(MIN_VERSION_TIMEOUT_ERROR);
+                    return Ok(ReaderResponse::Search(
+                        response.with_commit_payload(&self.snapshot().commit_payload),
+                    ));
+                }
+                let snapshot = self.snapshot();
+                let response = self.search(&snapshot, request).await?;
+                ReaderResponse::Search(
+                    response
+                        .with_commit_payload(&snapshot.commit_payload)
+                        .with_searcher_generation(snapshot.generation),
+                )
+            }
+            ReaderRequest::Batch(request) => {
+                let min_versions = request
+                    .requests
+                    .iter()
+                    .filter_map(|request| request.min_version.as_ref());
+                if !self.wait_for_versions(min_versions).await? {
+                    return Ok(ReaderResponse::Batch(BatchResponse::error(
+                        MIN_VERSION_TIMEOUT_ERROR,
+                    )));
+                }
+                ReaderResponse::Batch(self.batch(request).await)
+            }
+            ReaderRequest::Suggest(request) => {
+                ReaderResponse::Suggest(self.blocking(move |reader| reader.suggest(request)).await?)
+            }
+            ReaderRequest::Contacts(request) => ReaderResponse::Contacts(
+                self.blocking(move |reader| reader.contacts(request))
+                    .await?,
+            ),
+            ReaderRequest::Status(_) => {
+                ReaderResponse::Status(self.blocking(|reader| reader.status()).await?)
+            }
+            ReaderRequest::Stats(_) => {
+                ReaderResponse::Stats(self.blocking(|reader| reader.stats()).await?)
+            }
+        };
+
+        Ok(response)
+    }
+
+    /// Runs reader work off the runtime threads, as S3 storage blocks them on its own requests.
+    async fn blocking<T, F>(self: &Arc<Self>, work: F) -> Result<T>
+    where
+        F: FnOnce(&EmailIndexReader) -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        // NOTE: The S3 directory waits for futures it spawns on the runtime, which never run once
+        // every runtime thread is waiting the same way
+        let email_index_reader = self.clone();
+        tokio::task::spawn_blocking(move || work(&email_index_reader)).await?
+    }
+
+    fn snapshot(&self) -> Arc<ReaderSnapshot> {
+        self.snapshot
+            .read()
+            .expect("snapshot lock poisoned")
+            .clone()
+    }
+
+    fn reload_if_needed(&self) -> Result<()> {
+        match self.reload_policy {
+            // NOTE: Lambda freezes the reloader thread between invocations, so the first request after
+            // a pause reloads itself, unless another request already is
+            ReloadPolicy::Interval(reload_interval) => {
+                if self.snapshot().loaded_at.elapsed() < reload_interval * 2 {
+                    return Ok(());
+                }
+
+                match self.partitions.try_lock() {
+                    Ok(mut partitions) => self.reload_partitions(&mut partitions),
+                    Err(_) => Ok(()),
+                }
+            }
+            ReloadPolicy::OnCommit => {
+                if self.generation_changed()? {
+                    self.changed.store(true, Ordering::SeqCst);
+                }
+                if !self.changed.swap(false, Ordering::SeqCst) {
+                    return Ok(());
+                }
+
+                match self.partitions.try_lock() {
+                    Ok(mut partitions) => self.reload_partitions(&mut partitions),
+                    Err(_) => {
+                        // NOTE: The reload in progress may have started before the commit
+                        self.changed.store(true, Ordering::SeqCst);
+                        Ok(())
+                    }
+                }
+            }
+            ReloadPolicy::OnRequest => {
+                let email_index_schema = self.email_index_schema.clone().refresh_generation()?;
+                let partition_versions = partition_versions(&email_index_schema)?;
+                let snapshot = self.snapshot();
+                if snapshot.email_index_schema.generation == email_index_schema.generation
+                    && snapshot.partition_versions.as_ref() == Some(&partition_versions)
+                {
+                    return Ok(());
+                }
+
+                self.reload()
+            }
+        }
+    }
+
+    /// Checks whether a restore switched the current generation, at most once every
+    /// `GENERATION_CHECK_INTERVAL`.
+    fn generation_changed(&self) -> Result<bool> {
+        if !self.email_index_schema.follow_current_generation {
+            return Ok(false);
+        }
+
+        {
+            let mut generation_checked_at = self
+                .generation_checked_at
+                .lock()
+                .expect("generation check lock poisoned");
+            if generation_checked_at.elapsed() < GENERATION_CHECK_INTERVAL {
+                return Ok(false);
+            }
+            *generation_checked_at = Instant::now();
+        }
+
+        let email_index_schema = self.email_index_schema.clone().refresh_generation()?;
+        Ok(email_index_schema.generation != self.snapshot().email_index_schema.generation)
+    }
+
+    fn reload(&self) -> Result<()> {
+        let mut partitions = self.partitions.lock().expect("partitions lock poisoned");
+        self.reload_partitions(&mut partitions)
+    }
+
+    fn reload_partitions(&self, partitions: &mut Vec<Partition>) -> Result<()> {
+        let previous = self.snapshot();
+
+        // NOTE: A restore switches the current generation, which has partitions of its own
+        let email_index_schema = Arc::new(self.email_index_schema.clone().refresh_generation()?);
+        let switched = email_index_schema.generation != previous.email_index_schema.generation;
+        if switched {
+            info!(
+                "switching to generation {:?}",
+                email_index_schema.generation
+            );
+            partitions.clear();
+        }
+
+        // NOTE: Read the versions first, so a commit made during the reload triggers another one
+        let partition_versions = match self.reload_policy {
+            ReloadPolicy::OnRequest => Some(partition_versions(&email_index_schema)?),
+            _ => None,
+        };
+
+        // NOTE: Partitions past retention only hold expired emails, so skip them until the writer drops them
+        let now = unix_now();
+        let keys: Vec<String> = email_index_schema
+            .partition_keys()?
+            .into_iter()
+            .filter(|key| !self.email_index_schema.partitioning.is_expired(key, now))
+            .collect();
+
+        // NOTE: The writer creates and drops partitions, so pick up new ones and forget dropped ones
+        partitions.retain(|partition| keys.contains(&partition.key));
+
+        for key in keys {
+            if partitions.iter().any(|partition| partition.key == key) {
+                continue;
+            }
+
+            info!("opening partition {key}");
+            let index = email_index_schema.open_partition(&key)?;
+            // NOTE: Each index reader garbage collects its warmer with its own generations, so partitions don't share one
+            let warmer: Arc<dyn Warmer> =
+                Arc::new(IndexWarmer::new(self.email_index_schema.schema.clone()));
+            let index_reader = index
+                .reader_builder()
+                .reload_policy(tantivy::ReloadPolicy::Manual)
+                .warmers(vec![Arc::downgrade(&warmer)])
+                .try_into()?;
+            let watch_handle = match self.reload_policy {
+                ReloadPolicy::OnCommit => {
+                    let changed = self.changed.clone();
+                    Some(index.directory().watch(WatchCallback::new(move || {
+                        changed.store(true, Ordering::SeqCst)
+                    }))?)
+                }
+                _ => None,
+            };
+            let searcher = Arc::new(index_reader.searcher());
+            let segment_keys = searcher_segment_keys(&searcher);
+            let contacts = partition_contacts(&index, &searcher, &self.email_index_schema.fields)?;
+            partitions.push(Partition {
+                key,
+                index,
+                index_reader,
+                _warmer: warmer,
+                searcher,
+                segment_keys,
+                contacts,
+                _watch_handle: watch_handle,
+            });
+        }
+
+        partitions.sort_by(|a, b| a.key.cmp(&b.key));
+
+        // NOTE: Read the checkpoint before reloading, so the searchers are at least as new as the position. Shards
+        // commit independently, so the index is only through the position every shard has reached.
+        let commit_payload = email_index_schema
+            .load_checkpoint()?
+            .commit_payload(self.email_index_schema.partitioning.num_shards());
+
+        // NOTE: Reloading warms the new searchers before they are handed out, so only reload partitions whose
+        // segments changed since the last reload
+        let mut changed = switched
+            || previous
+                .keys
+                .iter()
+                .ne(partitions.iter().map(|partition| &partition.key));
+        for partition in partitions.iter_mut() {
+            let segment_keys = index_segment_keys(&partition.index)?;
+            if segment_keys == partition.segment_keys {
+                continue;
+            }
+
+            changed = true;
+            partition.index_reader.reload()?;
+            partition.searcher = Arc::new(partition.index_reader.searcher());
+            partition.segment_keys = searcher_segment_keys(&partition.searcher);
+            partition.contacts = partition_contacts(
+                &partition.index,
+                &partition.searcher,
+                &self.email_index_schema.fields,
+            )?;
+            email_index_schema.prune_cache(&partition.key, &partition.index)?;
+        }
+
+        let contacts = if changed {
+            let mut contacts = ContactDirectory::default();
+            for partition in partitions.iter() {
+                contacts.merge(&partition.contacts);
+            }
+            Arc::new(contacts)
+        } else {
+            previous.contacts.clone()
+        };
+
+        let snapshot = ReaderSnapshot {
+            generation: previous.generation + changed as u64,
+            email_index_schema,
+            segments_hash: fnv1a(
+                format!(
+                    "{:?}",
+                    partitions
+                        .iter()
+                        .map(|partition| (&partition.key, &partition.segment_keys))
+                        .collect::<Vec<_>>()
+                )
+                .as_bytes(),
+            ),
+            keys: partitions
+                .iter()
+                .map(|partition| partition.key.clone())
+                .collect(),
+            searchers: partitions
+                .iter()
+                .map(|partition| partition.searcher.clone())
+                .collect(),
+            num_docs: num_docs(
+                partitions.iter().map(|partition| &**partition.searcher),
+                self.email_index_schema.fields.ttl,
+            )?,
+            commit_payload,
+            contacts,
+            partition_versions,
+            loaded_at: Instant::now(),
+        };
+        *self.snapshot.write().expect("snapshot lock poisoned") = Arc::new(snapshot);
+
+        Ok(())
+    }
+
+    /// Waits until the snapshot has every email at its `min_version` or newer, returning false if
+    /// it still doesn't after `MIN_VERSION_TIMEOUT`.
+    async fn wait_for_versions(
+        self: &Arc<Self>,
+        min_versions: impl Iterator<Item = &MinVersion>,
+    ) -> Result<bool> {
+        let mut pending: Vec<MinVersion> = min_versions.cloned().collect();
+        if pending.is_empty() {
+            return Ok(true);
+        }
+        let start = Instant::now();
+
+        loop {
+            pending = self
+                .blocking(move |reader| reader.pending_versions(pending))
+                .await?;
+
+            if pending.is_empty() {
+                return Ok(true);
+            }
+            if start.elapsed() > MIN_VERSION_TIMEOUT {
+                return Ok(false);
+            }
+
+            self.blocking(|reader| reader.request_reload()).await?;
+            tokio::time::sleep(MIN_VERSION_POLL_INTERVAL).await;
+        }
+    }
+
+    /// The versions the snapshot hasn't reached yet.
+    fn pending_versions(&self, min_versions: Vec<MinVersion>) -> Result<Vec<MinVersion>> {
+        let snapshot = self.snapshot();
+        let mut pending: Vec<MinVersion> = vec![];
+
+        for min_version in min_versions {
+            let version = self.indexed_version(&snapshot, &min_version.id)?;
+            if version.is_none_or(|version| version < min_version.version.unwrap_or(0)) {
+                pending.push(min_version);
+            }
+        }
+
+        Ok(pending)
+    }
+
+    /// The newest version of an email in the snapshot, looked up in the partitions of its shard.
+    /// Removed emails have no version.
+    fn indexed_version(&self, snapshot: &ReaderSnapshot, id: &str) -> Result<Option<i64>> {
+        let partitioning = &self.email_index_schema.partitioning;
+        let mut indexed_version: Option<i64> = None;
+
+        for (key, searcher) in snapshot.keys.iter().zip(&snapshot.searchers) {
+            if partitioning.shard_of(key) != partitioning.shard_for(id) {
+                continue;
+            }
+
+            for change in EmailChange::indexed(searcher, &self.email_index_schema.fields, id)? {
+                if !change.removed {
+                    indexed_version = indexed_version.max(Some(change.version));
+                }
+            }
+        }
+
+        Ok(indexed_version)
+    }
+
+    /// Asks for a reload without waiting on one in progress.
+    fn request_reload(&self) -> Result<()> {
+        match self.reload_policy {
+            // NOTE: The reloader thread picks the request up on its next poll, so requests never block on a reload
+            ReloadPolicy::Interval(_) | ReloadPolicy::OnCommit => {
+                self.changed.store(true, Ordering::SeqCst);
+                Ok(())
+            }
+            ReloadPolicy::OnRequest => match self.partitions.try_lock() {
+                Ok(mut partitions) => self.reload_partitions(&mut partitions),
+                Err(_) => Ok(()),
+            },
+        }
+    }
+
+    async fn batch(self: &Arc<Self>, request: BatchRequest) -> BatchResponse {
+        // NOTE: Every query in the batch runs against the same searcher so the results are consistent
+        let snapshot = self.snapshot();
+        let mut responses: Vec<SearchResponse> = vec![];
+
+        for request in request.requests {
+            let response = match self.search(&snapshot, request).await {
+                Ok(response) => response.with_searcher_generation(snapshot.generation),
+                Err(error) => SearchResponse::error(error.to_string().as_str()),
+            };
+            responses.push(response);
+        }
+
+        BatchResponse::success(snapshot.num_docs, &snapshot.commit_payload, responses)
+    }
+
+    async fn search(
+        self: &Arc<Self>,
+        snapshot: &Arc<ReaderSnapshot>,
+        request: SearchRequest,
+    ) -> Result<SearchResponse> {
+        if request.query.is_none() {
+            return Ok(SearchResponse::error("query is required"));
+        }
+
+        if let Some(fields) = &request.fields {
+            for field in fields {
+                if !Email::ATTRIBUTE_NAMES.contains(&field.as_str()) {
+                    return Ok(SearchResponse::error(
+                        format!("{field} is not a valid field").as_str(),
+                    ));
+                }
+            }
+        }
+
+        // NOTE: Results only change with the segments searched, so cached results outlive reloads without a commit
+        let cache_key = format!("{:x}:{}", snapshot.segments_hash, request.cache_key());
+        let response = match self.query_cache.get(&cache_key) {
+            Some(response) => SearchResponse {
+                cached: Some(true),
+                ..response
+            },
+            None => {
+                let snapshot = snapshot.clone();
+                let index_request = request.clone();
+                let response = self
+                    .blocking(move |reader| reader.search_index(&snapshot, &index_request))
+                    .await?;
+                if response.error.is_none() {
+                    self.query_cache.put(cache_key, response.clone());
+                }
+                SearchResponse {
+                    cached: Some(false),
+                    ..response
+                }
+            }
+        };
+
+        if request.mode.unwrap_or(SearchMode::Full) != SearchMode::Full || response.hits.is_none() {
+            return Ok(response);
+        }
+
+        let ids: Vec<String> = response
+            .hits
+            .unwrap_or_default()
+            .into_iter()
+            .map(|hit| hit.id)
+            .collect();
+        let emails = self.get_emails(&ids, request.fields.as_deref()).await?;
+
+        Ok(SearchResponse {
+            hits: None,
+            emails: Some(emails),
+            ..response
+        })
+    }
+
+    /// Searches every partition that can match the query, returning the hits of `ids` and `full`
+    /// searches.
+    fn search_index(
+        &self,
+        snapshot: &ReaderSnapshot,
+        request: &SearchRequest,
+    ) -> Result<SearchResponse> {
+        let query = request.query.as_deref().unwrap_or_default();
+        let limit: usize = request.limit.unwrap_or(10);
+        let mode = request.mode.unwrap_or(SearchMode::Full);
+
+        match self.query_parser.parse_query(query) {
+            Ok(query) => {
+                // NOTE: Partitions outside the timestamp range of the query can't have any hits
+                let (start, end) =
+                    timestamp_range(&*query, self.email_index_schema.fields.timestamp);
+                let partitioning = &self.email_index_schema.partitioning;
+                let searchers: Vec<&Arc<LeasedItem<Searcher>>> = snapshot
+                    .keys
+                    .iter()
+                    .zip(&snapshot.searchers)
+                    .filter(|(key, _)| partitioning.may_contain(key, start, end))
+                    .map(|(_, searcher)| searcher)
+                    .collect();
+
+                // NOTE: DynamoDB can take days to delete expired items, so filter them out here. Documents indexed
+                // before the ttl field existed have no ttl, so exclude expired ones rather than requiring a ttl.
+                // Tombstones of removed emails have the lowest ttl, so they are excluded either way.
+                let expired_before = if request.include_expired.unwrap_or(false) {
+                    TOMBSTONE_TTL + 1
+                } else {
+                    unix_now()
+                };
+                let expired = RangeQuery::new_i64_bounds(
+                    self.email_index_schema.fields.ttl,
+                    Bound::Unbounded,
+                    Bound::Excluded(expired_before),
+                );
+                let query: Box<dyn Query> = Box::new(BooleanQuery::new(vec![
+                    (Occur::Must, query),
+                    (Occur::MustNot, Box::new(expired)),
+                ]));
+
+                let fields = &self.email_index_schema.fields;
+                let aggregations = request.aggregations.as_ref();
+                let date_interval = aggregations
+                    .and_then(|aggregations| aggregations.date_histogram.as_ref())
+                    .map(|date_histogram| date_histogram.interval);
+                let terms_request =
+                    aggregations.and_then(|aggregations| aggregations.terms.as_ref());
+                let terms_field = terms_request.map(|terms| match terms.field {
+                    TermsField::Recipient => fields.recipient,
+                    TermsField::Domain => fields.domain,
+                });
+                let terms_size = terms_request.and_then(|terms| terms.size).unwrap_or(10);
+
+                let partition_search = PartitionSearch {
+                    query: &*query,
+                    top_docs_limit: if mode == SearchMode::Count {
+                        None
+                    } else {
+                        Some(limit)
+                    },
+                    sort: request.sort.unwrap_or_default(),
+                    timestamp_field: fields.timestamp,
+                    date_interval,
+                    terms_field,
+                };
+
+                // NOTE: Partitions and shards are searched in parallel on a pool shared by every request, each with
+                // its own term statistics for scoring. tantivy can't score with statistics across indexes, so hits
+                // are sorted by timestamp unless the request asks for the approximate score order.
+                let partition_fruits = self
+                    .search_executor
+                    .map(|searcher| partition_search.run(searcher), searchers.iter())?;
+
+                let total = snapshot.num_docs;
+                let mut count = 0_usize;
+                let mut top_docs: Vec<(Option<i64>, Score, usize, DocAddress)> = vec![];
+                let mut date_histogram_buckets: BTreeMap<i64, u64> = BTreeMap::new();
+                let mut terms_counts: HashMap<String, u64> = HashMap::new();
+
+                for (ordinal, partition_fruit) in partition_fruits.into_iter().enumerate() {
+                    count += partition_fruit.count;
+
+                    for (timestamp, score, doc_address) in partition_fruit.top_docs {
+                        top_docs.push((timestamp, score, ordinal, doc_address));
+                    }
+
+                    for (key, doc_count) in partition_fruit.date_histogram {
+                        *date_histogram_buckets.entry(key).or_insert(0) += doc_count;
+                    }
+
+                    for (key, doc_count) in partition_fruit.terms {
+                        *terms_counts.entry(key).or_insert(0) += doc_count;
+                    }
+                }
+
+                top_docs.sort_by(|a, b| b.0.cmp(&a.0).then(b.1.total_cmp(&a.1)));
+                top_docs.truncate(limit);
+
+                let date_histogram = date_interval.map(|_| {
+                    date_histogram_buckets
+                        .into_iter()
+                        .map(|(key, doc_count)| DateHistogramBucket { key, doc_count })
+                        .collect::<Vec<_>>()
+                });
+                let terms = terms_field.map(|_| {
+                    let mut terms: Vec<TermsBucket> = terms_counts
+                        .into_iter()
+                        .map(|(key, doc_count)| TermsBucket { key, doc_count })
+                        .collect();
+                    terms.sort_by(|a, b| b.doc_count.cmp(&a.doc_count).then(a.key.cmp(&b.key)));
+                    terms.truncate(terms_size);
+                    terms
+                });
+                let aggregations = if date_histogram.is_some() || terms.is_some() {
+                    Some(AggregationsResponse {
+                        date_histogram,
+                        terms,
+                    })
+                } else {
+                    None
+                };
+
+                if mode == SearchMode::Count {
+                    return Ok(SearchResponse::success(
+                        total,
+                        count,
+                        None,
+                        None,
+                        aggregations,
+                    ));
+                }
+
+                let mut hits: Vec<SearchHit> = vec![];
+
+                for (_, score, ordinal, doc_address) in top_docs {
+                    let retrieved_doc = searchers[ordinal].doc(doc_address)?;
+
+                    let id = retrieved_doc
+                        .get_first(self.email_index_schema.fields.id)
+                        .unwrap()
+                        .as_text()
+                        .unwrap();
+
+                    hits.push(SearchHit {
+                        id: id.to_string(),
+                        score,
+                    });
+                }
+
+                Ok(SearchResponse::success(
+                    total,
+                    count,
+                    Some(hits),
+                    None,
+                    aggregations,
+                ))
+            }
+            Err(error) => Ok(SearchResponse::error(error.to_string().as_str())),
+        }
+    }
+
+    fn status(&self) -> Result<StatusResponse> {
+        self.reload()?;
+        let snapshot = self.snapshot();
+        Ok(StatusResponse::success(
+            snapshot.num_docs,
+            &snapshot.commit_payload,
+        ))
+    }
+
+    fn stats(&self) -> Result<StatsResponse> {
+        self.reload()?;
+        let snapshot = self.snapshot();
+
+        let mut segments: Vec<SegmentStats> = vec![];
+        let mut field_num_terms: BTreeMap<String, u64> = BTreeMap::new();
+        let mut num_deleted_docs = 0_u64;
+        let mut on_disk_bytes = 0_u64;
+        let mut index_schema_fingerprint: Option<String> = None;
+
+        for (key, searcher) in snapshot.keys.iter().zip(&snapshot.searchers) {
+            let schema = searcher.schema();
+            index_schema_fingerprint.get_or_insert_with(|| schema_fingerprint(schema));
+
+            for segment_reader in searcher.segment_readers() {
+                num_deleted_docs += segment_reader.num_deleted_docs() as u64;
+
+                segments.push(SegmentStats {
+                    partition: key.clone(),
+                    segment_id: segment_reader.segment_id().uuid_string(),
+                    num_docs: segment_reader.num_docs(),
+                    num_deleted_docs: segment_reader.num_deleted_docs(),
+                    size_bytes: segment_reader.space_usage()?.total() as u64,
+                });
+
+                for (field, field_entry) in schema.fields() {
+                    if !field_entry.is_indexed() {
+                        continue;
+                    }
+
+                    let num_terms =
+                        segment_reader.inverted_index(field)?.terms().num_terms() as u64;
+                    *field_num_terms
+                        .entry(field_entry.name().to_string())
+                        .or_insert(0) += num_terms;
+                }
+            }
+
+            on_disk_bytes += snapshot.email_index_schema.partition_size_bytes(key)?;
+        }
+
+        Ok(StatsResponse {
+            index_num_docs: Some(snapshot.num_docs),
+            index_num_deleted_docs: Some(num_deleted_docs),
+            segments: Some(segments),
+            field_num_terms: Some(field_num_terms),
+            on_disk_bytes: Some(on_disk_bytes),
+            schema_fingerprint: Some(self.email_index_schema.fingerprint()),
+            index_schema_fingerprint,
+            commit_payload: Some(snapshot.commit_payload.clone()),
+            query_cache: Some(self.query_cache.stats()),
+            email_cache: Some(self.email_cache.stats()),
+            error: None,
+        })
+    }
+
+    fn suggest(&self, request: SuggestRequest) -> Result<SuggestResponse> {
+        if request.prefix.is_none() {
+            return Ok(SuggestResponse::error("prefix is required"));
+        }
+
+        // NOTE: subject is tokenized with the default tokenizer and recipient addresses are lowercased, so
+        // terms are stored lowercased
+        let prefix = request.prefix.unwrap().to_lowercase();
+        let limit: usize = request.limit.unwrap_or(10);
+        let field_names = request
+            .fields
+            .unwrap_or_else(|| vec!["subject".to_string(), "to".to_string()]);
+
+        let mut fields: Vec<(String, Field)> = vec![];
+        for field_name in field_names {
+            match field_name.as_str() {
+                "subject" => fields.push((field_name, self.email_index_schema.fields.subject)),
+                // NOTE: to is tokenized into address fragments such as "example" and "com", so suggest whole
+                // addresses from the recipient facet instead
+                "to" => fields.push((field_name, self.email_index_schema.fields.recipient)),
+                _ => {
+                    return Ok(SuggestResponse::error(
+                        format!("{field_name} does not support suggestions").as_str(),
+                    ))
+                }
+            }
+        }
+
+        if prefix.chars().count() < MIN_PREFIX_CHARS {
+            return Ok(SuggestResponse::error(
+                format!("prefix must be at least {MIN_PREFIX_CHARS} characters").as_str(),
+            ));
+        }
+
+        let snapshot = self.snapshot();
+        let mut doc_freqs: HashMap<(String, String), u32> = HashMap::new();
+
+        let segment_readers = snapshot
+            .searchers
+            .iter()
+            .flat_map(|searcher| searcher.segment_readers());
+        for segment_reader in segment_readers {
+            for (field_name, field) in &fields {
+                let inverted_index = segment_reader.inverted_index(*field)?;
+                let mut stream = inverted_index
+                    .terms()
+                    .range()
+                    .ge(prefix.as_bytes())
+                    .into_stream()?;
+
+                while stream.advance() {
+                    if !stream.key().starts_with(prefix.as_bytes()) {
+                        break;
+                    }
+
+                    let term = if *field == self.email_index_schema.fields.recipient {
+                        let facet = Facet::from_encoded(stream.key().to_vec())?;
+                        facet.to_path().join("/")
+                    } else {
+                        String::from_utf8_lossy(stream.key()).to_string()
+                    };
+                    // NOTE: doc_freq counts deleted documents until their segment is merged away
+                    let doc_freq = match segment_reader.alive_bitset() {
+                        Some(alive_bitset) => inverted_index
+                            .read_postings_from_terminfo(stream.value(), IndexRecordOption::Basic)?
+                            .count(alive_bitset),
+                        None => stream.value().doc_freq,
+                    };
+                    *doc_freqs.entry((field_name.clone(), term)).or_insert(0) += doc_freq;
+                }
+            }
+        }
+
+        let mut suggestions: Vec<Suggestion> = doc_freqs
+            .into_iter()
+            .filter(|(_, doc_freq)| *doc_freq > 0)
+            .map(|((field, term), doc_freq)| Suggestion {
+                field,
+                term,
+                doc_freq,
+            })
+            .collect();
+
+        suggestions.sort_by(|a, b| b.doc_freq.cmp(&a.doc_freq).then(a.term.cmp(&b.term)));
+        suggestions.truncate(limit);
+
+        Ok(SuggestResponse::success(suggestions))
+    }
+
+    fn contacts(&self, request: ContactsRequest) -> Result<ContactsResponse> {
+        let snapshot = self.snapshot();
+        let prefix = request.prefix.unwrap_or_default();
+        let limit: usize = request.limit.unwrap_or(10);
+
+        Ok(ContactsResponse::success(
+            snapshot.contacts.len(),
+            snapshot.contacts.search(prefix.as_str(), limit),
+        ))
+    }
+
+    /// Hydrates the emails of `ids` in order, reading only the ones that aren't cached from the
+    /// table.
+    async fn get_emails(&self, ids: &[String], fields: Option<&[String]>) -> Result<Vec<Email>> {
+        self.email_table
+            .as_ref()
+            .context("full mode requires an email table")?;
+
+        let fields_key = match fields {
+            Some(fields) => {
+                let mut fields = fields.to_vec();
+                fields.sort();
+                fields.dedup();
+                fields.join(",")
+            }
+            None => "*".to_string(),
+        };
+        let cache_key = |id: &str| format!("{fields_key}:{id}");
+
+        let mut emails: HashMap<String, Email> = HashMap::new();
+        let mut missing_ids: Vec<String> = vec![];
+        for id in ids {
+            match self.email_cache.get(&cache_key(id)) {
+                Some(email) => {
+                    emails.insert(id.clone(), email);
+                }
+                None => missing_ids.push(id.clone()),
+            }
+        }
+
+        if !missing_ids.is_empty() {
+            for email in self.batch_get_items(&missing_ids, fields).await? {
+                self.email_cache.put(cache_key(&email.id), email.clone());
+                emails.insert(email.id.clone(), email);
+            }
+        }
+
+        // NOTE: Emails deleted from the table since they were indexed are left out
+        Ok(ids.iter().filter_map(|id| emails.remove(id)).collect())
+    }
+
+    async fn batch_get_items(
+        &self,
+        ids: &[String],
+        fields: Option<&[String]>,
+    ) -> Result<Vec<Email>> {
+        let email_table = self
+            .email_table
+            .as_ref()
+            .context("full mode requires an email table")?;
+        let mut emails: Vec<Email> = vec![];
+
+        for batch in ids.chunks(100) {
+            let mut keys: Vec<HashMap<String, AttributeValue>> = vec![];
+
+            for id in batch {
+                let item = HashMap::from([("id".to_owned(), AttributeValue::S(id.to_owned()))]);
+                keys.push(item);
+            }
+
+            let mut keys_and_attributes = KeysAndAttributes::builder().set_keys(Some(keys));
+
+            if let Some(fields) = fields {
+                // NOTE: timestamp, to and ttl are reserved words, so every field goes through an attribute name placeholder
+                let mut projection: Vec<String> = vec!["#id".to_string()];
+                keys_and_attributes = keys_and_attributes.expression_attribute_names("#id", "id");
+
+                for field in fields {
+                    let placeholder = format!("#{field}");
+                    if projection.contains(&placeholder) {
+                        continue;
+                    }
+
+                    keys_and_attributes =
+                        keys_and_attributes.expression_attribute_names(&placeholder, field);
+                    projection.push(placeholder);
+                }
+
+                keys_and_attributes =
+                    keys_and_attributes.projection_expression(projection.join(", "));
+            }
+
+            let response = email_table
+                .ddb
+                .batch_get_item()
+                .request_items(email_table.table_name.clone(), keys_and_attributes.build())
+                .send()
+                .await?;
+
+            if let Some(response) = response.responses() {
+                if let Some(rows) = response.get(email_table.table_name.as_str()) {
+                    for attributes in rows {
+                        let email = Email::from(attributes)?;
+                        emails.push(email);
+                    }
+                }
+            }
+        }
+
+        Ok(emails)
+    }
+}
+
+/// The segments of the latest commit of an index.
+fn partition_versions(
+    email_index_schema: &EmailIndexSchema,
+) -> Result<Vec<(String, Option<String>)>> {
+    email_index_schema
+        .partition_keys()?
+        .into_iter()
+        .map(|key| {
+            let partition_version = email_index_schema.partition_version(&key)?;
+            Ok((key, partition_version))
+        })
+        .collect()
+}
+
+fn index_segment_keys(index: &Index) -> Result<Vec<SegmentKey>> {
+    let mut segment_keys: Vec<SegmentKey> = index
+        .searchable_segment_metas()?
+        .iter()
+        .map(|segment_meta| (segment_meta.id(), segment_meta.delete_opstamp()))
+        .collect();
+    segment_keys.sort();
+    Ok(segment_keys)
+}
+
+/// The contacts the writer last committed to a partition. The commit may be newer than the
+/// searcher, in which case the next reload catches the searcher up. Partitions committed before
+/// contacts were have them read from their segments instead.
+fn partition_contacts(
+    index: &Index,
+    searcher: &Searcher,
+    fields: &EmailIndexFields,
+) -> Result<Arc<ContactDirectory>> {
+    let contacts = match CommitPayload::load(index)?.contacts {
+        Some(contacts) => contacts,
+        None => ContactDirectory::from_searcher(searcher, fields.contact, fields.timestamp)?,
+    };
+    Ok(Arc::new(contacts))
+}
+
+/// The segments a searcher searches.
+fn searcher_segment_keys(searcher: &Searcher) -> Vec<SegmentKey> {
+    let mut segment_keys: Vec<SegmentKey> = searcher
+        .segment_readers()
+        .iter()
+        .map(|segment_reader| (segment_reader.segment_id(), segment_reader.delete_opstamp()))
+        .collect();
+    segment_keys.sort();
+    segment_keys
+}
+
+/// Counts the emails of the searchers, which are the documents that aren't tombstones.
+fn num_docs<'a>(searchers: impl Iterator<Item = &'a Searcher>, ttl: Field) -> Result<u64> {
+    let tombstone = RangeQuery::new_i64(ttl, TOMBSTONE_TTL..TOMBSTONE_TTL + 1);
+    let mut num_docs = 0_u64;
+    for searcher in searchers {
+        num_docs += searcher.num_docs() - searcher.search(&tombstone, &Count)? as u64;
+    }
+    Ok(num_docs)
+}
+
+/// The range of timestamps `[start, end)` a query can match, from the timestamp ranges it
+/// requires. Either end is None when the query doesn't bound it.
+fn timestamp_range(query: &dyn Query, timestamp: Field) -> (Option<i64>, Option<i64>) {
+    if let Some(range_query) = query.downcast_ref::<RangeQuery>() {
+        if range_query.field() != timestamp {
+            return (None, None);
+        }
+
+        let start = match range_query.left_bound() {
+            Bound::Included(term) => term.as_i64(),
+            Bound::Excluded(term) => term.as_i64().map(|start| start.saturating_add(1)),
+            Bound::Unbounded => None,
+        };
+        let end = match range_query.right_bound() {
+            Bound::Included(term) => term.as_i64().map(|end| end.saturating_add(1)),
+            Bound::Excluded(term) => term.as_i64(),
+            Bound::Unbounded => None,
+        };
+        return (start, end);
+    }
+
+    if let Some(boolean_query) = query.downcast_ref::<BooleanQuery>() {
+        let clauses = boolean_query.clauses();
+
+        // NOTE: Optional clauses don't have to match once any clause is required, so only required clauses
+        // narrow the range, and without them any of the optional clauses can match
+        if clauses.iter().any(|(occur, _)| *occur == Occur::Must) {
+            let mut range: (Option<i64>, Option<i64>) = (None, None);
+            for (occur, query) in clauses {
+                if *occur == Occur::Must {
+                    let (start, end) = timestamp_range(&**query, timestamp);
+                    range.0 = range.0.max(start);
+                    range.1 = match (range.1, end) {
+                        (Some(a), Some(b)) => Some(a.min(b)),
+                        (a, b) => a.or(b),
+                    };
+                }
+            }
+            return range;
+        }
+
+        let mut ranges = clauses
+            .iter()
+            .filter(|(occur, _)| *occur == Occur::Should)
+            .map(|(_, query)| timestamp_range(&**query, timestamp));
+        if let Some(first) = ranges.next() {
+            return ranges.fold(first, |range, (start, end)| {
+                (
+                    range.0.and_then(|a| start.map(|b| a.min(b))),
+                    range.1.and_then(|a| end.map(|b| a.max(b))),
+                )
+            });
+        }
+    }
+
+    (None, None)
+}
+
+/// The collectors run against every partition of a search.
+struct PartitionSearch<'a> {
+    query: &'a dyn Query,
+    top_docs_limit: Option<usize>,
+    sort: SearchSort,
+    timestamp_field: Field,
+    date_interval: Option<DateInterval>,
+    terms_field: Option<Field>,
+}
+
+/// What one partition contributes to a search, merged across partitions afterwards.
+struct PartitionFruit {
+    count: usize,
+    /// The top documents with their timestamp when sorted by it.
+    top_docs: Vec<(Option<i64>, Score, DocAddress)>,
+    date_histogram: BTreeMap<i64, u64>,
+    terms: Vec<(String, u64)>,
+}
+
+impl PartitionSearch<'_> {
+    fn run(&self, searcher: &Searcher) -> tantivy::Result<PartitionFruit> {
+        let mut collectors = MultiCollector::new();
+        let (score_handle, timestamp_handle) = match (self.top_docs_limit, self.sort) {
+            (None, _) => (None, None),
+            (Some(limit), SearchSort::Score) => (
+                Some(collectors.add_collector(TopDocs::with_limit(limit))),
+                None,
+            ),
+            (Some(limit), SearchSort::Timestamp) => {
+                let timestamp_field = self.timestamp_field;
+                let top_docs = TopDocs::with_limit(limit).tweak_score(
+                    move |segment_reader: &SegmentReader| {
+                        let timestamps = segment_reader
+                            .fast_fields()
+                            .i64(timestamp_field)
+                            .expect("timestamp should be a fast field");
+                        move |doc: DocId, score: Score| (timestamps.get(doc), score)
+                    },
+                );
+                (None, Some(collectors.add_collector(top_docs)))
+            }
+        };
+        let count_handle = collectors.add_collector(Count);
+        let date_histogram_handle = self.date_interval.map(|interval| {
+            collectors.add_collector(DateHistogramCollector::new(self.timestamp_field, interval))
+        });
+        let terms_handle = self.terms_field.map(|field| {
+            let mut facet_collector = FacetCollector::for_field(field);
+            facet_collector.add_facet("/");
+            collectors.add_collector(facet_collector)
+        });
+
+        let mut fruits = searcher.search(self.query, &collectors)?;
+
+        Ok(PartitionFruit {
+            count: count_handle.extract(&mut fruits),
+            top_docs: match (score_handle, timestamp_handle) {
+                (Some(handle), _) => handle
+                    .extract(&mut fruits)
+                    .into_iter()
+                    .map(|(score, doc_address)| (None, score, doc_address))
+                    .collect(),
+                (_, Some(handle)) => handle
+                    .extract(&mut fruits)
+                    .into_iter()
+                    .map(|((timestamp, score), doc_address)| (Some(timestamp), score, doc_address))
+                    .collect(),
+                _ => vec![],
+            },
+            date_histogram: date_histogram_handle
+                .map(|handle| handle.extract(&mut fruits))
+                .unwrap_or_default(),
+            terms: terms_handle
+                .map(|handle| {
+                    handle
+                        .extract(&mut fruits)
+                        .get("/")
+                        .map(|(facet, doc_count)| (facet.to_path().join("/"), doc_count))
+                        .collect()
+                })
+                .unwrap_or_default(),
+        })
+    }
+}
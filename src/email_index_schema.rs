@@ -1,21 +1,63 @@
+use crate::commit_payload::CommitPayload;
+use crate::index_checkpoint::IndexCheckpoint;
+use crate::index_partitioning::{fnv1a, Partitioning, UNPARTITIONED_KEY};
+use crate::index_storage::IndexStorage;
+use crate::s3_directory::S3Directory;
 use anyhow::{Context, Result};
-use log::info;
-use std::{path::PathBuf, str::FromStr};
+use log::{info, warn};
+use std::{
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
 use tantivy::{
-    schema::{Field, Schema, INDEXED, STORED, STRING, TEXT},
-    Index,
+    directory::error::OpenReadError,
+    schema::{FacetOptions, Field, Schema, FAST, INDEXED, STORED, STRING, TEXT},
+    Directory, Index, IndexSettings,
 };
+
+const PARTITIONS_DIR: &str = "partitions";
+const CHECKPOINT_FILE: &str = "checkpoint.json";
+const GENERATIONS_DIR: &str = "generations";
+const CURRENT_GENERATION_FILE: &str = "current_generation";
+
+/// The ttl of the tombstones removed emails leave behind, which every search excludes as expired.
+pub const TOMBSTONE_TTL: i64 = i64::MIN;
+
+#[derive(Clone)]
 pub struct EmailIndexSchema {
     pub schema: Schema,
     pub fields: EmailIndexFields,
+    pub partitioning: Partitioning,
+    pub storage: IndexStorage,
+    /// The generation the index is kept in, None for the root of the storage.
+    pub generation: Option<String>,
+    /// Whether `refresh_generation` moves on to the generation the storage currently points to.
+    pub follow_current_generation: bool,
 }
 
+#[derive(Clone)]
 pub struct EmailIndexFields {
     pub id: Field,
     pub timestamp: Field,
     pub subject: Field,
     pub body: Field,
     pub to: Field,
+    pub recipient: Field,
+    pub domain: Field,
+    pub contact: Field,
+    pub ttl: Field,
+    /// The item's `version` attribute, 0 without one.
+    pub version: Field,
+    /// When the change that indexed the document was made (unix milliseconds).
+    pub changed_at: Field,
+}
+
+impl Default for EmailIndexSchema {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl EmailIndexSchema {
@@ -23,10 +65,16 @@ impl EmailIndexSchema {
         let mut builder = Schema::builder();
 
         let id = builder.add_text_field("id", STRING | STORED);
-        let timestamp = builder.add_i64_field("timestamp", INDEXED); // ADD FAST FIELD. Also test if indexed is needed
+        let timestamp = builder.add_i64_field("timestamp", INDEXED | FAST);
         let subject = builder.add_text_field("subject", TEXT);
         let body = builder.add_text_field("body", TEXT);
         let to = builder.add_text_field("to", TEXT);
+        let recipient = builder.add_facet_field("recipient", FacetOptions::default());
+        let domain = builder.add_facet_field("domain", FacetOptions::default());
+        let contact = builder.add_facet_field("contact", FacetOptions::default());
+        let ttl = builder.add_i64_field("ttl", INDEXED | FAST);
+        let version = builder.add_i64_field("version", FAST);
+        let changed_at = builder.add_i64_field("changed_at", INDEXED | FAST);
 
         let schema = builder.build();
 
@@ -36,9 +84,29 @@ impl EmailIndexSchema {
             to,
             body,
             subject,
+            recipient,
+            domain,
+            contact,
+            ttl,
+            version,
+            changed_at,
         };
 
-        EmailIndexSchema { schema, fields }
+        EmailIndexSchema {
+            schema,
+            fields,
+            partitioning: Partitioning::default(),
+            storage: IndexStorage::default(),
+            generation: None,
+            follow_current_generation: false,
+        }
+    }
+
+    pub fn with_partitioning(self, partitioning: Partitioning) -> Self {
+        EmailIndexSchema {
+            partitioning,
+            ..self
+        }
     }
 
     pub fn default_fields(&self) -> Vec<Field> {
@@ -51,36 +119,484 @@ impl EmailIndexSchema {
         ]
     }
 
+    pub fn with_storage(self, storage: IndexStorage) -> Self {
+        EmailIndexSchema { storage, ..self }
+    }
+
+    /// Keeps the index under `generations/<generation>` of the storage, so a restore can write a
+    /// whole new index next to the one in use.
+    pub fn with_generation(self, generation: Option<String>) -> Self {
+        EmailIndexSchema {
+            generation,
+            follow_current_generation: false,
+            ..self
+        }
+    }
+
+    /// Uses the generation the storage currently points to, which a restore switches once it has
+    /// written every partition, and keeps following it through `refresh_generation`.
+    pub fn with_current_generation(self) -> Result<Self> {
+        let generation = self
+            .read_file(Path::new(CURRENT_GENERATION_FILE))?
+            .map(String::from_utf8)
+            .transpose()
+            .context("Current generation is not valid")?;
+        Ok(EmailIndexSchema {
+            follow_current_generation: true,
+            ..self.with_generation(generation)
+        })
+    }
+
+    /// Moves on to the generation the storage currently points to, if the schema follows it.
+    /// Warm functions call it as they go, so they pick a restore up without a redeploy.
+    pub fn refresh_generation(self) -> Result<Self> {
+        if !self.follow_current_generation {
+            return Ok(self);
+        }
+
+        self.with_current_generation()
+    }
+
+    /// Points the storage to this schema's generation.
+    pub fn make_current_generation(&self) -> Result<()> {
+        let generation = self
+            .generation
+            .clone()
+            .context("Index has no generation to make current")?;
+        self.write_file(Path::new(CURRENT_GENERATION_FILE), generation.into_bytes())
+    }
+
+    /// Keeps the index in a local directory instead of under `EFS_MOUNT_PATH`.
+    pub fn in_dir(path: impl Into<PathBuf>) -> Self {
+        EmailIndexSchema::new().with_storage(IndexStorage::Path(path.into()))
+    }
+
+    /// Keeps the index in memory.
+    pub fn in_ram() -> Self {
+        EmailIndexSchema::new().with_storage(IndexStorage::Ram(Arc::default()))
+    }
+
     pub fn ensure_index(&self) -> Result<Index> {
-        let index_path = self.get_index_path()?;
+        self.ensure_at(&self.generation_relative_path(UNPARTITIONED_KEY))
+    }
 
-        let index: Index;
-        if !index_path.exists() {
-            info!("creating index");
-            std::fs::create_dir(&index_path).context("Error creating index dir")?;
-            index = Index::create_in_dir(&index_path, self.schema.clone())
-                .context("Error creating index")?;
-        } else {
-            info!("opening index");
-            index = self.open().context("Error opening index")?;
+    pub fn ensure_partition(&self, key: &str) -> Result<Index> {
+        self.ensure_at(&self.partition_relative_path(key))
+    }
+
+    pub fn open_partition(&self, key: &str) -> Result<Index> {
+        self.open_at(&self.partition_relative_path(key))
+    }
+
+    /// Opens a partition without checking its schema fingerprint, for tools that inspect indexes
+    /// made with an older schema.
+    pub fn open_partition_unchecked(&self, key: &str) -> Result<Index> {
+        self.open_unchecked_at(&self.partition_relative_path(key))
+    }
+
+    fn exists_at(&self, relative_path: &Path) -> Result<bool> {
+        let exists = match &self.storage {
+            IndexStorage::Mount | IndexStorage::Path(_) => self
+                .get_mount_path()?
+                .join(relative_path)
+                .join("meta.json")
+                .exists(),
+            IndexStorage::S3 { .. } => Index::exists(&self.s3_directory(relative_path)?)?,
+            IndexStorage::Ram(ram_storage) => {
+                ram_storage.contains(relative_path)
+                    && Index::exists(&ram_storage.directory(relative_path))?
+            }
+        };
+
+        Ok(exists)
+    }
+
+    fn ensure_at(&self, relative_path: &Path) -> Result<Index> {
+        if self.exists_at(relative_path)? {
+            info!("opening index {:?}", relative_path);
+            return self.open_at(relative_path);
         }
 
-        Ok(index)
+        info!("creating index {:?}", relative_path);
+        let index = match &self.storage {
+            IndexStorage::Mount | IndexStorage::Path(_) => {
+                let index_path = self.get_mount_path()?.join(relative_path);
+                std::fs::create_dir_all(&index_path).context("Error creating index dir")?;
+                Index::create_in_dir(&index_path, self.schema.clone())
+            }
+            IndexStorage::S3 { .. } => Index::create(
+                self.s3_directory(relative_path)?,
+                self.schema.clone(),
+                IndexSettings::default(),
+            ),
+            IndexStorage::Ram(ram_storage) => Index::create(
+                ram_storage.directory(relative_path),
+                self.schema.clone(),
+                IndexSettings::default(),
+            ),
+        };
+
+        index.context("Error creating index")
     }
 
-    fn open(&self) -> Result<Index> {
-        let index_path = self.get_index_path()?;
-        let index = Index::open_in_dir(&index_path).context("Error opening index")?;
+    fn open_at(&self, relative_path: &Path) -> Result<Index> {
+        let index = self.open_unchecked_at(relative_path)?;
+
+        // NOTE: Fields added since an index was created (such as the timestamp fast field and facets) are missing
+        // from its segments, so fail here rather than on the first query that needs them
+        let fingerprint = schema_fingerprint(&index.schema());
+        if fingerprint != self.fingerprint() {
+            return Err(anyhow::anyhow!(
+                "Index {:?} has schema fingerprint {fingerprint}, expected {}. Rebuild it from the table with the audit tool's --reindex option",
+                relative_path,
+                self.fingerprint()
+            ));
+        }
 
         Ok(index)
     }
 
-    fn get_index_path(&self) -> Result<PathBuf> {
+    fn open_unchecked_at(&self, relative_path: &Path) -> Result<Index> {
+        let index = match &self.storage {
+            IndexStorage::Mount | IndexStorage::Path(_) => {
+                Index::open_in_dir(self.get_mount_path()?.join(relative_path))
+            }
+            IndexStorage::S3 { .. } => Index::open(self.s3_directory(relative_path)?),
+            IndexStorage::Ram(ram_storage) => {
+                if !ram_storage.contains(relative_path) {
+                    return Err(anyhow::anyhow!("Index {:?} does not exist", relative_path));
+                }
+                Index::open(ram_storage.directory(relative_path))
+            }
+        };
+        index.context("Error opening index")
+    }
+
+    fn s3_directory(&self, relative_path: &Path) -> Result<S3Directory> {
+        match &self.storage {
+            IndexStorage::S3 {
+                store,
+                lock_table,
+                cache,
+            } => Ok(S3Directory::open(
+                store.clone(),
+                lock_table.clone(),
+                cache.clone(),
+                &relative_path.to_string_lossy(),
+            )?),
+            _ => Err(anyhow::anyhow!("Index is not stored in S3")),
+        }
+    }
+
+    /// Lists the keys of the partitions that exist, oldest first.
+    pub fn partition_keys(&self) -> Result<Vec<String>> {
+        // NOTE: Readers only have read access to the storage, so they leave creating the index to the writer
+        if !self.partitioning.is_partitioned() {
+            let exists = self.exists_at(&self.generation_relative_path(UNPARTITIONED_KEY))?;
+            return Ok(exists
+                .then(|| UNPARTITIONED_KEY.to_string())
+                .into_iter()
+                .collect());
+        }
+
+        let mut keys: Vec<String> = vec![];
+        let partitions_dir = self.generation_relative_path(PARTITIONS_DIR);
+        let partitions_prefix = format!("{}/", partitions_dir.to_string_lossy());
+
+        match &self.storage {
+            IndexStorage::Mount | IndexStorage::Path(_) => {
+                let partitions_path = self.get_mount_path()?.join(&partitions_dir);
+                if !partitions_path.exists() {
+                    return Ok(vec![]);
+                }
+
+                for entry in std::fs::read_dir(&partitions_path)? {
+                    let entry = entry?;
+                    if entry.path().join("meta.json").exists() {
+                        keys.push(entry.file_name().to_string_lossy().to_string());
+                    }
+                }
+            }
+            IndexStorage::S3 { store, .. } => {
+                for object in store.list(&partitions_prefix)? {
+                    let key = object
+                        .key
+                        .strip_prefix(&partitions_prefix)
+                        .and_then(|key| key.strip_suffix("/meta.json"));
+                    if let Some(key) = key {
+                        keys.push(key.to_string());
+                    }
+                }
+            }
+            IndexStorage::Ram(ram_storage) => {
+                for path in ram_storage.paths() {
+                    if let Ok(key) = path.strip_prefix(&partitions_dir) {
+                        if Index::exists(&ram_storage.directory(&path))? {
+                            keys.push(key.to_string_lossy().to_string());
+                        }
+                    }
+                }
+            }
+        }
+
+        keys.sort();
+        Ok(keys)
+    }
+
+    /// Deletes a partition and every file in it.
+    pub fn drop_partition(&self, key: &str) -> Result<()> {
+        let relative_path = self.partition_relative_path(key);
+
+        match &self.storage {
+            IndexStorage::Mount | IndexStorage::Path(_) => {
+                std::fs::remove_dir_all(self.get_mount_path()?.join(&relative_path))?
+            }
+            IndexStorage::Ram(ram_storage) => ram_storage.remove(&relative_path),
+            IndexStorage::S3 { store, cache, .. } => {
+                // NOTE: Delete meta.json first, so the partition stops being listed even if this fails part way
+                let prefix = format!("{}/", relative_path.to_string_lossy());
+                store.delete(&format!("{prefix}meta.json"))?;
+                for object in store.list(&prefix)? {
+                    store.delete(&object.key)?;
+                }
+
+                cache.remove_dir(&cache.root().join(&relative_path))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the total size of the files of a partition.
+    pub fn partition_size_bytes(&self, key: &str) -> Result<u64> {
+        let relative_path = self.partition_relative_path(key);
+        let mut size_bytes = 0_u64;
+
+        match &self.storage {
+            IndexStorage::Mount | IndexStorage::Path(_) => {
+                for entry in std::fs::read_dir(self.get_mount_path()?.join(&relative_path))? {
+                    let metadata = entry?.metadata()?;
+                    if metadata.is_file() {
+                        size_bytes += metadata.len();
+                    }
+                }
+            }
+            IndexStorage::S3 { store, .. } => {
+                for object in store.list(&format!("{}/", relative_path.to_string_lossy()))? {
+                    size_bytes += object.size;
+                }
+            }
+            IndexStorage::Ram(ram_storage) => size_bytes += ram_storage.size_bytes(&relative_path),
+        }
+
+        Ok(size_bytes)
+    }
+
+    /// Returns a value that changes whenever the partition commits, or None if it has no commit yet.
+    pub fn partition_version(&self, key: &str) -> Result<Option<String>> {
+        let meta_path = self.partition_relative_path(key).join("meta.json");
+
+        match &self.storage {
+            IndexStorage::Mount | IndexStorage::Path(_) => {
+                let meta_path = self.get_mount_path()?.join(meta_path);
+                if !meta_path.exists() {
+                    return Ok(None);
+                }
+
+                let metadata = std::fs::metadata(&meta_path)?;
+                Ok(Some(format!(
+                    "{:?}/{}",
+                    metadata.modified()?,
+                    metadata.len()
+                )))
+            }
+            IndexStorage::S3 { store, .. } => Ok(store
+                .head(&meta_path.to_string_lossy())?
+                .map(|object| object.etag.unwrap_or_default())),
+            IndexStorage::Ram(ram_storage) => {
+                let relative_path = self.partition_relative_path(key);
+                if !ram_storage.contains(&relative_path) {
+                    return Ok(None);
+                }
+
+                match ram_storage
+                    .directory(&relative_path)
+                    .atomic_read(Path::new("meta.json"))
+                {
+                    Ok(meta) => Ok(Some(fnv1a(&meta).to_string())),
+                    Err(OpenReadError::FileDoesNotExist(_)) => Ok(None),
+                    Err(error) => Err(error.into()),
+                }
+            }
+        }
+    }
+
+    /// Removes cached files the partition no longer uses. Does nothing unless stored in S3.
+    pub fn prune_cache(&self, key: &str, index: &Index) -> Result<()> {
+        if let IndexStorage::S3 { cache, .. } = &self.storage {
+            cache.prune(index, &cache.root().join(self.partition_relative_path(key)))?;
+        }
+
+        Ok(())
+    }
+
+    fn partition_relative_path(&self, key: &str) -> PathBuf {
+        if !self.partitioning.is_partitioned() {
+            return self.generation_relative_path(UNPARTITIONED_KEY);
+        }
+
+        self.generation_relative_path(PARTITIONS_DIR).join(key)
+    }
+
+    fn generation_relative_path(&self, path: &str) -> PathBuf {
+        match &self.generation {
+            Some(generation) => PathBuf::from(GENERATIONS_DIR).join(generation).join(path),
+            None => PathBuf::from(path),
+        }
+    }
+
+    /// Loads the stream position of every shard. Indexes written before the writer saved a
+    /// checkpoint fall back to the newest commit payload of their partitions.
+    pub fn load_checkpoint(&self) -> Result<IndexCheckpoint> {
+        if let Some(json) = self.read_file(&self.generation_relative_path(CHECKPOINT_FILE))? {
+            return IndexCheckpoint::parse(&json);
+        }
+
+        let mut commit_payload = CommitPayload::default();
+        for key in self.partition_keys()? {
+            let index = self.open_partition(&key)?;
+            commit_payload.merge(&CommitPayload::load(&index)?);
+        }
+
+        let mut checkpoint = IndexCheckpoint::default();
+        for shard in 0..self.partitioning.num_shards() {
+            checkpoint.observe(shard, &commit_payload);
+        }
+
+        Ok(checkpoint)
+    }
+
+    /// Saves the checkpoint, keeping the newer position of any shard a concurrent writer saved since
+    /// it was loaded. Positions can still go back when two writers save at once, which only makes
+    /// readers wait longer.
+    pub fn save_checkpoint(&self, checkpoint: &IndexCheckpoint) -> Result<()> {
+        let checkpoint_path = self.generation_relative_path(CHECKPOINT_FILE);
+        let mut checkpoint = checkpoint.clone();
+        if let Some(json) = self.read_file(&checkpoint_path)? {
+            checkpoint.merge(&IndexCheckpoint::parse(&json)?);
+        }
+
+        self.write_file(&checkpoint_path, checkpoint.to_json()?)
+    }
+
+    /// Forgets the stream position of every shard, for when documents were lost and have to be
+    /// indexed again.
+    pub fn reset_checkpoint(&self) -> Result<()> {
+        self.write_file(
+            &self.generation_relative_path(CHECKPOINT_FILE),
+            IndexCheckpoint::default().to_json()?,
+        )
+    }
+
+    fn read_file(&self, relative_path: &Path) -> Result<Option<Vec<u8>>> {
+        match &self.storage {
+            IndexStorage::Mount | IndexStorage::Path(_) => {
+                let path = self.get_mount_path()?.join(relative_path);
+                if !path.exists() {
+                    return Ok(None);
+                }
+
+                let data =
+                    std::fs::read(&path).with_context(|| format!("Error reading {path:?}"))?;
+                Ok(Some(data))
+            }
+            IndexStorage::S3 { store, .. } => store.get(&relative_path.to_string_lossy()),
+            IndexStorage::Ram(ram_storage) => Ok(ram_storage.read_file(relative_path)),
+        }
+    }
+
+    fn write_file(&self, relative_path: &Path, data: Vec<u8>) -> Result<()> {
+        match &self.storage {
+            IndexStorage::Mount | IndexStorage::Path(_) => {
+                // NOTE: Write to a temp file and rename so readers never see a partially written file, with a
+                // unique name as writers of different shards can save at the same time
+                let path = self.get_mount_path()?.join(relative_path);
+                if let Some(parent) = path.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                let nanos = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|duration| duration.as_nanos())
+                    .unwrap_or_default();
+                let temp_path = path.with_extension(format!("{nanos}.tmp"));
+                std::fs::write(&temp_path, data)
+                    .with_context(|| format!("Error writing {temp_path:?}"))?;
+                std::fs::rename(&temp_path, &path)
+                    .with_context(|| format!("Error renaming {temp_path:?}"))?;
+                Ok(())
+            }
+            IndexStorage::S3 { store, .. } => store.put(&relative_path.to_string_lossy(), data),
+            IndexStorage::Ram(ram_storage) => {
+                ram_storage.write_file(relative_path, data);
+                Ok(())
+            }
+        }
+    }
+
+    /// A stable FNV-1a hash of the schema definition, used to detect an index built with a
+    /// different schema.
+    pub fn fingerprint(&self) -> String {
+        schema_fingerprint(&self.schema)
+    }
+
+    /// Returns the directory of a partition kept on a file system.
+    /// Lists the names of the files of a partition, which may include files no commit refers to.
+    pub fn partition_files(&self, key: &str) -> Result<Vec<PathBuf>> {
+        let relative_path = self.partition_relative_path(key);
+        let mut files: Vec<PathBuf> = vec![];
+
+        match &self.storage {
+            IndexStorage::Mount | IndexStorage::Path(_) => {
+                for entry in std::fs::read_dir(self.get_mount_path()?.join(&relative_path))? {
+                    let entry = entry?;
+                    // NOTE: Tantivy only writes files, so leave anything else for a person to look at
+                    if !entry.file_type()?.is_file() {
+                        warn!("unexpected directory {:?}", entry.path());
+                        continue;
+                    }
+                    files.push(PathBuf::from(entry.file_name()));
+                }
+            }
+            IndexStorage::S3 { store, .. } => {
+                let prefix = format!("{}/", relative_path.to_string_lossy());
+                for object in store.list(&prefix)? {
+                    if let Some(name) = object.key.strip_prefix(&prefix) {
+                        files.push(PathBuf::from(name));
+                    }
+                }
+            }
+            IndexStorage::Ram(_) => {
+                return Err(anyhow::anyhow!("Index files in memory can't be listed"));
+            }
+        }
+
+        Ok(files)
+    }
+
+    fn get_mount_path(&self) -> Result<PathBuf> {
+        if let IndexStorage::Path(path) = &self.storage {
+            return Ok(path.clone());
+        }
+
         let mount_path =
             std::env::var("EFS_MOUNT_PATH").context("EFS_MOUNT_PATH env var missing")?;
 
         let path = PathBuf::from_str(mount_path.as_str()).context("EFS_MOUNT_PATH is not valid")?;
-        let index_path = path.join(PathBuf::from("index"));
-        Ok(index_path)
+        Ok(path)
     }
 }
+
+pub fn schema_fingerprint(schema: &Schema) -> String {
+    let json = serde_json::to_string(schema).unwrap_or_default();
+    format!("{:016x}", fnv1a(json.as_bytes()))
+}
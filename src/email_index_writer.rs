@@ -0,0 +1,879 @@
+use crate::commit_payload::{unix_now, CommitPayload};
+use crate::contact_directory::{contact_facet, parse_mailbox, ContactDirectory};
+use crate::email_change::EmailChange;
+use crate::email_index_schema::{EmailIndexFields, EmailIndexSchema, TOMBSTONE_TTL};
+use crate::merge_settings::MergeSettings;
+use crate::stream_records;
+use crate::writer_request::{WriterCommand, WriterRequest};
+use anyhow::Result;
+use aws_lambda_events::dynamodb::{attributes::AttributeValue, Event, EventRecord};
+use log::{debug, info};
+use serde_json::{json, Value};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    time::{Duration, Instant},
+};
+use tantivy::{
+    collector::DocSetCollector,
+    directory::error::LockError,
+    doc,
+    merge_policy::NoMergePolicy,
+    query::{BooleanQuery, Occur, Query, RangeQuery},
+    schema::{Facet, IndexRecordOption},
+    DocSet, Document, Index, IndexWriter, LeasedItem, ReloadPolicy, Searcher, SegmentMeta,
+    TantivyError, Term, TERMINATED,
+};
+
+const INDEX_WRITER_MEMORY: usize = 200_000_000;
+// NOTE: Well below the 60 second Lambda timeout, so a batch stuck behind another writer fails and is retried instead of timing out
+const WRITER_LOCK_TIMEOUT: Duration = Duration::from_secs(20);
+const WRITER_LOCK_POLL_INTERVAL: Duration = Duration::from_millis(250);
+const DEFAULT_TARGET_SEGMENTS: usize = 1;
+// NOTE: As long as DynamoDB streams keep records, so replays of a removed email's older changes stay removed
+const TOMBSTONE_RETENTION_SECONDS: i64 = 24 * 60 * 60;
+
+/// The index writer of a partition, and the contacts of its emails as of the documents it has
+/// added and deleted.
+struct PartitionWriter {
+    index_writer: IndexWriter,
+    /// The partition as committed when the writer was opened, to read the documents it deletes.
+    searcher: LeasedItem<Searcher>,
+    contacts: ContactDirectory,
+    deleted_terms: HashSet<Term>,
+    fields: EmailIndexFields,
+}
+
+impl PartitionWriter {
+    fn add_document(&mut self, doc: Document) -> Result<()> {
+        let facets = doc
+            .get_all(self.fields.contact)
+            .filter_map(|value| value.as_facet());
+        let timestamp = doc
+            .get_first(self.fields.timestamp)
+            .and_then(|value| value.as_i64())
+            .unwrap_or(i64::MIN);
+        self.contacts.add_email(facets, timestamp);
+
+        self.index_writer.add_document(doc)?;
+        Ok(())
+    }
+
+    /// Deletes the documents with the term, and stops counting the contacts of the committed ones.
+    fn delete_term(&mut self, term: Term) -> Result<()> {
+        if self.deleted_terms.insert(term.clone()) {
+            for segment_reader in self.searcher.segment_readers() {
+                let inverted_index = segment_reader.inverted_index(term.field())?;
+                let mut postings =
+                    match inverted_index.read_postings(&term, IndexRecordOption::Basic)? {
+                        Some(postings) => postings,
+                        None => continue,
+                    };
+
+                let mut doc = postings.doc();
+                while doc != TERMINATED {
+                    if !segment_reader.is_deleted(doc) {
+                        self.contacts.remove_segment_doc(
+                            segment_reader,
+                            self.fields.contact,
+                            doc,
+                        )?;
+                    }
+                    doc = postings.advance();
+                }
+            }
+        }
+
+        self.index_writer.delete_term(term);
+        Ok(())
+    }
+}
+
+/// The index writers of the partitions touched by a request, opened on first use.
+struct PartitionWriters {
+    merge_settings: MergeSettings,
+    writers: HashMap<String, PartitionWriter>,
+}
+
+impl PartitionWriters {
+    fn new(merge_settings: MergeSettings) -> Self {
+        PartitionWriters {
+            merge_settings,
+            writers: HashMap::new(),
+        }
+    }
+
+    fn get(
+        &mut self,
+        email_index_schema: &EmailIndexSchema,
+        key: &str,
+    ) -> Result<&mut PartitionWriter> {
+        if !self.writers.contains_key(key) {
+            let fields = email_index_schema.fields.clone();
+            let index = email_index_schema.ensure_partition(key)?;
+            let index_writer = open_writer(&index)?;
+            index_writer.set_merge_policy(Box::new(self.merge_settings.merge_policy()));
+
+            // NOTE: Read the partition once holding the writer lock, so no other writer commits to it after
+            let searcher = index
+                .reader_builder()
+                .reload_policy(ReloadPolicy::Manual)
+                .try_into()?
+                .searcher();
+            let contacts = match CommitPayload::load(&index)?.contacts {
+                Some(contacts) => contacts,
+                None => {
+                    ContactDirectory::from_searcher(&searcher, fields.contact, fields.timestamp)?
+                }
+            };
+
+            self.writers.insert(
+                key.to_string(),
+                PartitionWriter {
+                    index_writer,
+                    searcher,
+                    contacts,
+                    deleted_terms: HashSet::new(),
+                    fields,
+                },
+            );
+        }
+
+        Ok(self.writers.get_mut(key).expect("writer was just inserted"))
+    }
+
+    fn contains(&self, key: &str) -> bool {
+        self.writers.contains_key(key)
+    }
+
+    fn commit(&mut self, commit_payload: &mut CommitPayload) -> Result<()> {
+        commit_payload.committed_at = Some(unix_now());
+
+        for (key, partition_writer) in self.writers.iter_mut() {
+            info!("commiting index {key}");
+            // NOTE: Contacts are committed with the documents they count, so they always match
+            let payload = CommitPayload {
+                contacts: Some(partition_writer.contacts.clone()),
+                ..commit_payload.clone()
+            }
+            .to_json()?;
+            let mut prepared_commit = partition_writer.index_writer.prepare_commit()?;
+            prepared_commit.set_payload(&payload);
+            prepared_commit.commit()?;
+        }
+
+        Ok(())
+    }
+
+    fn wait_merging_threads(self) -> Result<()> {
+        for (_, partition_writer) in self.writers {
+            partition_writer.index_writer.wait_merging_threads()?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Whether any committed document of the index has the term, including deleted ones.
+fn contains_term(index: &Index, term: &Term) -> Result<bool> {
+    let index_reader = index
+        .reader_builder()
+        .reload_policy(ReloadPolicy::Manual)
+        .try_into()?;
+    Ok(index_reader.searcher().doc_freq(term)? > 0)
+}
+
+/// Opens the index writer, waiting for a concurrent writer of the same shard to finish.
+fn open_writer(index: &Index) -> Result<IndexWriter> {
+    let start = Instant::now();
+
+    loop {
+        match index.writer(INDEX_WRITER_MEMORY) {
+            Err(TantivyError::LockFailure(LockError::LockBusy, _))
+                if start.elapsed() < WRITER_LOCK_TIMEOUT =>
+            {
+                debug!("waiting for index writer lock");
+                std::thread::sleep(WRITER_LOCK_POLL_INTERVAL);
+            }
+            result => return Ok(result?),
+        }
+    }
+}
+
+/// Applies DynamoDB stream events and maintenance commands to the index partitions of an
+/// `EmailIndexSchema`.
+#[derive(Clone)]
+pub struct EmailIndexWriter {
+    email_index_schema: EmailIndexSchema,
+    merge_settings: MergeSettings,
+}
+
+impl EmailIndexWriter {
+    pub fn new(email_index_schema: EmailIndexSchema) -> Result<EmailIndexWriter> {
+        if !email_index_schema.partitioning.is_partitioned() {
+            email_index_schema.ensure_index()?;
+        }
+
+        Ok(EmailIndexWriter {
+            email_index_schema,
+            merge_settings: MergeSettings::default(),
+        })
+    }
+
+    pub fn with_merge_settings(self, merge_settings: MergeSettings) -> Self {
+        EmailIndexWriter {
+            merge_settings,
+            ..self
+        }
+    }
+
+    pub async fn handle(&self, request: WriterRequest) -> Result<Value> {
+        // NOTE: Committing, waiting for writer locks and merging all block, so keep them off the runtime threads
+        let email_index_writer = self.clone();
+        tokio::task::spawn_blocking(move || {
+            // NOTE: A restore may have switched the generation since the function started
+            let email_index_writer = EmailIndexWriter {
+                email_index_schema: email_index_writer.email_index_schema.refresh_generation()?,
+                ..email_index_writer
+            };
+            email_index_writer.handle_blocking(request)
+        })
+        .await?
+    }
+
+    fn handle_blocking(&self, request: WriterRequest) -> Result<Value> {
+        match request {
+            WriterRequest::Stream(event) => self.index_write(event),
+            WriterRequest::Replay {
+                event,
+                failed_message_ids,
+            } => {
+                // NOTE: SQS only retries the failed messages if the event source reports batch item failures,
+                // otherwise the whole batch is deleted once this returns
+                let mut result = self.index_write(event)?;
+                result["batchItemFailures"] = failed_message_ids
+                    .iter()
+                    .map(|message_id| json!({ "itemIdentifier": message_id }))
+                    .collect();
+                Ok(result)
+            }
+            WriterRequest::Command(WriterCommand::PurgeExpired) => self.purge_expired(),
+            WriterRequest::Command(WriterCommand::Optimize { target_segments }) => {
+                self.optimize(target_segments.unwrap_or(DEFAULT_TARGET_SEGMENTS))
+            }
+        }
+    }
+
+    fn index_write(&self, event: Event) -> Result<Value> {
+        let total = event.records.len() as u32;
+        let now = unix_now();
+
+        let mut created = 0_u32;
+        let mut updated = 0_u32;
+        let mut deleted = 0_u32;
+        let mut expired = 0_u32;
+        let mut stale = 0_u32;
+
+        // NOTE: Replayed records (such as audit repairs) have no sequence number and don't advance the index position.
+        // Kinesis records have none either, but are live, so they still advance how far the index is through.
+        let mut batch_payload = CommitPayload::default();
+        for record in &event.records {
+            if let Some(sequence_number) = &record.change.sequence_number {
+                batch_payload.observe_sequence_number(sequence_number);
+            } else if !stream_records::is_kinesis_record(record) {
+                continue;
+            }
+            batch_payload
+                .observe_creation_time(record.change.approximate_creation_date_time.timestamp());
+        }
+
+        // NOTE: Records of an id always go to the same shard in stream order, and each shard is committed
+        // on its own so its writer lock is held as briefly as possible
+        let mut shards: BTreeMap<u32, Vec<EventRecord>> = BTreeMap::new();
+        for record in event.records {
+            let shard = match parse_string(record_image(&record), "id") {
+                Ok(id) => self.email_index_schema.partitioning.shard_for(&id),
+                Err(_) => 0,
+            };
+            shards.entry(shard).or_default().push(record);
+        }
+
+        // NOTE: Shards without records in the batch are already through it, and are saved with the first shard
+        let mut checkpoint = self.email_index_schema.load_checkpoint()?;
+        for shard in 0..self.email_index_schema.partitioning.num_shards() {
+            if !shards.contains_key(&shard) {
+                checkpoint.observe(shard, &batch_payload);
+            }
+        }
+
+        for (shard, records) in shards {
+            // NOTE: Kinesis delivers changes out of order and more than once, and SQS replays old ones, so only apply
+            // the newest change of each email, and only if it is newer than the one indexed
+            let num_records = records.len() as u32;
+            let records = latest_changes(records);
+            stale += num_records - records.len() as u32;
+            let indexed_changes = self.indexed_changes(shard, &records)?;
+
+            let mut partition_writers = PartitionWriters::new(self.merge_settings);
+
+            for record in records {
+                let change = record_change(&record)?;
+                let is_stale = parse_string(record_image(&record), "id")
+                    .ok()
+                    .and_then(|id| indexed_changes.get(&id))
+                    .is_some_and(|indexed_change| change.is_older_than(indexed_change));
+                if is_stale {
+                    debug!("skipping stale change");
+                    stale += 1;
+                    continue;
+                }
+
+                match record.event_name.as_str() {
+                    "INSERT" => {
+                        let key = self.partition_key(&record.change.new_image)?;
+                        if self.email_index_schema.partitioning.is_expired(&key, now) {
+                            continue;
+                        }
+
+                        // NOTE: A retried batch replays inserts that may already be committed, so replace
+                        // the document rather than adding a second copy
+                        let id = parse_string(&record.change.new_image, "id")?;
+                        let doc = self.parse_document(record.change.new_image, &change)?;
+                        debug!("creating document");
+                        let partition_writer =
+                            partition_writers.get(&self.email_index_schema, &key)?;
+                        partition_writer.delete_term(Term::from_field_text(
+                            self.email_index_schema.fields.id,
+                            &id,
+                        ))?;
+                        partition_writer.add_document(doc)?;
+                        created += 1;
+                    }
+                    "MODIFY" => {
+                        let id = parse_string(&record.change.new_image, "id")?;
+                        self.delete_document(
+                            &mut partition_writers,
+                            &record.change.old_image,
+                            &id,
+                        )?;
+
+                        let key = self.partition_key(&record.change.new_image)?;
+                        if self.email_index_schema.partitioning.is_expired(&key, now) {
+                            continue;
+                        }
+
+                        let doc = self.parse_document(record.change.new_image, &change)?;
+                        debug!("updating document");
+                        partition_writers
+                            .get(&self.email_index_schema, &key)?
+                            .add_document(doc)?;
+                        updated += 1;
+                    }
+                    "REMOVE" => {
+                        // NOTE: Only the id is needed to delete, so removals work with keys only images
+                        let id = parse_string(&record.change.old_image, "id")?;
+                        debug!("deleting document");
+                        let keys = self.delete_document(
+                            &mut partition_writers,
+                            &record.change.old_image,
+                            &id,
+                        )?;
+
+                        // NOTE: Older changes of the email may still arrive, so leave a tombstone to compare them with,
+                        // in the partition the email was in or else the one of its removal
+                        let key = match keys.into_iter().next() {
+                            Some(key) => key,
+                            None => self.email_index_schema.partitioning.key_for(
+                                &id,
+                                record.change.approximate_creation_date_time.timestamp(),
+                            )?,
+                        };
+                        if !self.email_index_schema.partitioning.is_expired(&key, now) {
+                            let tombstone = self.tombstone(&id, &key, &change)?;
+                            partition_writers
+                                .get(&self.email_index_schema, &key)?
+                                .add_document(tombstone)?;
+                        }
+                        if is_ttl_deletion(&record) {
+                            expired += 1;
+                        } else {
+                            deleted += 1;
+                        }
+                    }
+                    _ => {}
+                }
+            }
+
+            // NOTE: Only save the shard's position once every partition it touched has committed, as the
+            // partition commits are not atomic
+            debug!("commiting shard {shard}");
+            let mut commit_payload = checkpoint.shard(shard);
+            commit_payload.merge(&batch_payload);
+            partition_writers.commit(&mut commit_payload)?;
+            partition_writers.wait_merging_threads()?;
+
+            checkpoint.observe(shard, &commit_payload);
+            self.email_index_schema.save_checkpoint(&checkpoint)?;
+        }
+
+        let commit_payload =
+            checkpoint.commit_payload(self.email_index_schema.partitioning.num_shards());
+
+        let result = json!({
+            "total": total,
+            "created": created,
+            "updated": updated,
+            "deleted": deleted,
+            "expired": expired,
+            "stale": stale,
+            "skipped": total - created - updated - deleted - expired - stale,
+            "sequence_number": commit_payload.sequence_number,
+            "indexed_through": commit_payload.indexed_through,
+        });
+
+        info!("indexed {}", result);
+
+        Ok(result)
+    }
+
+    fn purge_expired(&self) -> Result<Value> {
+        let fields = &self.email_index_schema.fields;
+        let now = unix_now();
+
+        let mut purged = 0_usize;
+        let mut dropped_partitions: Vec<String> = vec![];
+
+        for key in self.email_index_schema.partition_keys()? {
+            // NOTE: Every email in a partition past the retention period has expired, so drop it whole
+            if self.email_index_schema.partitioning.is_expired(&key, now) {
+                info!("dropping partition {key}");
+                self.email_index_schema.drop_partition(&key)?;
+                dropped_partitions.push(key);
+                continue;
+            }
+
+            let index = self.email_index_schema.open_partition(&key)?;
+            let searcher = index.reader()?.searcher();
+
+            // NOTE: Tombstones are kept for a while after the removal, expired emails are purged right away
+            let expired = RangeQuery::new_i64(fields.ttl, TOMBSTONE_TTL + 1..now);
+            let tombstone = RangeQuery::new_i64(fields.ttl, TOMBSTONE_TTL..TOMBSTONE_TTL + 1);
+            let removed_before = RangeQuery::new_i64(
+                fields.changed_at,
+                i64::MIN..(now - TOMBSTONE_RETENTION_SECONDS) * 1000,
+            );
+            let purgeable = BooleanQuery::new(vec![
+                (Occur::Should, Box::new(expired) as Box<dyn Query>),
+                (
+                    Occur::Should,
+                    Box::new(BooleanQuery::new(vec![
+                        (Occur::Must, Box::new(tombstone) as Box<dyn Query>),
+                        (Occur::Must, Box::new(removed_before)),
+                    ])),
+                ),
+            ]);
+            let doc_addresses = searcher.search(&purgeable, &DocSetCollector)?;
+
+            if doc_addresses.is_empty() {
+                continue;
+            }
+
+            let mut partition_writers = PartitionWriters::new(self.merge_settings);
+            let partition_writer = partition_writers.get(&self.email_index_schema, &key)?;
+            for doc_address in &doc_addresses {
+                let doc = searcher.doc(*doc_address)?;
+                partition_writer.delete_term(self.get_id_term(&doc))?;
+            }
+
+            // NOTE: Purging doesn't apply stream records, so keep the partition's position
+            let mut commit_payload = CommitPayload::load(&index)?;
+            partition_writers.commit(&mut commit_payload)?;
+            partition_writers.wait_merging_threads()?;
+
+            purged += doc_addresses.len();
+        }
+
+        let result = json!({
+            "purged": purged,
+            "dropped_partitions": dropped_partitions,
+        });
+
+        info!("purged expired {}", result);
+
+        Ok(result)
+    }
+
+    fn optimize(&self, target_segments: usize) -> Result<Value> {
+        let target_segments = target_segments.max(1);
+        let now = unix_now();
+        // NOTE: Merges finish on tantivy's merge threads, this only waits for them from the blocking thread
+        let runtime = tokio::runtime::Handle::current();
+        let mut partitions: Vec<Value> = vec![];
+
+        for key in self.email_index_schema.partition_keys()? {
+            // NOTE: Expired partitions are dropped by the next purge, so don't spend time on them
+            if self.email_index_schema.partitioning.is_expired(&key, now) {
+                continue;
+            }
+
+            let index = self.email_index_schema.open_partition(&key)?;
+            let segment_metas = index.searchable_segment_metas()?;
+            let merges = plan_merges(segment_metas.clone(), target_segments);
+            if merges.is_empty() {
+                continue;
+            }
+
+            info!("optimizing index {key} with {} merges", merges.len());
+            let mut index_writer = open_writer(&index)?;
+            // NOTE: Segments can only be in one merge at a time, so keep the merge policy from
+            // starting merges of its own
+            index_writer.set_merge_policy(Box::new(NoMergePolicy));
+
+            for merge in merges {
+                let segment_ids: Vec<_> = merge.iter().map(|meta| meta.id()).collect();
+                runtime.block_on(index_writer.merge(&segment_ids))?;
+            }
+
+            runtime.block_on(index_writer.garbage_collect_files())?;
+            index_writer.wait_merging_threads()?;
+            self.email_index_schema.prune_cache(&key, &index)?;
+
+            partitions.push(json!({
+                "partition": key,
+                "segments_before": segment_metas.len(),
+                "segments_after": index.searchable_segment_metas()?.len(),
+                "vacuumed_docs": segment_metas
+                    .iter()
+                    .map(|meta| meta.num_deleted_docs() as u64)
+                    .sum::<u64>(),
+            }));
+        }
+
+        let result = json!({
+            "target_segments": target_segments,
+            "partitions": partitions,
+        });
+
+        info!("optimized {}", result);
+
+        Ok(result)
+    }
+
+    fn partition_key(&self, attributes: &HashMap<String, AttributeValue>) -> Result<String> {
+        let id = parse_string(attributes, "id")?;
+        let timestamp: i64 = parse_string(attributes, "timestamp")?.parse()?;
+        self.email_index_schema.partitioning.key_for(&id, timestamp)
+    }
+
+    /// Deletes the documents of an email, returning the keys of the partitions it was deleted from.
+    fn delete_document(
+        &self,
+        partition_writers: &mut PartitionWriters,
+        old_image: &HashMap<String, AttributeValue>,
+        id: &str,
+    ) -> Result<Vec<String>> {
+        let partitioning = &self.email_index_schema.partitioning;
+        let term = Term::from_field_text(self.email_index_schema.fields.id, id);
+
+        // NOTE: Without the old timestamp we can't tell which partition the document is in, so look it up in the
+        // partitions of its shard rather than opening a writer on each of them
+        let keys = match self.partition_key(old_image) {
+            Ok(key) => vec![key],
+            Err(_) => {
+                let mut keys = vec![];
+                for key in self.email_index_schema.partition_keys()? {
+                    if partitioning.shard_of(&key) != partitioning.shard_for(id) {
+                        continue;
+                    }
+
+                    // NOTE: The document may also have been added earlier in this batch, and not be committed yet
+                    if partition_writers.contains(&key)
+                        || contains_term(&self.email_index_schema.open_partition(&key)?, &term)?
+                    {
+                        keys.push(key);
+                    }
+                }
+                keys
+            }
+        };
+
+        let keys: Vec<String> = keys
+            .into_iter()
+            .filter(|key| !partitioning.is_expired(key, unix_now()))
+            .collect();
+        for key in &keys {
+            partition_writers
+                .get(&self.email_index_schema, key)?
+                .delete_term(term.clone())?;
+        }
+
+        Ok(keys)
+    }
+
+    /// The newest change indexed for each email of the records, including removals. Emails are
+    /// looked up in the partitions of the records' images, or in every partition of the shard for
+    /// keys only images.
+    fn indexed_changes(
+        &self,
+        shard: u32,
+        records: &[EventRecord],
+    ) -> Result<HashMap<String, EmailChange>> {
+        let partitioning = &self.email_index_schema.partitioning;
+        let partition_keys = self.email_index_schema.partition_keys()?;
+
+        let mut ids_by_key: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        for record in records {
+            let id = match parse_string(record_image(record), "id") {
+                Ok(id) => id,
+                Err(_) => continue,
+            };
+
+            let mut keys: Vec<String> = [&record.change.new_image, &record.change.old_image]
+                .into_iter()
+                .filter_map(|image| self.partition_key(image).ok())
+                .collect();
+            if keys.is_empty() {
+                keys = partition_keys
+                    .iter()
+                    .filter(|key| partitioning.shard_of(key) == shard)
+                    .cloned()
+                    .collect();
+            }
+            keys.dedup();
+
+            for key in keys {
+                if partition_keys.contains(&key) {
+                    ids_by_key.entry(key).or_default().push(id.clone());
+                }
+            }
+        }
+
+        let mut indexed_changes: HashMap<String, EmailChange> = HashMap::new();
+        for (key, ids) in ids_by_key {
+            let index = self.email_index_schema.open_partition(&key)?;
+            let index_reader = index
+                .reader_builder()
+                .reload_policy(ReloadPolicy::Manual)
+                .try_into()?;
+            let searcher = index_reader.searcher();
+
+            for id in ids {
+                for change in EmailChange::indexed(&searcher, &self.email_index_schema.fields, &id)?
+                {
+                    match indexed_changes.get(&id) {
+                        Some(indexed_change) if !indexed_change.is_older_than(&change) => {}
+                        _ => {
+                            indexed_changes.insert(id.clone(), change);
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(indexed_changes)
+    }
+
+    /// A document that only marks the email as removed, until purged after
+    /// `TOMBSTONE_RETENTION_SECONDS`.
+    fn tombstone(&self, id: &str, key: &str, change: &EmailChange) -> Result<Document> {
+        let fields = &self.email_index_schema.fields;
+        debug!("adding tombstone to {key}");
+
+        Ok(doc!(
+            fields.id => id,
+            fields.ttl => TOMBSTONE_TTL,
+            fields.version => change.version,
+            fields.changed_at => change.changed_at,
+        ))
+    }
+
+    fn get_id_term(&self, doc: &Document) -> Term {
+        let id = doc
+            .get_first(self.email_index_schema.fields.id)
+            .expect("Documents should have a id value")
+            .as_text()
+            .expect("id value should be text");
+
+        Term::from_field_text(self.email_index_schema.fields.id, id)
+    }
+
+    fn parse_document(
+        &self,
+        attributes: HashMap<String, AttributeValue>,
+        change: &EmailChange,
+    ) -> Result<Document> {
+        let id = parse_string(&attributes, "id")?;
+        let timestamp: i64 = parse_string(&attributes, "timestamp")?.parse()?;
+        let subject = parse_string(&attributes, "subject")?;
+        let body = parse_string(&attributes, "body")?;
+        let to = parse_string_array(&attributes, "to")?;
+        // NOTE: Items without a ttl never expire
+        let ttl = parse_optional_int_64(&attributes, "ttl")?.unwrap_or(i64::MAX);
+        let version = parse_optional_int_64(&attributes, "version")?.unwrap_or(0);
+
+        let mut doc = doc!(
+            self.email_index_schema.fields.id => id,
+            self.email_index_schema.fields.timestamp => timestamp,
+            self.email_index_schema.fields.subject => subject,
+            self.email_index_schema.fields.body => body,
+            self.email_index_schema.fields.ttl => ttl,
+            self.email_index_schema.fields.version => version,
+            self.email_index_schema.fields.changed_at => change.changed_at,
+        );
+
+        for email in to.iter() {
+            let (_, address) = parse_mailbox(email);
+            if let Some((_, domain)) = address.rsplit_once('@') {
+                doc.add_facet(
+                    self.email_index_schema.fields.domain,
+                    Facet::from_path(vec![domain]),
+                );
+            }
+            doc.add_facet(
+                self.email_index_schema.fields.recipient,
+                Facet::from_path(vec![address.as_str()]),
+            );
+            doc.add_text(self.email_index_schema.fields.to, email.as_str());
+        }
+
+        let mut mailboxes = to;
+        if let Ok(from) = parse_string(&attributes, "from") {
+            mailboxes.push(from);
+        }
+        if let Ok(cc) = parse_string_array(&attributes, "cc") {
+            mailboxes.extend(cc);
+        }
+        for mailbox in mailboxes {
+            if let Some(facet) = contact_facet(&mailbox) {
+                doc.add_facet(self.email_index_schema.fields.contact, facet);
+            }
+        }
+
+        Ok(doc)
+    }
+}
+
+/// Merges the smallest segments so at most `target_segments` remain, and merges every other segment
+/// with deleted documents on its own, which rewrites it without them.
+fn plan_merges(
+    mut segment_metas: Vec<SegmentMeta>,
+    target_segments: usize,
+) -> Vec<Vec<SegmentMeta>> {
+    let mut merges: Vec<Vec<SegmentMeta>> = vec![];
+    segment_metas.sort_by_key(|meta| meta.num_docs());
+
+    if segment_metas.len() > target_segments {
+        let smallest = segment_metas
+            .drain(..segment_metas.len() - target_segments + 1)
+            .collect();
+        merges.push(smallest);
+    }
+
+    for segment_meta in segment_metas {
+        if segment_meta.has_deletes() {
+            merges.push(vec![segment_meta]);
+        }
+    }
+
+    merges
+}
+
+/// The image of the item a record changes, the old one for removals.
+fn record_image(record: &EventRecord) -> &HashMap<String, AttributeValue> {
+    if record.change.new_image.is_empty() {
+        &record.change.old_image
+    } else {
+        &record.change.new_image
+    }
+}
+
+fn record_change(record: &EventRecord) -> Result<EmailChange> {
+    Ok(EmailChange {
+        changed_at: record
+            .change
+            .approximate_creation_date_time
+            .timestamp_millis(),
+        version: parse_optional_int_64(record_image(record), "version")?.unwrap_or(0),
+        removed: record.event_name == "REMOVE",
+    })
+}
+
+/// Keeps the newest change of every email in a batch, in stream order. Of changes made at the
+/// same time, the last one delivered wins.
+fn latest_changes(records: Vec<EventRecord>) -> Vec<EventRecord> {
+    let mut latest: HashMap<String, (EmailChange, usize)> = HashMap::new();
+    for (position, record) in records.iter().enumerate() {
+        let (id, change) = match (
+            parse_string(record_image(record), "id"),
+            record_change(record),
+        ) {
+            (Ok(id), Ok(change)) => (id, change),
+            _ => continue,
+        };
+
+        match latest.get(&id) {
+            Some((latest_change, _)) if change.is_older_than(latest_change) => {}
+            _ => {
+                latest.insert(id, (change, position));
+            }
+        }
+    }
+
+    records
+        .into_iter()
+        .enumerate()
+        .filter(
+            |(position, record)| match parse_string(record_image(record), "id") {
+                Ok(id) => latest
+                    .get(&id)
+                    .is_none_or(|(_, latest_position)| latest_position == position),
+                Err(_) => true,
+            },
+        )
+        .map(|(_, record)| record)
+        .collect()
+}
+
+/// Items deleted by DynamoDB TTL arrive as REMOVE records made by the DynamoDB service principal.
+fn is_ttl_deletion(record: &EventRecord) -> bool {
+    match &record.user_identity {
+        Some(user_identity) => {
+            user_identity.type_ == "Service"
+                && user_identity.principal_id == "dynamodb.amazonaws.com"
+        }
+        None => false,
+    }
+}
+
+fn parse_string(
+    attributes: &HashMap<String, AttributeValue>,
+    attribute_name: &str,
+) -> Result<String> {
+    if let Some(AttributeValue::String(value)) = attributes.get(attribute_name) {
+        return Ok(value.clone());
+    }
+
+    Err(anyhow::anyhow!("{attribute_name} missing"))
+}
+
+fn parse_optional_int_64(
+    attributes: &HashMap<String, AttributeValue>,
+    attribute_name: &str,
+) -> Result<Option<i64>> {
+    match attributes.get(attribute_name) {
+        Some(AttributeValue::String(value)) => Ok(Some(value.parse()?)),
+        Some(AttributeValue::Number(value)) => Ok(Some(*value as i64)),
+        Some(_) => Err(anyhow::anyhow!("{attribute_name} is not a number")),
+        None => Ok(None),
+    }
+}
+
+fn parse_string_array(
+    attributes: &HashMap<String, AttributeValue>,
+    attribute_name: &str,
+) -> Result<Vec<String>> {
+    if let Some(AttributeValue::StringSet(values)) = attributes.get(attribute_name) {
+        return Ok(values.clone());
+    };
+
+    Err(anyhow::anyhow!("{attribute_name} missing"))
+}
@@ -0,0 +1,21 @@
+use anyhow::{Context, Result};
+use std::str::FromStr;
+
+pub struct EnvHelper;
+
+impl EnvHelper {
+    /// Parses an optional env var, returning `None` when it isn't set.
+    pub fn parse<T: FromStr>(name: &str) -> Result<Option<T>>
+    where
+        T::Err: std::error::Error + Send + Sync + 'static,
+    {
+        match std::env::var(name) {
+            Ok(value) => Ok(Some(
+                value
+                    .parse()
+                    .with_context(|| format!("{name} is not valid"))?,
+            )),
+            Err(_) => Ok(None),
+        }
+    }
+}
@@ -0,0 +1,92 @@
+use crate::http_request::HttpRequest;
+use crate::http_response::HttpResponse;
+use anyhow::{Context, Result};
+use serde_json::{json, Value};
+
+/// The services that deliver HTTP requests to the reader, each with its own event shape.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum HttpEventSource {
+    /// Lambda function URLs and API Gateway HTTP APIs with payload format 2.0.
+    FunctionUrl,
+    /// API Gateway REST APIs and HTTP APIs with payload format 1.0.
+    ApiGateway,
+    /// Application load balancers, which expect multi-value response headers when the target
+    /// group sends multi-value request headers.
+    Alb { multi_value_headers: bool },
+}
+
+/// An HTTP request along with the source it came from, so the response can take the shape the
+/// source expects.
+#[derive(Debug)]
+pub struct HttpEvent {
+    pub source: HttpEventSource,
+    pub request: HttpRequest,
+}
+
+impl HttpEvent {
+    /// Detects the source from the fields only its events have.
+    pub fn parse(event: Value) -> Result<HttpEvent> {
+        let request_context = &event["requestContext"];
+
+        let (source, request) = if !request_context["elb"].is_null() {
+            let source = HttpEventSource::Alb {
+                multi_value_headers: !event["multiValueHeaders"].is_null(),
+            };
+            let request = serde_json::from_value(event).context("invalid ALB event")?;
+            (source, HttpRequest::from_alb(request))
+        } else if !request_context["http"].is_null() {
+            let request = serde_json::from_value(event).context("invalid function URL event")?;
+            (
+                HttpEventSource::FunctionUrl,
+                HttpRequest::from_function_url(request),
+            )
+        } else if !event["httpMethod"].is_null() {
+            let request = serde_json::from_value(event).context("invalid API Gateway event")?;
+            (
+                HttpEventSource::ApiGateway,
+                HttpRequest::from_api_gateway(request),
+            )
+        } else {
+            return Err(anyhow::anyhow!("unsupported event source"));
+        };
+
+        Ok(HttpEvent { source, request })
+    }
+
+    pub fn response(&self, response: HttpResponse) -> Value {
+        match self.source {
+            HttpEventSource::FunctionUrl | HttpEventSource::ApiGateway => json!(response),
+            HttpEventSource::Alb {
+                multi_value_headers,
+            } => {
+                let status_description = match http::StatusCode::from_u16(response.status_code) {
+                    Ok(status_code) => format!(
+                        "{} {}",
+                        status_code.as_u16(),
+                        status_code.canonical_reason().unwrap_or_default()
+                    ),
+                    Err(_) => response.status_code.to_string(),
+                };
+
+                let mut alb_response = json!({
+                    "statusCode": response.status_code,
+                    "statusDescription": status_description,
+                    "body": response.body,
+                    "isBase64Encoded": response.is_base64_encoded,
+                });
+                if multi_value_headers {
+                    let headers: serde_json::Map<String, Value> = response
+                        .headers
+                        .into_iter()
+                        .map(|(name, value)| (name, json!([value])))
+                        .collect();
+                    alb_response["multiValueHeaders"] = Value::Object(headers);
+                } else {
+                    alb_response["headers"] = json!(response.headers);
+                }
+
+                alb_response
+            }
+        }
+    }
+}
@@ -0,0 +1,282 @@
+use crate::http_response::HttpResponse;
+use crate::reader_request::ReaderRequest;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+
+/// The parts of a Lambda function URL event the reader uses. Function URLs send the API Gateway
+/// HTTP API payload format 2.0, so HTTP APIs can invoke the reader too.
+#[derive(Deserialize, Serialize, Default, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct FunctionUrlRequest {
+    pub headers: Option<HashMap<String, String>>,
+    pub query_string_parameters: Option<HashMap<String, String>>,
+    pub request_context: Option<FunctionUrlRequestContext>,
+    pub body: Option<String>,
+    #[serde(default)]
+    pub is_base64_encoded: bool,
+}
+
+#[derive(Deserialize, Serialize, Default, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct FunctionUrlRequestContext {
+    pub request_id: Option<String>,
+    pub http: Option<FunctionUrlHttp>,
+}
+
+#[derive(Deserialize, Serialize, Default, Debug)]
+pub struct FunctionUrlHttp {
+    pub method: String,
+}
+
+/// The parts of an API Gateway REST API (or HTTP API payload format 1.0) event or an ALB event
+/// the reader uses. ALBs send either the single or the multi-value maps, depending on the target
+/// group, and pass query parameters on still URL-encoded.
+#[derive(Deserialize, Serialize, Default, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ProxyRequest {
+    pub http_method: String,
+    pub headers: Option<HashMap<String, String>>,
+    pub multi_value_headers: Option<HashMap<String, Vec<String>>>,
+    pub query_string_parameters: Option<HashMap<String, String>>,
+    pub multi_value_query_string_parameters: Option<HashMap<String, Vec<String>>>,
+    pub request_context: Option<ProxyRequestContext>,
+    pub body: Option<String>,
+    #[serde(default)]
+    pub is_base64_encoded: bool,
+}
+
+#[derive(Deserialize, Serialize, Default, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ProxyRequestContext {
+    pub request_id: Option<String>,
+}
+
+/// An HTTP request to the reader, independent of the event source that delivered it.
+#[derive(Default, Debug)]
+pub struct HttpRequest {
+    /// Uppercase.
+    pub method: String,
+    /// Keyed by lowercase name.
+    pub headers: HashMap<String, String>,
+    pub query_parameters: HashMap<String, String>,
+    pub body: Option<String>,
+    pub is_base64_encoded: bool,
+    /// Correlates the response, and the logs of a failed request, with the caller.
+    pub request_id: Option<String>,
+}
+
+impl HttpRequest {
+    pub fn from_function_url(request: FunctionUrlRequest) -> Self {
+        let request_context = request.request_context.unwrap_or_default();
+
+        HttpRequest {
+            method: request_context
+                .http
+                .map(|http| http.method.to_uppercase())
+                .unwrap_or_default(),
+            headers: request
+                .headers
+                .unwrap_or_default()
+                .into_iter()
+                .map(|(name, value)| (name.to_lowercase(), value))
+                .collect(),
+            query_parameters: request.query_string_parameters.unwrap_or_default(),
+            body: request.body,
+            is_base64_encoded: request.is_base64_encoded,
+            request_id: request_context.request_id,
+        }
+    }
+
+    pub fn from_api_gateway(request: ProxyRequest) -> Self {
+        let request_id = request
+            .request_context
+            .as_ref()
+            .and_then(|request_context| request_context.request_id.clone());
+
+        HttpRequest {
+            request_id,
+            ..HttpRequest::from_proxy(request, false)
+        }
+    }
+
+    /// ALBs have no request id, so the trace id they add identifies the request instead.
+    pub fn from_alb(request: ProxyRequest) -> Self {
+        let request = HttpRequest::from_proxy(request, true);
+
+        HttpRequest {
+            request_id: request.headers.get("x-amzn-trace-id").cloned(),
+            ..request
+        }
+    }
+
+    fn from_proxy(request: ProxyRequest, url_encoded: bool) -> Self {
+        // NOTE: The last value of a repeated header or parameter wins, as in the single value maps
+        let headers = single_values(request.headers, request.multi_value_headers);
+        let query_parameters = single_values(
+            request.query_string_parameters,
+            request.multi_value_query_string_parameters,
+        );
+
+        HttpRequest {
+            method: request.http_method.to_uppercase(),
+            headers: headers
+                .into_iter()
+                .map(|(name, value)| (name.to_lowercase(), value))
+                .collect(),
+            query_parameters: if url_encoded {
+                query_parameters
+                    .into_iter()
+                    .map(|(name, value)| (url_decode(&name), url_decode(&value)))
+                    .collect()
+            } else {
+                query_parameters
+            },
+            body: request.body,
+            is_base64_encoded: request.is_base64_encoded,
+            request_id: None,
+        }
+    }
+
+    /// `POST` takes a JSON body, `GET` takes the request fields as query parameters with `fields`
+    /// separated by commas and `min_version` as `<version>:<id>`, or just `<id>`. Returns the error
+    /// response for requests the reader can't serve.
+    pub fn reader_request(&self) -> Result<ReaderRequest, HttpResponse> {
+        if !self.accepts_json() {
+            return Err(HttpResponse::error(
+                406,
+                "responses are only available as application/json",
+            ));
+        }
+
+        let reader_request = match self.method.as_str() {
+            "GET" => self.query_reader_request(),
+            "POST" => {
+                if !self.has_json_body() {
+                    return Err(HttpResponse::error(
+                        415,
+                        "request bodies must be application/json",
+                    ));
+                }
+                self.body_reader_request()
+            }
+            _ => {
+                return Err(HttpResponse::error(405, "only GET and POST are supported")
+                    .with_header("allow", "GET, POST"))
+            }
+        };
+
+        reader_request.map_err(|error| HttpResponse::error(400, &format!("{error:#}")))
+    }
+
+    fn body_reader_request(&self) -> Result<ReaderRequest> {
+        let body = self.body.as_deref().unwrap_or_default();
+        if body.is_empty() {
+            return Err(anyhow::anyhow!("body is required"));
+        }
+
+        if self.is_base64_encoded {
+            let body = base64::decode(body).context("body is not valid base64")?;
+            let body = String::from_utf8(body).context("body is not valid UTF-8")?;
+            ReaderRequest::parse(&body).context("body is not a valid request")
+        } else {
+            ReaderRequest::parse(body).context("body is not a valid request")
+        }
+    }
+
+    fn query_reader_request(&self) -> Result<ReaderRequest> {
+        let mut object = Map::new();
+
+        for (name, value) in &self.query_parameters {
+            let value = match name.as_str() {
+                "limit" => Value::from(
+                    value
+                        .parse::<u64>()
+                        .with_context(|| format!("{name} is not a valid number"))?,
+                ),
+                // NOTE: Versions are numbers and ids can be anything, so the version goes first
+                "min_version" => match value.split_once(':') {
+                    Some((version, id)) => json!({
+                        "id": id,
+                        "version": version
+                            .parse::<i64>()
+                            .with_context(|| format!("{name} is not a valid version"))?,
+                    }),
+                    None => json!({ "id": value }),
+                },
+                "include_expired" => Value::Bool(
+                    value
+                        .parse()
+                        .with_context(|| format!("{name} is not a valid boolean"))?,
+                ),
+                "fields" => Value::from(
+                    value
+                        .split(',')
+                        .map(str::trim)
+                        .filter(|field| !field.is_empty())
+                        .collect::<Vec<_>>(),
+                ),
+                _ => Value::String(value.clone()),
+            };
+            object.insert(name.clone(), value);
+        }
+
+        ReaderRequest::parse(&Value::Object(object).to_string())
+            .context("query parameters are not a valid request")
+    }
+
+    fn accepts_json(&self) -> bool {
+        match self.headers.get("accept") {
+            Some(accept) => accept.split(',').any(|media_range| {
+                matches!(
+                    media_type(media_range).as_str(),
+                    "application/json" | "application/*" | "*/*"
+                )
+            }),
+            None => true,
+        }
+    }
+
+    fn has_json_body(&self) -> bool {
+        match self.headers.get("content-type") {
+            Some(content_type) => {
+                let media_type = media_type(content_type);
+                media_type == "application/json"
+                    || media_type == "text/plain"
+                    || media_type.ends_with("+json")
+            }
+            None => true,
+        }
+    }
+}
+
+fn single_values(
+    values: Option<HashMap<String, String>>,
+    multi_values: Option<HashMap<String, Vec<String>>>,
+) -> HashMap<String, String> {
+    match (values, multi_values) {
+        (Some(values), _) if !values.is_empty() => values,
+        (_, Some(multi_values)) => multi_values
+            .into_iter()
+            .filter_map(|(name, values)| Some((name, values.into_iter().last()?)))
+            .collect(),
+        _ => HashMap::new(),
+    }
+}
+
+fn url_decode(value: &str) -> String {
+    percent_encoding::percent_decode_str(&value.replace('+', " "))
+        .decode_utf8_lossy()
+        .into_owned()
+}
+
+/// The media type of a header value without its parameters, lowercased.
+fn media_type(value: &str) -> String {
+    value
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_lowercase()
+}
@@ -0,0 +1,47 @@
+use crate::email_index_reader::MIN_VERSION_TIMEOUT_ERROR;
+use crate::reader_response::ReaderResponse;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::BTreeMap;
+
+/// A response in the shape Lambda function URLs and API Gateway expect from the function.
+#[derive(Serialize, Deserialize, Default, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct HttpResponse {
+    pub status_code: u16,
+    pub headers: BTreeMap<String, String>,
+    pub body: String,
+    pub is_base64_encoded: bool,
+}
+
+impl HttpResponse {
+    pub fn json(status_code: u16, body: &impl Serialize) -> Self {
+        HttpResponse {
+            status_code,
+            headers: BTreeMap::from([("content-type".to_string(), "application/json".to_string())]),
+            body: json!(body).to_string(),
+            is_base64_encoded: false,
+        }
+    }
+
+    pub fn error(status_code: u16, error: &str) -> Self {
+        HttpResponse::json(status_code, &json!({ "error": error }))
+    }
+
+    /// Requests the reader rejected, such as a search with an invalid query, are client errors. An
+    /// index that hasn't caught up to `min_version` yet is unavailable, and may be retried.
+    pub fn from_reader_response(response: &ReaderResponse) -> Self {
+        match response.error() {
+            Some(MIN_VERSION_TIMEOUT_ERROR) => {
+                HttpResponse::json(503, response).with_header("retry-after", "1")
+            }
+            Some(_) => HttpResponse::json(400, response),
+            None => HttpResponse::json(200, response),
+        }
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.insert(name.to_string(), value.to_string());
+        self
+    }
+}
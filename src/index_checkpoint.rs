@@ -0,0 +1,64 @@
+use crate::commit_payload::CommitPayload;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// The stream position each shard of the index has committed. The writer updates a shard once
+/// every partition it touched in the shard has committed, so the position of the whole index is
+/// the minimum across shards.
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+pub struct IndexCheckpoint {
+    pub shards: BTreeMap<u32, CommitPayload>,
+}
+
+impl IndexCheckpoint {
+    pub fn parse(json: &[u8]) -> Result<IndexCheckpoint> {
+        serde_json::from_slice(json).context("Error parsing index checkpoint")
+    }
+
+    pub fn to_json(&self) -> Result<Vec<u8>> {
+        let json = serde_json::to_vec(self)?;
+        Ok(json)
+    }
+
+    /// Returns the position of a shard, or an empty one if it has not committed yet.
+    pub fn shard(&self, shard: u32) -> CommitPayload {
+        self.shards.get(&shard).cloned().unwrap_or_default()
+    }
+
+    /// Records that a shard has committed every stream record up to the payload.
+    pub fn observe(&mut self, shard: u32, commit_payload: &CommitPayload) {
+        self.shards.entry(shard).or_default().merge(commit_payload);
+    }
+
+    /// Keeps the newest position of every shard of both checkpoints, as writers of other shards may
+    /// have saved theirs since this one was loaded.
+    pub fn merge(&mut self, other: &IndexCheckpoint) {
+        for (shard, commit_payload) in &other.shards {
+            self.observe(*shard, commit_payload);
+        }
+    }
+
+    /// The position every one of `num_shards` shards has reached. Sequence numbers are only
+    /// ordered within a stream shard, so there is only a version without sharding.
+    pub fn commit_payload(&self, num_shards: u32) -> CommitPayload {
+        let shards: Vec<CommitPayload> = (0..num_shards).map(|shard| self.shard(shard)).collect();
+
+        CommitPayload {
+            sequence_number: match shards.as_slice() {
+                [commit_payload] => commit_payload.sequence_number.clone(),
+                _ => None,
+            },
+            indexed_through: shards
+                .iter()
+                .map(|commit_payload| commit_payload.indexed_through)
+                .min()
+                .flatten(),
+            committed_at: shards
+                .iter()
+                .filter_map(|commit_payload| commit_payload.committed_at)
+                .max(),
+            contacts: None,
+        }
+    }
+}
@@ -0,0 +1,177 @@
+use crate::env_helper::EnvHelper;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use tantivy::chrono::{Datelike, Duration, NaiveDate, NaiveDateTime, Weekday};
+
+/// The key of the single index used when partitioning is disabled.
+pub const UNPARTITIONED_KEY: &str = "index";
+
+/// Separates the time partition from the shard in a partition key, as in `2022-05.s03`.
+const SHARD_SEPARATOR: &str = ".s";
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum PartitionInterval {
+    Week,
+    Month,
+}
+
+/// Splits the index into one index per week or month of `timestamp`, so whole partitions can be
+/// dropped once every email in them has passed the retention period, and optionally into shards
+/// by a hash of `id`, so several writers can index at the same time.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+pub struct Partitioning {
+    pub interval: Option<PartitionInterval>,
+    pub retention_days: Option<i64>,
+    /// Changing this re-hashes every id, so updates and deletes of existing emails would miss
+    /// their documents; reindex into a new index instead.
+    pub shards: Option<u32>,
+}
+
+impl Partitioning {
+    /// Reads `INDEX_PARTITION_INTERVAL` (`week` or `month`), `INDEX_RETENTION_DAYS` and
+    /// `INDEX_SHARDS`, all optional.
+    pub fn from_env() -> Result<Partitioning> {
+        let interval = match std::env::var("INDEX_PARTITION_INTERVAL") {
+            Ok(interval) => match interval.as_str() {
+                "week" => Some(PartitionInterval::Week),
+                "month" => Some(PartitionInterval::Month),
+                "" | "none" => None,
+                _ => return Err(anyhow::anyhow!("INDEX_PARTITION_INTERVAL is not valid")),
+            },
+            Err(_) => None,
+        };
+
+        let retention_days = EnvHelper::parse("INDEX_RETENTION_DAYS")?;
+
+        let shards = match EnvHelper::parse("INDEX_SHARDS")? {
+            Some(0) => return Err(anyhow::anyhow!("INDEX_SHARDS must be at least 1")),
+            Some(1) | None => None,
+            shards => shards,
+        };
+
+        Ok(Partitioning {
+            interval,
+            retention_days,
+            shards,
+        })
+    }
+
+    pub fn is_partitioned(&self) -> bool {
+        self.interval.is_some() || self.shards.is_some()
+    }
+
+    /// Returns the number of shards, 1 without sharding.
+    pub fn num_shards(&self) -> u32 {
+        self.shards.unwrap_or(1)
+    }
+
+    /// Returns the shard the email with the given id belongs to, always 0 without sharding.
+    pub fn shard_for(&self, id: &str) -> u32 {
+        match self.shards {
+            Some(shards) => (fnv1a(id.as_bytes()) % shards as u64) as u32,
+            None => 0,
+        }
+    }
+
+    /// Returns the shard of a partition key, always 0 without sharding.
+    pub fn shard_of(&self, key: &str) -> u32 {
+        key.rsplit_once(SHARD_SEPARATOR)
+            .and_then(|(_, shard)| shard.parse().ok())
+            .unwrap_or(0)
+    }
+
+    /// Returns the key of the partition an email with the given id and timestamp (in seconds)
+    /// belongs to.
+    pub fn key_for(&self, id: &str, timestamp: i64) -> Result<String> {
+        let date = NaiveDateTime::from_timestamp_opt(timestamp, 0)
+            .with_context(|| format!("timestamp {timestamp} is out of range"))?
+            .date();
+
+        let key = match self.interval {
+            None => UNPARTITIONED_KEY.to_string(),
+            Some(PartitionInterval::Week) => {
+                let week = date.iso_week();
+                format!("{:04}-W{:02}", week.year(), week.week())
+            }
+            Some(PartitionInterval::Month) => format!("{:04}-{:02}", date.year(), date.month()),
+        };
+
+        match self.shards {
+            Some(_) => Ok(format!("{key}{SHARD_SEPARATOR}{:02}", self.shard_for(id))),
+            None => Ok(key),
+        }
+    }
+
+    /// Returns the unix timestamps at which the partition starts and ends, or None if the key is
+    /// not valid.
+    pub fn partition_range(&self, key: &str) -> Option<(i64, i64)> {
+        let key = match key.split_once(SHARD_SEPARATOR) {
+            Some((key, _)) => key,
+            None => key,
+        };
+
+        let (start, end) = match self.interval? {
+            PartitionInterval::Week => {
+                let (year, week) = key.split_once("-W")?;
+                let start = NaiveDate::from_isoywd_opt(
+                    year.parse().ok()?,
+                    week.parse().ok()?,
+                    Weekday::Mon,
+                )?;
+                (start, start + Duration::weeks(1))
+            }
+            PartitionInterval::Month => {
+                let (year, month) = key.split_once('-')?;
+                let (year, month): (i32, u32) = (year.parse().ok()?, month.parse().ok()?);
+                let start = NaiveDate::from_ymd_opt(year, month, 1)?;
+                if month == 12 {
+                    (start, NaiveDate::from_ymd_opt(year + 1, 1, 1)?)
+                } else {
+                    (start, NaiveDate::from_ymd_opt(year, month + 1, 1)?)
+                }
+            }
+        };
+
+        Some((
+            start.and_hms(0, 0, 0).timestamp(),
+            end.and_hms(0, 0, 0).timestamp(),
+        ))
+    }
+
+    /// Returns the unix timestamp at which the partition ends, or None if the key is not valid.
+    pub fn partition_end(&self, key: &str) -> Option<i64> {
+        self.partition_range(key).map(|(_, end)| end)
+    }
+
+    /// Returns false if no email in the partition can have a timestamp in `[start, end)`. Keys
+    /// that are not valid may contain anything.
+    pub fn may_contain(&self, key: &str, start: Option<i64>, end: Option<i64>) -> bool {
+        match self.partition_range(key) {
+            Some((partition_start, partition_end)) => {
+                start.is_none_or(|start| start < partition_end)
+                    && end.is_none_or(|end| end > partition_start)
+            }
+            None => true,
+        }
+    }
+
+    /// Returns true if every email in the partition is older than the retention period.
+    pub fn is_expired(&self, key: &str, now: i64) -> bool {
+        match (self.retention_days, self.partition_end(key)) {
+            (Some(retention_days), Some(end)) => end < now - retention_days * 24 * 60 * 60,
+            _ => false,
+        }
+    }
+}
+
+/// FNV-1a, which unlike the std hasher is stable across builds and processes.
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+
+    hash
+}
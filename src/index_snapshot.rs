@@ -0,0 +1,376 @@
+use crate::commit_payload::{unix_now, CommitPayload};
+use crate::email_index_schema::{schema_fingerprint, EmailIndexSchema};
+use crate::index_checkpoint::IndexCheckpoint;
+use crate::index_partitioning::Partitioning;
+use anyhow::{Context, Result};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::{
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+};
+use tantivy::{
+    directory::{error::OpenReadError, FileSlice, TerminatingWrite},
+    Directory, HasLen, TantivyError,
+};
+
+const FORMAT_VERSION: u32 = 2;
+const MANIFEST_ENTRY: &str = "manifest.json";
+const META_FILE: &str = "meta.json";
+const MAX_EXPORT_ATTEMPTS: usize = 5;
+const MAX_MANIFEST_BYTES: u64 = 64 * 1024 * 1024;
+
+/// Describes the contents of a snapshot archive. It is the first entry of the archive, so a
+/// restore can validate it before writing anything.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SnapshotManifest {
+    pub format_version: u32,
+    pub created_at: i64,
+    pub schema_fingerprint: String,
+    pub partitioning: Partitioning,
+    /// The stream position every shard had reached.
+    pub commit_payload: CommitPayload,
+    /// The stream position of each shard, restored with the partitions.
+    pub checkpoint: IndexCheckpoint,
+    pub partitions: Vec<SnapshotPartition>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SnapshotPartition {
+    pub key: String,
+    pub num_docs: u64,
+    /// The segment files of the commit, followed by its `meta.json`.
+    pub files: Vec<SnapshotFile>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SnapshotFile {
+    pub path: String,
+    pub size: u64,
+}
+
+/// A partition pinned at a commit point, with the files of that commit open.
+struct PinnedPartition {
+    key: String,
+    num_docs: u64,
+    meta: Vec<u8>,
+    files: Vec<(PathBuf, FileSlice)>,
+}
+
+/// Writes every partition of the index, as of its latest commit, to a gzipped archive. The writer can keep running while this runs.
+///
+/// The archive is a sequence of entries, each a little endian u32 name length, the name, a little
+/// endian u64 data length and the data.
+pub fn export(
+    email_index_schema: &EmailIndexSchema,
+    output: impl Write,
+) -> Result<SnapshotManifest> {
+    // NOTE: Load the checkpoint before pinning, so the partitions are at least as new as the position
+    let checkpoint = email_index_schema.load_checkpoint()?;
+    let mut pinned_partitions: Vec<PinnedPartition> = vec![];
+
+    for key in email_index_schema.partition_keys()? {
+        pinned_partitions.push(pin_partition(email_index_schema, &key)?);
+    }
+
+    let manifest = SnapshotManifest {
+        format_version: FORMAT_VERSION,
+        created_at: unix_now(),
+        schema_fingerprint: email_index_schema.fingerprint(),
+        partitioning: email_index_schema.partitioning,
+        commit_payload: checkpoint.commit_payload(email_index_schema.partitioning.num_shards()),
+        checkpoint,
+        partitions: pinned_partitions
+            .iter()
+            .map(|pinned_partition| {
+                let mut files: Vec<SnapshotFile> = pinned_partition
+                    .files
+                    .iter()
+                    .map(|(path, file_slice)| SnapshotFile {
+                        path: path.to_string_lossy().to_string(),
+                        size: file_slice.len() as u64,
+                    })
+                    .collect();
+                files.push(SnapshotFile {
+                    path: META_FILE.to_string(),
+                    size: pinned_partition.meta.len() as u64,
+                });
+
+                SnapshotPartition {
+                    key: pinned_partition.key.clone(),
+                    num_docs: pinned_partition.num_docs,
+                    files,
+                }
+            })
+            .collect(),
+    };
+
+    let mut encoder = GzEncoder::new(output, Compression::default());
+    write_entry(
+        &mut encoder,
+        MANIFEST_ENTRY,
+        &serde_json::to_vec(&manifest)?,
+    )?;
+
+    for pinned_partition in &pinned_partitions {
+        for (path, file_slice) in &pinned_partition.files {
+            let data = file_slice.read_bytes()?;
+            write_entry(
+                &mut encoder,
+                &entry_name(&pinned_partition.key, path),
+                data.as_slice(),
+            )?;
+        }
+        write_entry(
+            &mut encoder,
+            &entry_name(&pinned_partition.key, Path::new(META_FILE)),
+            &pinned_partition.meta,
+        )?;
+    }
+
+    encoder.finish()?.flush()?;
+
+    info!(
+        "exported {} partitions at sequence number {:?}",
+        manifest.partitions.len(),
+        manifest.commit_payload.sequence_number
+    );
+
+    Ok(manifest)
+}
+
+/// Reads only the manifest of an archive.
+pub fn read_manifest(input: impl Read) -> Result<SnapshotManifest> {
+    let mut decoder = GzDecoder::new(input);
+    read_manifest_entry(&mut decoder)
+}
+
+/// Restores an archive into a new generation of the index, and makes it the current generation
+/// once every partition is written, so the index in use is never mixed with the snapshot. The
+/// index must have the same schema and partitioning as the snapshot.
+pub fn restore(
+    email_index_schema: &EmailIndexSchema,
+    input: impl Read,
+) -> Result<SnapshotManifest> {
+    let mut decoder = GzDecoder::new(input);
+    let manifest = read_manifest_entry(&mut decoder)?;
+
+    if manifest.format_version != FORMAT_VERSION {
+        return Err(anyhow::anyhow!(
+            "Snapshot format version {} is not supported",
+            manifest.format_version
+        ));
+    }
+    if manifest.schema_fingerprint != email_index_schema.fingerprint() {
+        return Err(anyhow::anyhow!(
+            "Snapshot schema fingerprint {} does not match {}",
+            manifest.schema_fingerprint,
+            email_index_schema.fingerprint()
+        ));
+    }
+    // NOTE: The retention period doesn't change where emails are, so it may differ
+    let partitioning = email_index_schema.partitioning;
+    if manifest.partitioning.interval != partitioning.interval
+        || manifest.partitioning.shards != partitioning.shards
+    {
+        return Err(anyhow::anyhow!(
+            "Snapshot partitioning {:?} does not match {:?}",
+            manifest.partitioning,
+            partitioning
+        ));
+    }
+
+    // NOTE: Keys and paths become paths in the storage, so they must not leave the partition
+    for partition in &manifest.partitions {
+        if !is_plain_name(&partition.key)
+            || !partition.files.iter().all(|file| is_plain_name(&file.path))
+        {
+            return Err(anyhow::anyhow!(
+                "Snapshot partition {:?} has an invalid path",
+                partition.key
+            ));
+        }
+    }
+
+    let generation = unix_now().to_string();
+    let email_index_schema = email_index_schema
+        .clone()
+        .with_generation(Some(generation.clone()));
+
+    for key in email_index_schema.partition_keys()? {
+        let index = email_index_schema.ensure_partition(&key)?;
+        if !index.load_metas()?.segments.is_empty() {
+            return Err(anyhow::anyhow!(
+                "Generation {generation} already has documents in partition {key}"
+            ));
+        }
+    }
+
+    for partition in &manifest.partitions {
+        let index = email_index_schema.ensure_partition(&partition.key)?;
+        let directory = index.directory();
+
+        for file in &partition.files {
+            let path = Path::new(&file.path);
+            let mut entry = read_entry_header(&mut decoder, &entry_name(&partition.key, path))?;
+
+            if file.path == META_FILE {
+                // NOTE: meta.json is the commit point, so it's written last and atomically
+                let mut meta = vec![];
+                entry.read_to_end(&mut meta)?;
+                directory.atomic_write(path, &meta)?;
+            } else {
+                let mut writer = directory.open_write(path)?;
+                io::copy(&mut entry, &mut writer)?;
+                writer.terminate()?;
+            }
+
+            if entry.limit() > 0 {
+                return Err(anyhow::anyhow!("Snapshot entry {} is truncated", file.path));
+            }
+        }
+
+        info!(
+            "restored partition {} with {} documents",
+            partition.key, partition.num_docs
+        );
+    }
+
+    email_index_schema.save_checkpoint(&manifest.checkpoint)?;
+    email_index_schema.make_current_generation()?;
+    info!("restored into generation {generation}");
+
+    Ok(manifest)
+}
+
+/// Opens every file of the partition's latest commit, so they can still be read after a merge
+/// deletes them.
+fn pin_partition(email_index_schema: &EmailIndexSchema, key: &str) -> Result<PinnedPartition> {
+    let index = email_index_schema.open_partition(key)?;
+    let directory = index.directory();
+
+    for attempt in 1..=MAX_EXPORT_ATTEMPTS {
+        let metas = index.load_metas()?;
+
+        let fingerprint = schema_fingerprint(&metas.schema);
+        if fingerprint != email_index_schema.fingerprint() {
+            return Err(anyhow::anyhow!(
+                "Partition {key} has schema fingerprint {fingerprint}, expected {}",
+                email_index_schema.fingerprint()
+            ));
+        }
+
+        let paths: Vec<PathBuf> = metas
+            .segments
+            .iter()
+            .flat_map(|segment_meta| {
+                // NOTE: The delete file is listed even if the segment has no deletes
+                segment_meta.list_files().into_iter().filter(|path| {
+                    segment_meta.has_deletes() || path.extension().is_none_or(|ext| ext != "del")
+                })
+            })
+            .collect();
+
+        if let Some(files) = open_files(directory, paths)? {
+            return Ok(PinnedPartition {
+                key: key.to_string(),
+                num_docs: metas
+                    .segments
+                    .iter()
+                    .map(|segment_meta| segment_meta.num_docs() as u64)
+                    .sum(),
+                meta: serde_json::to_vec_pretty(&metas)?,
+                files,
+            });
+        }
+
+        warn!("partition {key} changed while pinning it, attempt {attempt}");
+    }
+
+    Err(anyhow::anyhow!(
+        "Partition {key} kept changing while pinning it"
+    ))
+}
+
+/// Opens the files, or returns None if one of them has been deleted.
+fn open_files(
+    directory: &dyn Directory,
+    paths: Vec<PathBuf>,
+) -> Result<Option<Vec<(PathBuf, FileSlice)>>> {
+    let mut files: Vec<(PathBuf, FileSlice)> = vec![];
+
+    for path in paths {
+        match directory.open_read(&path) {
+            Ok(file_slice) => files.push((path, file_slice)),
+            // NOTE: A merge committed and deleted the file since the metas were loaded
+            Err(OpenReadError::FileDoesNotExist(_)) => return Ok(None),
+            Err(error) => return Err(TantivyError::from(error).into()),
+        }
+    }
+
+    Ok(Some(files))
+}
+
+fn entry_name(key: &str, path: &Path) -> String {
+    format!("{key}/{}", path.to_string_lossy())
+}
+
+fn write_entry(writer: &mut impl Write, name: &str, data: &[u8]) -> Result<()> {
+    writer.write_all(&(name.len() as u32).to_le_bytes())?;
+    writer.write_all(name.as_bytes())?;
+    writer.write_all(&(data.len() as u64).to_le_bytes())?;
+    writer.write_all(data)?;
+
+    Ok(())
+}
+
+/// Reads the header of the next entry, which must be `name`, and returns a reader of its data.
+fn read_entry_header<'a, R: Read>(reader: &'a mut R, name: &str) -> Result<io::Take<&'a mut R>> {
+    let mut name_len = [0_u8; 4];
+    reader
+        .read_exact(&mut name_len)
+        .with_context(|| format!("Snapshot ends before {name}"))?;
+
+    // NOTE: The expected name is known, so never allocate what the archive claims
+    let name_len = u32::from_le_bytes(name_len) as usize;
+    if name_len != name.len() {
+        return Err(anyhow::anyhow!(
+            "Expected snapshot entry {name}, found one with a {name_len} byte name"
+        ));
+    }
+    let mut entry_name = vec![0_u8; name_len];
+    reader.read_exact(&mut entry_name)?;
+
+    if entry_name != name.as_bytes() {
+        return Err(anyhow::anyhow!(
+            "Expected snapshot entry {name}, found {}",
+            String::from_utf8_lossy(&entry_name)
+        ));
+    }
+
+    let mut data_len = [0_u8; 8];
+    reader.read_exact(&mut data_len)?;
+
+    Ok(reader.take(u64::from_le_bytes(data_len)))
+}
+
+fn read_manifest_entry(reader: &mut impl Read) -> Result<SnapshotManifest> {
+    let mut manifest = vec![];
+    let mut entry = read_entry_header(reader, MANIFEST_ENTRY).context("Not a snapshot archive")?;
+    if entry.limit() > MAX_MANIFEST_BYTES {
+        return Err(anyhow::anyhow!(
+            "Snapshot manifest of {} bytes is too large",
+            entry.limit()
+        ));
+    }
+    entry.read_to_end(&mut manifest)?;
+
+    serde_json::from_slice(&manifest).context("Error parsing snapshot manifest")
+}
+
+/// Whether a snapshot key or path is a single file name, rather than one that could point outside
+/// the partition.
+fn is_plain_name(name: &str) -> bool {
+    !name.is_empty() && name != "." && name != ".." && !name.contains(['/', '\\'])
+}
@@ -0,0 +1,130 @@
+use crate::dynamodb_lock_table::DynamoDbLockTable;
+use crate::env_helper::EnvHelper;
+use crate::lock_table::LockTable;
+use crate::object_store::ObjectStore;
+use crate::s3_store::S3Store;
+use crate::segment_cache::SegmentCache;
+use anyhow::{Context, Result};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+use tantivy::directory::RamDirectory;
+
+// NOTE: Lambda's /tmp is 512 MB unless configured otherwise, and writers need room for new segments too
+const DEFAULT_CACHE_BYTES: u64 = 256 * 1024 * 1024;
+
+/// Where the index is kept.
+#[derive(Default, Clone)]
+pub enum IndexStorage {
+    /// A shared file system mounted at `EFS_MOUNT_PATH`.
+    #[default]
+    Mount,
+    /// A local directory, for tools and tests.
+    Path(PathBuf),
+    /// In memory, for tests and ephemeral use. Nothing survives the process.
+    Ram(Arc<RamStorage>),
+    /// An S3 bucket, or any other object store, with segment files cached locally. Writing
+    /// requires a lock table, so writers in different processes exclude each other.
+    S3 {
+        store: Arc<dyn ObjectStore>,
+        lock_table: Option<Arc<dyn LockTable>>,
+        cache: Arc<SegmentCache>,
+    },
+}
+
+impl IndexStorage {
+    /// Reads `INDEX_STORAGE` (`mount` or `s3`). S3 storage requires `INDEX_BUCKET` and optionally
+    /// takes `INDEX_PREFIX`, `INDEX_S3_ENDPOINT`, `INDEX_CACHE_PATH`, `INDEX_CACHE_BYTES` and
+    /// `INDEX_LOCK_TABLE`, which writers need. Must be called on the runtime that will run the
+    /// requests.
+    pub fn from_env(shared_config: &aws_types::SdkConfig) -> Result<IndexStorage> {
+        let storage = std::env::var("INDEX_STORAGE").unwrap_or_default();
+
+        match storage.as_str() {
+            "" | "mount" => Ok(IndexStorage::Mount),
+            "s3" => {
+                let bucket =
+                    std::env::var("INDEX_BUCKET").context("INDEX_BUCKET env var missing")?;
+                let prefix = std::env::var("INDEX_PREFIX").unwrap_or_default();
+                let endpoint = std::env::var("INDEX_S3_ENDPOINT").ok();
+                // NOTE: /tmp is the only writable path in lambda
+                let cache_path = std::env::var("INDEX_CACHE_PATH")
+                    .unwrap_or_else(|_| "/tmp/index-cache".to_string());
+                let cache_bytes =
+                    EnvHelper::parse("INDEX_CACHE_BYTES")?.unwrap_or(DEFAULT_CACHE_BYTES);
+
+                let store = S3Store::new(shared_config, &bucket, &prefix, endpoint.as_deref())?;
+                let lock_table: Option<Arc<dyn LockTable>> = match std::env::var("INDEX_LOCK_TABLE")
+                {
+                    Ok(table_name) => Some(Arc::new(DynamoDbLockTable::new(
+                        shared_config,
+                        &table_name,
+                    )?)),
+                    Err(_) => None,
+                };
+
+                Ok(IndexStorage::S3 {
+                    store: Arc::new(store),
+                    lock_table,
+                    cache: Arc::new(SegmentCache::new(Path::new(&cache_path), cache_bytes)?),
+                })
+            }
+            _ => Err(anyhow::anyhow!("INDEX_STORAGE is not valid")),
+        }
+    }
+}
+
+/// The in memory directories of each index and the other files of the index, shared by every
+/// schema using the same `RamStorage`, so a writer and a reader in the same process see the same
+/// indexes.
+#[derive(Default)]
+pub struct RamStorage {
+    directories: Mutex<HashMap<PathBuf, RamDirectory>>,
+    files: Mutex<HashMap<PathBuf, Vec<u8>>>,
+}
+
+impl RamStorage {
+    /// Returns the directory at the path, creating it if needed. Clones share their files.
+    pub fn directory(&self, path: &Path) -> RamDirectory {
+        let mut directories = self.directories.lock().expect("ram storage lock poisoned");
+        directories
+            .entry(path.to_path_buf())
+            .or_insert_with(RamDirectory::create)
+            .clone()
+    }
+
+    pub fn contains(&self, path: &Path) -> bool {
+        let directories = self.directories.lock().expect("ram storage lock poisoned");
+        directories.contains_key(path)
+    }
+
+    pub fn paths(&self) -> Vec<PathBuf> {
+        let directories = self.directories.lock().expect("ram storage lock poisoned");
+        directories.keys().cloned().collect()
+    }
+
+    pub fn remove(&self, path: &Path) {
+        let mut directories = self.directories.lock().expect("ram storage lock poisoned");
+        directories.remove(path);
+    }
+
+    pub fn size_bytes(&self, path: &Path) -> u64 {
+        let directories = self.directories.lock().expect("ram storage lock poisoned");
+        directories
+            .get(path)
+            .map(|directory| directory.total_mem_usage() as u64)
+            .unwrap_or_default()
+    }
+
+    pub fn read_file(&self, path: &Path) -> Option<Vec<u8>> {
+        let files = self.files.lock().expect("ram storage lock poisoned");
+        files.get(path).cloned()
+    }
+
+    pub fn write_file(&self, path: &Path, data: Vec<u8>) {
+        let mut files = self.files.lock().expect("ram storage lock poisoned");
+        files.insert(path.to_path_buf(), data);
+    }
+}
@@ -0,0 +1,96 @@
+use log::debug;
+use std::{collections::HashSet, sync::Mutex, time::Instant};
+use tantivy::{
+    fastfield::FastFieldReader,
+    schema::{Field, FieldType, Schema},
+    Searcher, SearcherGeneration, SegmentId, Warmer,
+};
+
+/// Reads the term dictionaries and fast fields of the new segments of a searcher before it serves
+/// requests, so the first searches after a reload don't wait on EFS or S3 reads. Segments never
+/// change, so each one is only warmed once, for the index reader of one partition.
+pub struct IndexWarmer {
+    schema: Schema,
+    /// The `body` terms dwarf every other field and few searches read them, so they stay cold.
+    body: Option<Field>,
+    warm_segment_ids: Mutex<HashSet<SegmentId>>,
+}
+
+impl IndexWarmer {
+    pub fn new(schema: Schema) -> Self {
+        IndexWarmer {
+            body: schema.get_field("body"),
+            schema,
+            warm_segment_ids: Mutex::new(HashSet::new()),
+        }
+    }
+}
+
+impl Warmer for IndexWarmer {
+    fn warm(&self, searcher: &Searcher) -> tantivy::Result<()> {
+        let start = Instant::now();
+        let mut warmed = 0;
+
+        for segment_reader in searcher.segment_readers() {
+            let segment_id = segment_reader.segment_id();
+            if self
+                .warm_segment_ids
+                .lock()
+                .expect("warm segment ids lock poisoned")
+                .contains(&segment_id)
+            {
+                continue;
+            }
+
+            for (field, field_entry) in self.schema.fields() {
+                if Some(field) == self.body {
+                    continue;
+                }
+
+                if field_entry.is_indexed() {
+                    let inverted_index = segment_reader.inverted_index(field)?;
+                    let mut stream = inverted_index.terms().stream()?;
+                    while stream.advance() {}
+                }
+
+                match field_entry.field_type() {
+                    FieldType::I64(options) if options.is_fast() => {
+                        let fast_field_reader = segment_reader.fast_fields().i64(field)?;
+                        let mut values = vec![0_i64; segment_reader.max_doc() as usize];
+                        fast_field_reader.get_range(0, &mut values);
+                    }
+                    FieldType::Facet(_) => {
+                        segment_reader.facet_reader(field)?;
+                    }
+                    _ => {}
+                }
+            }
+
+            self.warm_segment_ids
+                .lock()
+                .expect("warm segment ids lock poisoned")
+                .insert(segment_id);
+            warmed += 1;
+        }
+
+        debug!(
+            "warmed {warmed} of {} segments in {:?}",
+            searcher.segment_readers().len(),
+            start.elapsed()
+        );
+
+        Ok(())
+    }
+
+    fn garbage_collect(&self, live_generations: &[&SearcherGeneration]) {
+        // NOTE: Forget merged away segments, which no searcher of the partition reads anymore
+        let live_segment_ids: HashSet<SegmentId> = live_generations
+            .iter()
+            .flat_map(|generation| generation.segments().keys().copied())
+            .collect();
+        self.warm_segment_ids
+            .lock()
+            .expect("warm segment ids lock poisoned")
+            .retain(|segment_id| live_segment_ids.contains(segment_id));
+    }
+}
@@ -1,5 +1,47 @@
+pub mod aggregations;
 pub mod attribute_helper;
+pub mod audit_report;
+pub mod batch_request;
+pub mod batch_response;
+pub mod cache_settings;
+pub mod commit_payload;
+pub mod contact_directory;
+pub mod contacts_request;
+pub mod contacts_response;
+pub mod dynamodb_lock_table;
+pub mod dynamodb_streams;
 pub mod email;
+pub mod email_change;
+pub mod email_index_reader;
 pub mod email_index_schema;
+pub mod email_index_writer;
+pub mod env_helper;
+pub mod http_event;
+pub mod http_request;
+pub mod http_response;
+pub mod index_checkpoint;
+pub mod index_partitioning;
+pub mod index_snapshot;
+pub mod index_storage;
+pub mod index_warmer;
+pub mod lock_table;
+pub mod merge_settings;
+pub mod object_store;
+pub mod reader_request;
+pub mod reader_response;
+pub mod reload_policy;
+pub mod runtime_bridge;
+pub mod s3_directory;
+pub mod s3_store;
 pub mod search_request;
 pub mod search_response;
+pub mod segment_cache;
+pub mod stats_request;
+pub mod stats_response;
+pub mod status_request;
+pub mod status_response;
+pub mod stream_records;
+pub mod suggest_request;
+pub mod suggest_response;
+pub mod ttl_cache;
+pub mod writer_request;
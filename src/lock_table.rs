@@ -0,0 +1,13 @@
+use anyhow::Result;
+use std::{fmt, time::Duration};
+
+/// Leases on named locks shared by every process using the table, for storages that can't lock
+/// files themselves. Calls block, as tantivy's `Directory` is synchronous.
+pub trait LockTable: fmt::Debug + Send + Sync {
+    /// Takes or renews the lease on `key` for `owner` if it is free, expired or already held by
+    /// `owner`, returning whether it did.
+    fn try_acquire(&self, key: &str, owner: &str, lease: Duration) -> Result<bool>;
+
+    /// Gives up the lease on `key` if `owner` still holds it.
+    fn release(&self, key: &str, owner: &str) -> Result<()>;
+}
@@ -0,0 +1,62 @@
+use crate::env_helper::EnvHelper;
+use anyhow::Result;
+use tantivy::merge_policy::LogMergePolicy;
+
+/// Settings of the writer's `LogMergePolicy`. Unset values keep tantivy's defaults.
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct MergeSettings {
+    /// The fewest segments of a level that are merged together.
+    pub min_num_segments: Option<usize>,
+    /// Segments with more documents than this are no longer merged.
+    pub max_docs_before_merge: Option<usize>,
+    /// Segments with fewer documents than this all belong to the lowest level.
+    pub min_layer_size: Option<u32>,
+    /// The ratio between the sizes of consecutive levels.
+    pub level_log_size: Option<f64>,
+    /// The ratio of deleted documents in a segment that triggers a merge of its level.
+    pub del_docs_ratio: Option<f32>,
+}
+
+impl MergeSettings {
+    /// Reads `INDEX_MERGE_MIN_SEGMENTS`, `INDEX_MERGE_MAX_DOCS`, `INDEX_MERGE_MIN_LAYER_SIZE`,
+    /// `INDEX_MERGE_LEVEL_LOG_SIZE` and `INDEX_MERGE_DEL_DOCS_RATIO`, all optional.
+    pub fn from_env() -> Result<MergeSettings> {
+        let del_docs_ratio: Option<f32> = EnvHelper::parse("INDEX_MERGE_DEL_DOCS_RATIO")?;
+        // NOTE: tantivy panics on ratios outside of (0, 1]
+        if del_docs_ratio.is_some_and(|ratio| ratio <= 0.0 || ratio > 1.0) {
+            return Err(anyhow::anyhow!(
+                "INDEX_MERGE_DEL_DOCS_RATIO must be greater than 0 and at most 1"
+            ));
+        }
+
+        Ok(MergeSettings {
+            min_num_segments: EnvHelper::parse("INDEX_MERGE_MIN_SEGMENTS")?,
+            max_docs_before_merge: EnvHelper::parse("INDEX_MERGE_MAX_DOCS")?,
+            min_layer_size: EnvHelper::parse("INDEX_MERGE_MIN_LAYER_SIZE")?,
+            level_log_size: EnvHelper::parse("INDEX_MERGE_LEVEL_LOG_SIZE")?,
+            del_docs_ratio,
+        })
+    }
+
+    pub fn merge_policy(&self) -> LogMergePolicy {
+        let mut merge_policy = LogMergePolicy::default();
+
+        if let Some(min_num_segments) = self.min_num_segments {
+            merge_policy.set_min_num_segments(min_num_segments);
+        }
+        if let Some(max_docs_before_merge) = self.max_docs_before_merge {
+            merge_policy.set_max_docs_before_merge(max_docs_before_merge);
+        }
+        if let Some(min_layer_size) = self.min_layer_size {
+            merge_policy.set_min_layer_size(min_layer_size);
+        }
+        if let Some(level_log_size) = self.level_log_size {
+            merge_policy.set_level_log_size(level_log_size);
+        }
+        if let Some(del_docs_ratio) = self.del_docs_ratio {
+            merge_policy.set_del_docs_ratio_before_merge(del_docs_ratio);
+        }
+
+        merge_policy
+    }
+}
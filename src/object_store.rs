@@ -0,0 +1,29 @@
+use anyhow::Result;
+use std::fmt;
+
+/// An object in the store, with its key relative to the store prefix.
+#[derive(Clone, Debug)]
+pub struct ObjectMeta {
+    pub key: String,
+    pub size: u64,
+    pub etag: Option<String>,
+}
+
+/// The handful of object operations an index kept in an object store needs. Calls block, as
+/// tantivy's `Directory` is synchronous.
+pub trait ObjectStore: fmt::Debug + Send + Sync {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>>;
+
+    fn head(&self, key: &str) -> Result<Option<ObjectMeta>>;
+
+    fn put(&self, key: &str, data: Vec<u8>) -> Result<()>;
+
+    /// Deletes the object, succeeding if it doesn't exist.
+    fn delete(&self, key: &str) -> Result<()>;
+
+    /// Lists every object whose key starts with the prefix.
+    fn list(&self, prefix: &str) -> Result<Vec<ObjectMeta>>;
+
+    /// Returns a name for the key that is unique across stores, such as `s3://bucket/prefix/key`.
+    fn url(&self, key: &str) -> String;
+}
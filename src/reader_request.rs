@@ -0,0 +1,34 @@
+use crate::{
+    batch_request::BatchRequest, contacts_request::ContactsRequest, search_request::SearchRequest,
+    stats_request::StatsRequest, status_request::StatusRequest, suggest_request::SuggestRequest,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+#[derive(Deserialize, Serialize)]
+#[serde(tag = "operation", rename_all = "snake_case")]
+pub enum ReaderRequest {
+    Search(SearchRequest),
+    Suggest(SuggestRequest),
+    Contacts(ContactsRequest),
+    Batch(BatchRequest),
+    Status(StatusRequest),
+    Stats(StatsRequest),
+}
+
+impl ReaderRequest {
+    /// Parses a request body, treating bodies without an `operation` as a search so existing
+    /// callers that post a bare `SearchRequest` keep working.
+    pub fn parse(body: &str) -> anyhow::Result<ReaderRequest> {
+        let mut value: Value = serde_json::from_str(body)?;
+
+        if let Value::Object(object) = &mut value {
+            object
+                .entry("operation")
+                .or_insert_with(|| Value::String("search".to_string()));
+        }
+
+        let request = serde_json::from_value(value)?;
+        Ok(request)
+    }
+}
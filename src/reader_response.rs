@@ -0,0 +1,30 @@
+use crate::{
+    batch_response::BatchResponse, contacts_response::ContactsResponse,
+    search_response::SearchResponse, stats_response::StatsResponse,
+    status_response::StatusResponse, suggest_response::SuggestResponse,
+};
+use serde::Serialize;
+
+#[derive(Serialize, Debug)]
+#[serde(untagged)]
+pub enum ReaderResponse {
+    Search(SearchResponse),
+    Suggest(SuggestResponse),
+    Contacts(ContactsResponse),
+    Batch(BatchResponse),
+    Status(StatusResponse),
+    Stats(StatsResponse),
+}
+
+impl ReaderResponse {
+    pub fn error(&self) -> Option<&str> {
+        match self {
+            ReaderResponse::Search(response) => response.error.as_deref(),
+            ReaderResponse::Suggest(response) => response.error.as_deref(),
+            ReaderResponse::Contacts(response) => response.error.as_deref(),
+            ReaderResponse::Batch(response) => response.error.as_deref(),
+            ReaderResponse::Status(response) => response.error.as_deref(),
+            ReaderResponse::Stats(response) => response.error.as_deref(),
+        }
+    }
+}
@@ -0,0 +1,44 @@
+use crate::env_helper::EnvHelper;
+use anyhow::Result;
+use std::time::Duration;
+
+const DEFAULT_RELOAD_INTERVAL: Duration = Duration::from_secs(3);
+
+/// When the reader picks up new commits, trading freshness against reads of the index storage.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ReloadPolicy {
+    /// Reloads in the background every interval, whether or not anything was committed.
+    Interval(Duration),
+    /// Reloads in the background when the directory reports a commit. File watching is polling
+    /// based and unreliable on network file systems, and indexes in S3 can't be watched at all.
+    OnCommit,
+    /// Checks the `meta.json` of every partition on each request, and reloads before serving the
+    /// request if one has changed.
+    OnRequest,
+}
+
+impl Default for ReloadPolicy {
+    fn default() -> Self {
+        ReloadPolicy::Interval(DEFAULT_RELOAD_INTERVAL)
+    }
+}
+
+impl ReloadPolicy {
+    /// Reads `INDEX_RELOAD_POLICY` (`interval`, `on_commit` or `on_request`, `interval` by default)
+    /// and `INDEX_RELOAD_INTERVAL_MS` (3 seconds by default).
+    pub fn from_env() -> Result<ReloadPolicy> {
+        let reload_interval = EnvHelper::parse("INDEX_RELOAD_INTERVAL_MS")?
+            .map(Duration::from_millis)
+            .unwrap_or(DEFAULT_RELOAD_INTERVAL);
+
+        match std::env::var("INDEX_RELOAD_POLICY") {
+            Ok(reload_policy) => match reload_policy.as_str() {
+                "" | "interval" => Ok(ReloadPolicy::Interval(reload_interval)),
+                "on_commit" => Ok(ReloadPolicy::OnCommit),
+                "on_request" => Ok(ReloadPolicy::OnRequest),
+                _ => Err(anyhow::anyhow!("INDEX_RELOAD_POLICY is not valid")),
+            },
+            Err(_) => Ok(ReloadPolicy::Interval(reload_interval)),
+        }
+    }
+}
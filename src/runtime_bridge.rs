@@ -0,0 +1,34 @@
+use anyhow::{Context, Result};
+use std::future::Future;
+use tokio::runtime::Handle;
+
+/// Runs futures on the process's tokio runtime and waits for them from synchronous code, such as
+/// tantivy's directory calls.
+#[derive(Clone, Debug)]
+pub struct RuntimeBridge {
+    handle: Handle,
+}
+
+impl RuntimeBridge {
+    /// Uses the runtime the caller is running on, which has to be a multi threaded runtime.
+    pub fn current() -> Result<RuntimeBridge> {
+        let handle = Handle::try_current().context("Not running on a tokio runtime")?;
+
+        Ok(RuntimeBridge { handle })
+    }
+
+    pub fn block_on<F>(&self, future: F) -> Result<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        // NOTE: tantivy calls the directory from its own executors and from runtime threads, where
+        // Handle::block_on panics, so spawn the future and wait on a plain channel instead
+        let (sender, receiver) = std::sync::mpsc::channel();
+        self.handle.spawn(async move {
+            let _ = sender.send(future.await);
+        });
+
+        receiver.recv().context("Runtime dropped the request")
+    }
+}
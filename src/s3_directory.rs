@@ -0,0 +1,350 @@
+use crate::lock_table::LockTable;
+use crate::object_store::ObjectStore;
+use crate::segment_cache::SegmentCache;
+use log::{debug, error};
+use std::{
+    fmt,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    thread::JoinHandle,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tantivy::directory::{
+    error::{DeleteError, LockError, OpenDirectoryError, OpenReadError, OpenWriteError},
+    AntiCallToken, Directory, DirectoryLock, FileHandle, Lock, MmapDirectory, TerminatingWrite,
+    WatchCallback, WatchHandle, WritePtr, INDEX_WRITER_LOCK,
+};
+
+// NOTE: Long enough to ride out a slow DynamoDB call, short enough that a crashed writer doesn't hold up the
+// next one for long. The lease is renewed a few times per period while it is held.
+const LOCK_LEASE: Duration = Duration::from_secs(60);
+const LOCK_RENEW_INTERVAL: Duration = Duration::from_secs(20);
+
+static LOCK_OWNER_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// A tantivy `Directory` that keeps the index in an object store under `prefix`, with the files
+/// it has read or written cached locally.
+///
+/// Segment files are immutable once written, so they are cached until evicted. `meta.json` and
+/// `.managed.json` are only ever written with `atomic_write`, so they always come from the store
+/// and `meta.json` acts as the commit point.
+#[derive(Clone)]
+pub struct S3Directory {
+    store: Arc<dyn ObjectStore>,
+    lock_table: Option<Arc<dyn LockTable>>,
+    segment_cache: Arc<SegmentCache>,
+    prefix: String,
+    cache_path: PathBuf,
+    cache: MmapDirectory,
+}
+
+impl fmt::Debug for S3Directory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("S3Directory")
+            .field("store", &self.store)
+            .field("lock_table", &self.lock_table)
+            .field("prefix", &self.prefix)
+            .field("cache_path", &self.cache_path)
+            .finish()
+    }
+}
+
+impl S3Directory {
+    /// Caches the files under `prefix` in the cache directory of the same name.
+    pub fn open(
+        store: Arc<dyn ObjectStore>,
+        lock_table: Option<Arc<dyn LockTable>>,
+        segment_cache: Arc<SegmentCache>,
+        prefix: &str,
+    ) -> Result<S3Directory, OpenDirectoryError> {
+        let prefix = prefix.trim_matches('/').to_string();
+        let cache_path = segment_cache.root().join(&prefix);
+        std::fs::create_dir_all(&cache_path)
+            .map_err(|error| OpenDirectoryError::wrap_io_error(error, cache_path.clone()))?;
+        let cache = MmapDirectory::open(&cache_path)?;
+
+        Ok(S3Directory {
+            store,
+            lock_table,
+            segment_cache,
+            prefix,
+            cache_path,
+            cache,
+        })
+    }
+
+    fn object_key(&self, path: &Path) -> String {
+        format!("{}/{}", self.prefix, path.to_string_lossy())
+    }
+
+    fn download(&self, path: &Path) -> Result<(), OpenReadError> {
+        debug!("downloading {:?}", path);
+        let data = self
+            .store
+            .get(&self.object_key(path))
+            .map_err(|error| OpenReadError::wrap_io_error(to_io_error(error), path.into()))?
+            .ok_or_else(|| OpenReadError::FileDoesNotExist(path.to_path_buf()))?;
+
+        // NOTE: Write to a temp file and rename so a concurrent read never sees a partial file
+        let cache_file = self.cache_path.join(path);
+        let temp_file = cache_file.with_extension("download");
+        let size = data.len() as u64;
+        std::fs::write(&temp_file, data)
+            .and_then(|_| std::fs::rename(&temp_file, &cache_file))
+            .map_err(|error| OpenReadError::wrap_io_error(error, path.into()))?;
+        self.segment_cache.insert(&cache_file, size);
+
+        Ok(())
+    }
+}
+
+impl Directory for S3Directory {
+    fn get_file_handle(&self, path: &Path) -> Result<Box<dyn FileHandle>, OpenReadError> {
+        let cache_file = self.cache_path.join(path);
+
+        if !self.segment_cache.touch(&cache_file) && !cache_file.exists() {
+            self.download(path)?;
+        }
+
+        // NOTE: Another partition may have evicted the file since it was checked
+        match self.cache.get_file_handle(path) {
+            Err(OpenReadError::FileDoesNotExist(_)) => {
+                self.download(path)?;
+                self.cache.get_file_handle(path)
+            }
+            result => result,
+        }
+    }
+
+    fn delete(&self, path: &Path) -> Result<(), DeleteError> {
+        self.store
+            .delete(&self.object_key(path))
+            .map_err(|error| DeleteError::IoError {
+                io_error: to_io_error(error),
+                filepath: path.to_path_buf(),
+            })?;
+
+        self.segment_cache
+            .remove(&self.cache_path.join(path))
+            .map_err(|io_error| DeleteError::IoError {
+                io_error,
+                filepath: path.to_path_buf(),
+            })
+    }
+
+    fn exists(&self, path: &Path) -> Result<bool, OpenReadError> {
+        if self.cache_path.join(path).exists() {
+            return Ok(true);
+        }
+
+        let object_meta = self
+            .store
+            .head(&self.object_key(path))
+            .map_err(|error| OpenReadError::wrap_io_error(to_io_error(error), path.into()))?;
+        Ok(object_meta.is_some())
+    }
+
+    fn open_write(&self, path: &Path) -> Result<WritePtr, OpenWriteError> {
+        let writer = self.cache.open_write(path)?;
+
+        Ok(io::BufWriter::new(Box::new(S3Writer {
+            writer: Some(writer),
+            store: self.store.clone(),
+            segment_cache: self.segment_cache.clone(),
+            key: self.object_key(path),
+            cache_file: self.cache_path.join(path),
+        })))
+    }
+
+    fn atomic_read(&self, path: &Path) -> Result<Vec<u8>, OpenReadError> {
+        self.store
+            .get(&self.object_key(path))
+            .map_err(|error| OpenReadError::wrap_io_error(to_io_error(error), path.into()))?
+            .ok_or_else(|| OpenReadError::FileDoesNotExist(path.to_path_buf()))
+    }
+
+    fn atomic_write(&self, path: &Path, data: &[u8]) -> io::Result<()> {
+        self.store
+            .put(&self.object_key(path), data.to_vec())
+            .map_err(to_io_error)
+    }
+
+    fn sync_directory(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    // NOTE: Writers in other processes only see the writer lock through the lock table. The meta lock stays
+    // local, as it only keeps garbage collection from deleting files this process is opening.
+    fn acquire_lock(&self, lock: &Lock) -> Result<DirectoryLock, LockError> {
+        if lock.filepath != INDEX_WRITER_LOCK.filepath {
+            return self.cache.acquire_lock(lock);
+        }
+
+        let lock_table = self.lock_table.clone().ok_or_else(|| {
+            LockError::IoError(io::Error::other(
+                "Writing to an index in S3 requires a lock table",
+            ))
+        })?;
+
+        let key = self.store.url(&self.object_key(&lock.filepath));
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_nanos())
+            .unwrap_or_default();
+        let owner = format!(
+            "{}-{}-{}",
+            std::process::id(),
+            nanos,
+            LOCK_OWNER_COUNTER.fetch_add(1, Ordering::Relaxed)
+        );
+
+        loop {
+            let acquired = lock_table
+                .try_acquire(&key, &owner, LOCK_LEASE)
+                .map_err(|error| LockError::IoError(to_io_error(error)))?;
+            if acquired {
+                break;
+            }
+            if !lock.is_blocking {
+                return Err(LockError::LockBusy);
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+
+        debug!("took the lease on {key}");
+        Ok(DirectoryLock::from(Box::new(LeaseGuard::new(
+            lock_table, key, owner,
+        ))))
+    }
+
+    // NOTE: Changes made by other processes can't be watched, so readers have to reload manually
+    fn watch(&self, _watch_callback: WatchCallback) -> tantivy::Result<WatchHandle> {
+        Ok(WatchHandle::empty())
+    }
+}
+
+/// Holds a lease on the lock table, renewing it in the background until dropped.
+struct LeaseGuard {
+    lock_table: Arc<dyn LockTable>,
+    key: String,
+    owner: String,
+    stopped: Arc<(Mutex<bool>, Condvar)>,
+    renewer: Option<JoinHandle<()>>,
+}
+
+impl LeaseGuard {
+    fn new(lock_table: Arc<dyn LockTable>, key: String, owner: String) -> LeaseGuard {
+        let stopped = Arc::new((Mutex::new(false), Condvar::new()));
+
+        let renewer = {
+            let lock_table = lock_table.clone();
+            let key = key.clone();
+            let owner = owner.clone();
+            let stopped = stopped.clone();
+
+            std::thread::spawn(move || {
+                let (lock, condvar) = &*stopped;
+                let mut is_stopped = lock.lock().expect("lease lock poisoned");
+                loop {
+                    let (guard, timeout) = condvar
+                        .wait_timeout(is_stopped, LOCK_RENEW_INTERVAL)
+                        .expect("lease lock poisoned");
+                    is_stopped = guard;
+                    if *is_stopped {
+                        return;
+                    }
+                    if !timeout.timed_out() {
+                        continue;
+                    }
+
+                    // NOTE: tantivy can't be told it lost the writer lock, so all that can be done is to make noise
+                    match lock_table.try_acquire(&key, &owner, LOCK_LEASE) {
+                        Ok(true) => debug!("renewed the lease on {key}"),
+                        Ok(false) => error!("lost the lease on {key} to another writer"),
+                        Err(renew_error) => {
+                            error!("error renewing the lease on {key}: {renew_error}")
+                        }
+                    }
+                }
+            })
+        };
+
+        LeaseGuard {
+            lock_table,
+            key,
+            owner,
+            stopped,
+            renewer: Some(renewer),
+        }
+    }
+}
+
+impl Drop for LeaseGuard {
+    fn drop(&mut self) {
+        let (lock, condvar) = &*self.stopped;
+        *lock.lock().expect("lease lock poisoned") = true;
+        condvar.notify_all();
+        if let Some(renewer) = self.renewer.take() {
+            let _ = renewer.join();
+        }
+
+        match self.lock_table.release(&self.key, &self.owner) {
+            Ok(()) => debug!("released the lease on {}", self.key),
+            Err(release_error) => error!(
+                "error releasing the lease on {}, it expires in {:?}: {release_error}",
+                self.key, LOCK_LEASE
+            ),
+        }
+    }
+}
+
+/// Writes to the cache file, then uploads it once tantivy is done with it.
+struct S3Writer {
+    writer: Option<WritePtr>,
+    store: Arc<dyn ObjectStore>,
+    segment_cache: Arc<SegmentCache>,
+    key: String,
+    cache_file: PathBuf,
+}
+
+impl Write for S3Writer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match &mut self.writer {
+            Some(writer) => writer.write(buf),
+            None => Err(io::Error::other("write after terminate")),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.writer {
+            Some(writer) => writer.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl TerminatingWrite for S3Writer {
+    fn terminate_ref(&mut self, _: AntiCallToken) -> io::Result<()> {
+        if let Some(writer) = self.writer.take() {
+            writer.into_inner()?.terminate()?;
+
+            debug!("uploading {}", self.key);
+            let data = std::fs::read(&self.cache_file)?;
+            let size = data.len() as u64;
+            self.store.put(&self.key, data).map_err(to_io_error)?;
+
+            // NOTE: Only cache the file once it's uploaded, as evicting it before would lose it
+            self.segment_cache.insert(&self.cache_file, size);
+        }
+
+        Ok(())
+    }
+}
+
+fn to_io_error(error: anyhow::Error) -> io::Error {
+    io::Error::other(error.to_string())
+}
@@ -0,0 +1,180 @@
+use crate::object_store::{ObjectMeta, ObjectStore};
+use crate::runtime_bridge::RuntimeBridge;
+use anyhow::{Context, Result};
+use aws_sdk_s3::{
+    error::{GetObjectErrorKind, HeadObjectErrorKind},
+    types::{ByteStream, SdkError},
+    Client, Endpoint,
+};
+use futures::StreamExt;
+use std::fmt;
+
+/// An S3 bucket, or an S3 compatible stand-in such as MinIO when given an endpoint, with every
+/// key under `prefix`.
+pub struct S3Store {
+    client: Client,
+    bucket: String,
+    prefix: String,
+    runtime: RuntimeBridge,
+}
+
+impl fmt::Debug for S3Store {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("S3Store")
+            .field("bucket", &self.bucket)
+            .field("prefix", &self.prefix)
+            .finish()
+    }
+}
+
+impl S3Store {
+    /// Must be called on the runtime that will run the requests.
+    pub fn new(
+        shared_config: &aws_types::SdkConfig,
+        bucket: &str,
+        prefix: &str,
+        endpoint: Option<&str>,
+    ) -> Result<S3Store> {
+        let mut config = aws_sdk_s3::config::Builder::from(shared_config);
+        if let Some(endpoint) = endpoint {
+            let uri = endpoint.parse().context("S3 endpoint is not valid")?;
+            config = config.endpoint_resolver(Endpoint::immutable(uri));
+        }
+
+        Ok(S3Store {
+            client: Client::from_conf(config.build()),
+            bucket: bucket.to_string(),
+            prefix: prefix.trim_matches('/').to_string(),
+            runtime: RuntimeBridge::current()?,
+        })
+    }
+
+    fn object_key(&self, key: &str) -> String {
+        if self.prefix.is_empty() {
+            key.to_string()
+        } else {
+            format!("{}/{}", self.prefix, key)
+        }
+    }
+}
+
+impl ObjectStore for S3Store {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        let request = self
+            .client
+            .get_object()
+            .bucket(&self.bucket)
+            .key(self.object_key(key));
+
+        self.runtime
+            .block_on(async move {
+                match request.send().await {
+                    Ok(output) => Ok(Some(output.body.collect().await?.into_bytes().to_vec())),
+                    Err(SdkError::ServiceError { err, .. })
+                        if matches!(err.kind, GetObjectErrorKind::NoSuchKey(_)) =>
+                    {
+                        Ok(None)
+                    }
+                    Err(error) => Err(anyhow::Error::from(error)),
+                }
+            })?
+            .with_context(|| format!("Error getting {key}"))
+    }
+
+    fn head(&self, key: &str) -> Result<Option<ObjectMeta>> {
+        let request = self
+            .client
+            .head_object()
+            .bucket(&self.bucket)
+            .key(self.object_key(key));
+
+        let output = self
+            .runtime
+            .block_on(async move {
+                match request.send().await {
+                    Ok(output) => Ok(Some(output)),
+                    Err(SdkError::ServiceError { err, .. })
+                        if matches!(err.kind, HeadObjectErrorKind::NotFound(_)) =>
+                    {
+                        Ok(None)
+                    }
+                    Err(error) => Err(anyhow::Error::from(error)),
+                }
+            })?
+            .with_context(|| format!("Error getting the metadata of {key}"))?;
+
+        Ok(output.map(|output| ObjectMeta {
+            key: key.to_string(),
+            size: output.content_length.max(0) as u64,
+            etag: output.e_tag,
+        }))
+    }
+
+    fn put(&self, key: &str, data: Vec<u8>) -> Result<()> {
+        let request = self
+            .client
+            .put_object()
+            .bucket(&self.bucket)
+            .key(self.object_key(key))
+            .body(ByteStream::from(data));
+
+        self.runtime
+            .block_on(async move { request.send().await })?
+            .with_context(|| format!("Error putting {key}"))?;
+
+        Ok(())
+    }
+
+    fn delete(&self, key: &str) -> Result<()> {
+        let request = self
+            .client
+            .delete_object()
+            .bucket(&self.bucket)
+            .key(self.object_key(key));
+
+        // NOTE: S3 succeeds when deleting an object that doesn't exist
+        self.runtime
+            .block_on(async move { request.send().await })?
+            .with_context(|| format!("Error deleting {key}"))?;
+
+        Ok(())
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<ObjectMeta>> {
+        let store_prefix = self.object_key("");
+        let mut pages = self
+            .client
+            .list_objects_v2()
+            .bucket(&self.bucket)
+            .prefix(self.object_key(prefix))
+            .into_paginator()
+            .send();
+
+        let objects = self.runtime.block_on(async move {
+            let mut objects: Vec<ObjectMeta> = vec![];
+            while let Some(page) = pages.next().await {
+                for object in page?.contents.unwrap_or_default() {
+                    let key = object.key.context("Listed object has no key")?;
+                    let key = match key.strip_prefix(&store_prefix) {
+                        Some(key) => key.to_string(),
+                        None => key,
+                    };
+
+                    objects.push(ObjectMeta {
+                        key,
+                        size: object.size.max(0) as u64,
+                        etag: object.e_tag,
+                    });
+                }
+            }
+
+            Ok::<_, anyhow::Error>(objects)
+        })?;
+
+        objects.with_context(|| format!("Error listing {prefix}"))
+    }
+
+    fn url(&self, key: &str) -> String {
+        format!("s3://{}/{}", self.bucket, self.object_key(key))
+    }
+}
@@ -1,7 +1,70 @@
+use crate::aggregations::AggregationsRequest;
 use serde::{Deserialize, Serialize};
+use serde_json::json;
 
-#[derive(Deserialize, Serialize)]
+#[derive(Deserialize, Serialize, Default, Clone)]
 pub struct SearchRequest {
     pub query: Option<String>,
     pub limit: Option<usize>,
+    pub mode: Option<SearchMode>,
+    pub fields: Option<Vec<String>>,
+    /// Waits (bounded) until the index has an email at a version or newer, such as the one the
+    /// caller just wrote.
+    pub min_version: Option<MinVersion>,
+    /// Includes emails whose ttl has passed but that DynamoDB has not deleted yet.
+    pub include_expired: Option<bool>,
+    pub aggregations: Option<AggregationsRequest>,
+    /// Newest first by default.
+    pub sort: Option<SearchSort>,
+}
+
+impl SearchRequest {
+    /// Identifies the index search of the request. Requests that differ only in whitespace,
+    /// defaults, `min_version` or the hydrated `fields` share a key.
+    pub fn cache_key(&self) -> String {
+        let count = self.mode == Some(SearchMode::Count);
+
+        json!({
+            "query": self.query.as_deref().unwrap_or_default().split_whitespace().collect::<Vec<_>>().join(" "),
+            "limit": if count { None } else { Some(self.limit.unwrap_or(10)) },
+            "count": count,
+            "include_expired": self.include_expired.unwrap_or(false),
+            "aggregations": self.aggregations,
+            "sort": if count { None } else { Some(self.sort.unwrap_or_default()) },
+        })
+        .to_string()
+    }
+}
+
+/// An email the index has to have caught up to. Stream sequence numbers can't be waited on, as
+/// they are only ordered within a stream shard and PutItem doesn't return them, so writes are
+/// identified by the item's `version` attribute, which writers of the table increment.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct MinVersion {
+    pub id: String,
+    /// Items without a `version` are at version 0, so leaving it out waits for the email to be
+    /// indexed at all.
+    pub version: Option<i64>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum SearchMode {
+    /// Returns the matching emails hydrated from DynamoDB.
+    Full,
+    /// Returns the matching ids with their scores without reading from DynamoDB.
+    Ids,
+    /// Returns only the document counts.
+    Count,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Default, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum SearchSort {
+    /// Newest emails first, then by score.
+    #[default]
+    Timestamp,
+    /// Best matches first. Each partition scores with its own term statistics, so across
+    /// partitions and shards the order is only approximately by relevance.
+    Score,
 }
@@ -1,13 +1,30 @@
+use crate::aggregations::AggregationsResponse;
+use crate::commit_payload::CommitPayload;
 use crate::email::Email;
 
 use serde::Deserialize;
 use serde::Serialize;
 
-#[derive(Serialize, Deserialize, Default, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SearchHit {
+    pub id: String,
+    pub score: f32,
+}
+
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
 pub struct SearchResponse {
     pub index_num_docs: Option<u64>,
     pub query_num_docs: Option<usize>,
+    pub hits: Option<Vec<SearchHit>>,
     pub emails: Option<Vec<Email>>,
+    pub aggregations: Option<AggregationsResponse>,
+    pub version: Option<String>,
+    pub indexed_through: Option<i64>,
+    pub lag_seconds: Option<i64>,
+    /// Identifies the reload of the index the search ran against.
+    pub searcher_generation: Option<u64>,
+    /// Whether the index was not searched because an identical search was cached.
+    pub cached: Option<bool>,
     pub error: Option<String>,
 }
 
@@ -19,12 +36,41 @@ impl SearchResponse {
         }
     }
 
-    pub fn success(total: u64, count: usize, emails: Vec<Email>) -> Self {
+    pub fn success(
+        total: u64,
+        count: usize,
+        hits: Option<Vec<SearchHit>>,
+        emails: Option<Vec<Email>>,
+        aggregations: Option<AggregationsResponse>,
+    ) -> Self {
         SearchResponse {
             index_num_docs: Some(total),
             query_num_docs: Some(count),
-            emails: Some(emails),
+            hits,
+            emails,
+            aggregations,
+            version: None,
+            indexed_through: None,
+            lag_seconds: None,
+            searcher_generation: None,
+            cached: None,
             error: None,
         }
     }
+
+    pub fn with_commit_payload(self, commit_payload: &CommitPayload) -> Self {
+        SearchResponse {
+            version: commit_payload.sequence_number.clone(),
+            indexed_through: commit_payload.indexed_through,
+            lag_seconds: commit_payload.lag_seconds(),
+            ..self
+        }
+    }
+
+    pub fn with_searcher_generation(self, searcher_generation: u64) -> Self {
+        SearchResponse {
+            searcher_generation: Some(searcher_generation),
+            ..self
+        }
+    }
 }
@@ -0,0 +1,181 @@
+use anyhow::Result;
+use log::debug;
+use lru::LruCache;
+use std::{
+    collections::HashSet,
+    io,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+use tantivy::Index;
+
+struct CachedFiles {
+    sizes: LruCache<PathBuf, u64>,
+    size_bytes: u64,
+}
+
+/// The local copies of index files kept in an object store, shared by the directories of every
+/// partition. Once the files add up to more than `max_bytes`, the least recently used are
+/// deleted, to be downloaded again when next opened.
+pub struct SegmentCache {
+    root: PathBuf,
+    max_bytes: u64,
+    files: Mutex<CachedFiles>,
+}
+
+impl SegmentCache {
+    /// Picks up the files already under `root`, such as those of a previous process in the same
+    /// lambda sandbox.
+    pub fn new(root: &Path, max_bytes: u64) -> Result<SegmentCache> {
+        std::fs::create_dir_all(root)?;
+
+        let segment_cache = SegmentCache {
+            root: root.to_path_buf(),
+            max_bytes,
+            files: Mutex::new(CachedFiles {
+                sizes: LruCache::unbounded(),
+                size_bytes: 0,
+            }),
+        };
+
+        let mut dirs = vec![root.to_path_buf()];
+        while let Some(dir) = dirs.pop() {
+            for entry in std::fs::read_dir(&dir)? {
+                let entry = entry?;
+                let file_type = entry.file_type()?;
+                if file_type.is_dir() {
+                    dirs.push(entry.path());
+                } else if file_type.is_file() && is_segment_file(&entry.path()) {
+                    segment_cache.insert(&entry.path(), entry.metadata()?.len());
+                }
+            }
+        }
+
+        Ok(segment_cache)
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn max_bytes(&self) -> u64 {
+        self.max_bytes
+    }
+
+    pub fn size_bytes(&self) -> u64 {
+        self.files.lock().expect("cache lock poisoned").size_bytes
+    }
+
+    /// Marks the file as used, returning false if it isn't cached.
+    pub fn touch(&self, path: &Path) -> bool {
+        let mut files = self.files.lock().expect("cache lock poisoned");
+        files.sizes.get(&path.to_path_buf()).is_some()
+    }
+
+    /// Adds a file that was just downloaded or written, then evicts other files until the cache
+    /// fits. The new file is kept even if it's larger than the cache on its own.
+    pub fn insert(&self, path: &Path, size: u64) {
+        let mut files = self.files.lock().expect("cache lock poisoned");
+        if let Some(previous_size) = files.sizes.put(path.to_path_buf(), size) {
+            files.size_bytes -= previous_size;
+        }
+        files.size_bytes += size;
+
+        while files.size_bytes > self.max_bytes {
+            let evicted = match files.sizes.peek_lru() {
+                Some((lru_path, _)) if lru_path != path => files.sizes.pop_lru(),
+                _ => None,
+            };
+            let (evicted_path, evicted_size) = match evicted {
+                Some(evicted) => evicted,
+                None => break,
+            };
+
+            // NOTE: Searchers that have the file open keep reading it after it is unlinked
+            debug!("evicting {:?}", evicted_path);
+            files.size_bytes -= evicted_size;
+            if let Err(error) = remove_file(&evicted_path) {
+                debug!("error evicting {:?}: {}", evicted_path, error);
+            }
+        }
+    }
+
+    /// Deletes a cached file, if there is one.
+    pub fn remove(&self, path: &Path) -> io::Result<()> {
+        let mut files = self.files.lock().expect("cache lock poisoned");
+        if let Some(size) = files.sizes.pop(&path.to_path_buf()) {
+            files.size_bytes -= size;
+        }
+
+        remove_file(path)
+    }
+
+    /// Deletes every cached file under the directory.
+    pub fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        let mut files = self.files.lock().expect("cache lock poisoned");
+        let cached_paths: Vec<PathBuf> = files
+            .sizes
+            .iter()
+            .filter(|(cached_path, _)| cached_path.starts_with(path))
+            .map(|(cached_path, _)| cached_path.clone())
+            .collect();
+        for cached_path in cached_paths {
+            if let Some(size) = files.sizes.pop(&cached_path) {
+                files.size_bytes -= size;
+            }
+        }
+
+        match std::fs::remove_dir_all(path) {
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    }
+
+    /// Removes the cached files of the index at `path` that it no longer uses, such as segments
+    /// that have been merged away.
+    pub fn prune(&self, index: &Index, path: &Path) -> Result<()> {
+        if !path.exists() {
+            return Ok(());
+        }
+
+        let live_files: HashSet<PathBuf> = index
+            .searchable_segment_metas()?
+            .iter()
+            .flat_map(|segment_meta| segment_meta.list_files())
+            .collect();
+
+        for entry in std::fs::read_dir(path)? {
+            let entry = entry?;
+            let file_name = PathBuf::from(entry.file_name());
+
+            if entry.file_type()?.is_file()
+                && is_segment_file(&file_name)
+                && !live_files.contains(&file_name)
+            {
+                debug!("pruning {:?}", file_name);
+                self.remove(&entry.path())?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns false for lock files and downloads in progress, which are never cached or evicted.
+fn is_segment_file(path: &Path) -> bool {
+    let is_hidden = path
+        .file_name()
+        .is_some_and(|file_name| file_name.to_string_lossy().starts_with('.'));
+
+    !is_hidden
+        && path
+            .extension()
+            .is_some_and(|extension| extension != "download")
+}
+
+fn remove_file(path: &Path) -> io::Result<()> {
+    match std::fs::remove_file(path) {
+        Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
@@ -0,0 +1,31 @@
+use crate::commit_payload::CommitPayload;
+use crate::ttl_cache::CacheStats;
+
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SegmentStats {
+    pub partition: String,
+    pub segment_id: String,
+    pub num_docs: u32,
+    pub num_deleted_docs: u32,
+    pub size_bytes: u64,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct StatsResponse {
+    pub index_num_docs: Option<u64>,
+    pub index_num_deleted_docs: Option<u64>,
+    pub segments: Option<Vec<SegmentStats>>,
+    /// Terms per indexed field, summed over segments so terms shared by segments count twice.
+    pub field_num_terms: Option<BTreeMap<String, u64>>,
+    pub on_disk_bytes: Option<u64>,
+    pub schema_fingerprint: Option<String>,
+    pub index_schema_fingerprint: Option<String>,
+    pub commit_payload: Option<CommitPayload>,
+    pub query_cache: Option<CacheStats>,
+    pub email_cache: Option<CacheStats>,
+    pub error: Option<String>,
+}
@@ -0,0 +1,29 @@
+use crate::commit_payload::CommitPayload;
+
+use serde::Deserialize;
+use serde::Serialize;
+
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct StatusResponse {
+    pub index_num_docs: Option<u64>,
+    pub version: Option<String>,
+    pub indexed_through: Option<i64>,
+    pub committed_at: Option<i64>,
+    pub lag_seconds: Option<i64>,
+    pub commit_age_seconds: Option<i64>,
+    pub error: Option<String>,
+}
+
+impl StatusResponse {
+    pub fn success(total: u64, commit_payload: &CommitPayload) -> Self {
+        StatusResponse {
+            index_num_docs: Some(total),
+            version: commit_payload.sequence_number.clone(),
+            indexed_through: commit_payload.indexed_through,
+            committed_at: commit_payload.committed_at,
+            lag_seconds: commit_payload.lag_seconds(),
+            commit_age_seconds: commit_payload.commit_age_seconds(),
+            error: None,
+        }
+    }
+}
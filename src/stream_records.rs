@@ -0,0 +1,146 @@
+use crate::dynamodb_streams::{DynamoDbStreams, StreamBatchInfo};
+use anyhow::{Context, Result};
+use aws_lambda_events::dynamodb::EventRecord;
+use aws_lambda_events::event::kinesis::KinesisEvent;
+use aws_lambda_events::event::sqs::{SqsEvent, SqsMessage};
+use log::error;
+use serde_json::Value;
+
+/// Decodes the DynamoDB change records that Kinesis Data Streams for DynamoDB delivers base64
+/// encoded. They have no DynamoDB sequence number, and the Kinesis ones are not comparable with
+/// it, so they leave the index version alone. Kinesis may also deliver them out of order or more
+/// than once, which the writer skips as stale changes.
+pub fn from_kinesis(event: KinesisEvent) -> Result<Vec<EventRecord>> {
+    let mut records: Vec<EventRecord> = vec![];
+
+    for record in event.records {
+        let change: Value = serde_json::from_slice(&record.kinesis.data.0)
+            .context("Kinesis record is not a DynamoDB change record")?;
+
+        records.push(parse_change_record(
+            change,
+            record.event_source_arn.as_deref(),
+        )?);
+    }
+
+    Ok(records)
+}
+
+/// Returns true for records delivered by Kinesis Data Streams for DynamoDB.
+pub fn is_kinesis_record(record: &EventRecord) -> bool {
+    record.event_source_arn.starts_with("arn:aws:kinesis:")
+}
+
+/// Decodes the change records replayed through SQS, returning them with the ids of the messages
+/// that failed to decode. A message holds a stream record, a Kinesis change record, a whole
+/// stream event or the notice of a failed stream batch that a DynamoDB stream event source sends
+/// to its on-failure destination. The records of failed batches are read back from the stream,
+/// which only keeps them for 24 hours. Replayed records are usually older than what is already
+/// indexed, which the writer skips as stale changes.
+pub async fn from_sqs(
+    event: SqsEvent,
+    dynamodb_streams: Option<&DynamoDbStreams>,
+) -> (Vec<EventRecord>, Vec<String>) {
+    let mut records: Vec<EventRecord> = vec![];
+    let mut failed_message_ids: Vec<String> = vec![];
+
+    for message in event.records {
+        let message_id = message.message_id.clone().unwrap_or_default();
+
+        // NOTE: Later messages may hold newer changes of the same emails, so retry them too rather than
+        // index them ahead of the failed one
+        if !failed_message_ids.is_empty() {
+            failed_message_ids.push(message_id);
+            continue;
+        }
+
+        match from_sqs_message(message, dynamodb_streams).await {
+            Ok(message_records) => records.extend(message_records),
+            Err(decode_error) => {
+                error!("error decoding SQS message {message_id}: {decode_error:?}");
+                failed_message_ids.push(message_id);
+            }
+        }
+    }
+
+    (records, failed_message_ids)
+}
+
+async fn from_sqs_message(
+    message: SqsMessage,
+    dynamodb_streams: Option<&DynamoDbStreams>,
+) -> Result<Vec<EventRecord>> {
+    let message_id = message.message_id.unwrap_or_default();
+    let body: Value = serde_json::from_str(message.body.as_deref().unwrap_or_default())
+        .with_context(|| format!("SQS message {message_id} is not JSON"))?;
+
+    if let Some(batch_info) = body.get("DDBStreamBatchInfo") {
+        let batch_info: StreamBatchInfo = serde_json::from_value(batch_info.clone())
+            .with_context(|| format!("SQS message {message_id} has invalid batch info"))?;
+        let dynamodb_streams = dynamodb_streams
+            .context("Reading failed stream batches requires a DynamoDB streams client")?;
+        dynamodb_streams.read_batch(&batch_info).await
+    } else if body.get("KinesisBatchInfo").is_some() {
+        Err(anyhow::anyhow!(
+            "SQS message {message_id} is a failed Kinesis batch, replay it from the stream"
+        ))
+    } else if let Some(Value::Array(stream_records)) = body.get("Records") {
+        stream_records
+            .iter()
+            .map(|stream_record| parse_change_record(stream_record.clone(), None))
+            .collect()
+    } else if body.get("dynamodb").is_some() {
+        Ok(vec![parse_change_record(body, None)?])
+    } else {
+        Err(anyhow::anyhow!(
+            "SQS message {message_id} has no DynamoDB change records"
+        ))
+    }
+}
+
+/// Parses a stream record, or a Kinesis change record, which has creation times in milliseconds
+/// and lacks the sequence number, event version and source ARN.
+pub fn parse_change_record(
+    mut change: Value,
+    event_source_arn: Option<&str>,
+) -> Result<EventRecord> {
+    let object = change
+        .as_object_mut()
+        .context("Change record is not an object")?;
+
+    let is_kinesis_format = object.contains_key("recordFormat");
+
+    let event_source_arn = object
+        .get("eventSourceARN")
+        .and_then(|value| value.as_str())
+        .or(event_source_arn)
+        .or_else(|| object.get("tableName").and_then(|value| value.as_str()))
+        .unwrap_or_default()
+        .to_string();
+    object.insert(
+        "eventSourceARN".to_string(),
+        Value::String(event_source_arn),
+    );
+    object
+        .entry("eventVersion")
+        .or_insert_with(|| Value::String("1.1".to_string()));
+
+    let stream_record = object
+        .get_mut("dynamodb")
+        .and_then(|value| value.as_object_mut())
+        .context("Change record has no dynamodb record")?;
+
+    if is_kinesis_format {
+        if let Some(created_at) = stream_record
+            .get("ApproximateCreationDateTime")
+            .and_then(|value| value.as_f64())
+        {
+            stream_record.insert(
+                "ApproximateCreationDateTime".to_string(),
+                Value::from(created_at / 1000.0),
+            );
+        }
+    }
+
+    serde_json::from_value(change).context("Invalid DynamoDB change record")
+}
@@ -0,0 +1,12 @@
+use serde::{Deserialize, Serialize};
+
+/// Shorter prefixes match most of the terms of the index, which suggest would have to read.
+pub const MIN_PREFIX_CHARS: usize = 2;
+
+#[derive(Deserialize, Serialize, Default)]
+pub struct SuggestRequest {
+    /// At least `MIN_PREFIX_CHARS` characters.
+    pub prefix: Option<String>,
+    pub fields: Option<Vec<String>>,
+    pub limit: Option<usize>,
+}
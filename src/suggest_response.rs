@@ -0,0 +1,31 @@
+use serde::Deserialize;
+use serde::Serialize;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Suggestion {
+    pub field: String,
+    pub term: String,
+    pub doc_freq: u32,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct SuggestResponse {
+    pub suggestions: Option<Vec<Suggestion>>,
+    pub error: Option<String>,
+}
+
+impl SuggestResponse {
+    pub fn error(error: &str) -> Self {
+        SuggestResponse {
+            error: Some(error.to_string()),
+            ..Default::default()
+        }
+    }
+
+    pub fn success(suggestions: Vec<Suggestion>) -> Self {
+        SuggestResponse {
+            suggestions: Some(suggestions),
+            error: None,
+        }
+    }
+}
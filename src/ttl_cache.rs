@@ -0,0 +1,78 @@
+use lru::LruCache;
+use serde::{Deserialize, Serialize};
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// Hit and miss counts of a cache since the reader started.
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct CacheStats {
+    pub capacity: usize,
+    pub len: usize,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+/// An LRU cache whose entries also expire `ttl` after they were put, shared by concurrent
+/// requests.
+pub struct TtlCache<V> {
+    ttl: Duration,
+    entries: Mutex<LruCache<String, (Instant, V)>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl<V: Clone> TtlCache<V> {
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        TtlCache {
+            ttl,
+            entries: Mutex::new(LruCache::new(capacity)),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<V> {
+        let mut entries = self.entries.lock().expect("cache lock poisoned");
+
+        let value = match entries.get(key) {
+            Some((put_at, value)) if put_at.elapsed() < self.ttl => Some(value.clone()),
+            Some(_) => {
+                entries.pop(key);
+                None
+            }
+            None => None,
+        };
+
+        let counter = if value.is_some() {
+            &self.hits
+        } else {
+            &self.misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+
+        value
+    }
+
+    pub fn put(&self, key: String, value: V) {
+        self.entries
+            .lock()
+            .expect("cache lock poisoned")
+            .put(key, (Instant::now(), value));
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let entries = self.entries.lock().expect("cache lock poisoned");
+
+        CacheStats {
+            capacity: entries.cap(),
+            len: entries.len(),
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+}
@@ -0,0 +1,61 @@
+use crate::dynamodb_streams::DynamoDbStreams;
+use crate::stream_records;
+use anyhow::{Context, Result};
+use aws_lambda_events::dynamodb::Event;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+#[derive(Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum WriterRequest {
+    Command(WriterCommand),
+    Stream(Event),
+    /// Change records replayed through SQS, and the ids of the messages that failed to decode,
+    /// to report back as batch item failures.
+    #[serde(skip)]
+    Replay {
+        event: Event,
+        failed_message_ids: Vec<String>,
+    },
+}
+
+impl WriterRequest {
+    /// Parses a writer invocation, decoding Kinesis and SQS events into the stream event of the
+    /// change records they carry.
+    pub async fn parse(
+        event: Value,
+        dynamodb_streams: Option<&DynamoDbStreams>,
+    ) -> Result<WriterRequest> {
+        match event["Records"][0]["eventSource"].as_str() {
+            Some("aws:kinesis") => {
+                let records = stream_records::from_kinesis(
+                    serde_json::from_value(event).context("Invalid Kinesis event")?,
+                )?;
+                Ok(WriterRequest::Stream(Event { records }))
+            }
+            Some("aws:sqs") => {
+                let (records, failed_message_ids) = stream_records::from_sqs(
+                    serde_json::from_value(event).context("Invalid SQS event")?,
+                    dynamodb_streams,
+                )
+                .await;
+                Ok(WriterRequest::Replay {
+                    event: Event { records },
+                    failed_message_ids,
+                })
+            }
+            _ => Ok(serde_json::from_value(event)?),
+        }
+    }
+}
+
+/// Maintenance invocations of the writer, for example from a scheduled rule.
+#[derive(Deserialize, Serialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum WriterCommand {
+    /// Deletes every document whose ttl has passed.
+    PurgeExpired,
+    /// Merges each partition down to `target_segments` segments (1 by default) and rewrites
+    /// segments with deleted documents, for example during quiet hours.
+    Optimize { target_segments: Option<usize> },
+}
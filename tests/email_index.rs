@@ -0,0 +1,1879 @@
+use aws_lambda_events::dynamodb::{
+    attributes::AttributeValue, Event, EventRecord, StreamRecord, StreamViewType, UserIdentity,
+};
+use aws_sdk_dynamodb::{Client, Credentials, Endpoint, Region};
+use dynamodb_email_indexer::audit_report::AuditReport;
+use dynamodb_email_indexer::commit_payload::CommitPayload;
+use dynamodb_email_indexer::email_index_reader::EmailIndexReader;
+use dynamodb_email_indexer::email_index_schema::EmailIndexSchema;
+use dynamodb_email_indexer::email_index_writer::EmailIndexWriter;
+use dynamodb_email_indexer::http_request::HttpRequest;
+use dynamodb_email_indexer::index_partitioning::{PartitionInterval, Partitioning};
+use dynamodb_email_indexer::index_snapshot;
+use dynamodb_email_indexer::index_storage::{IndexStorage, RamStorage};
+use dynamodb_email_indexer::reader_request::ReaderRequest;
+use dynamodb_email_indexer::reload_policy::ReloadPolicy;
+use dynamodb_email_indexer::writer_request::{WriterCommand, WriterRequest};
+use flate2::{write::GzEncoder, Compression};
+use serde_json::{json, Value};
+use std::{
+    collections::HashMap,
+    io::Write,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tantivy::{
+    chrono::{self, Utc},
+    doc,
+    schema::{Schema, INDEXED, STORED, STRING},
+    Index, IndexSettings,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+};
+
+const DAY: i64 = 24 * 60 * 60;
+
+/// A writer and reader sharing one in-memory index.
+struct TestIndex {
+    ram_storage: Arc<RamStorage>,
+    partitioning: Partitioning,
+    writer: EmailIndexWriter,
+    sequence_number: u64,
+}
+
+impl TestIndex {
+    fn new(partitioning: Partitioning) -> TestIndex {
+        let ram_storage = Arc::new(RamStorage::default());
+        let writer = EmailIndexWriter::new(schema(&ram_storage, partitioning)).unwrap();
+
+        TestIndex {
+            ram_storage,
+            partitioning,
+            writer,
+            sequence_number: 0,
+        }
+    }
+
+    async fn write(&mut self, records: Vec<EventRecord>) -> Value {
+        let records = records
+            .into_iter()
+            .map(|mut record| {
+                self.sequence_number += 1;
+                record.change.sequence_number = Some(self.sequence_number.to_string());
+                record
+            })
+            .collect();
+
+        self.writer
+            .handle(WriterRequest::Stream(Event { records }))
+            .await
+            .unwrap()
+    }
+
+    async fn purge_expired(&mut self) -> Value {
+        self.writer
+            .handle(WriterRequest::Command(WriterCommand::PurgeExpired))
+            .await
+            .unwrap()
+    }
+
+    async fn optimize(&mut self, target_segments: usize) -> Value {
+        self.writer
+            .handle(WriterRequest::Command(WriterCommand::Optimize {
+                target_segments: Some(target_segments),
+            }))
+            .await
+            .unwrap()
+    }
+
+    async fn read(&self, request: Value) -> Value {
+        let reader =
+            Arc::new(EmailIndexReader::new(schema(&self.ram_storage, self.partitioning)).unwrap());
+        let request = ReaderRequest::parse(&request.to_string()).unwrap();
+        let response = reader.handle(request).await.unwrap();
+
+        serde_json::to_value(response).unwrap()
+    }
+
+    async fn search_ids(&self, query: &str) -> Vec<String> {
+        let response = self
+            .read(json!({ "query": query, "mode": "ids", "limit": 100 }))
+            .await;
+        assert_eq!(response["error"], Value::Null);
+
+        let mut ids: Vec<String> = response["hits"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|hit| hit["id"].as_str().unwrap().to_string())
+            .collect();
+        ids.sort();
+        ids
+    }
+}
+
+fn schema(ram_storage: &Arc<RamStorage>, partitioning: Partitioning) -> EmailIndexSchema {
+    EmailIndexSchema::new()
+        .with_partitioning(partitioning)
+        .with_storage(IndexStorage::Ram(ram_storage.clone()))
+        .with_current_generation()
+        .unwrap()
+}
+
+fn email(id: &str, timestamp: i64, subject: &str, to: &str) -> HashMap<String, AttributeValue> {
+    HashMap::from([
+        ("id".to_string(), AttributeValue::String(id.to_string())),
+        (
+            "timestamp".to_string(),
+            AttributeValue::String(timestamp.to_string()),
+        ),
+        (
+            "subject".to_string(),
+            AttributeValue::String(subject.to_string()),
+        ),
+        (
+            "body".to_string(),
+            AttributeValue::String(format!("body of {subject}")),
+        ),
+        (
+            "from".to_string(),
+            AttributeValue::String("Sender <sender@example.com>".to_string()),
+        ),
+        (
+            "to".to_string(),
+            AttributeValue::StringSet(vec![to.to_string()]),
+        ),
+    ])
+}
+
+fn with_ttl(
+    mut image: HashMap<String, AttributeValue>,
+    ttl: i64,
+) -> HashMap<String, AttributeValue> {
+    image.insert("ttl".to_string(), AttributeValue::Number(ttl as f64));
+    image
+}
+
+fn record(
+    event_name: &str,
+    new_image: HashMap<String, AttributeValue>,
+    old_image: HashMap<String, AttributeValue>,
+) -> EventRecord {
+    EventRecord {
+        aws_region: "us-east-1".to_string(),
+        change: StreamRecord {
+            approximate_creation_date_time: Utc::now(),
+            keys: HashMap::new(),
+            new_image,
+            old_image,
+            sequence_number: None,
+            size_bytes: 0,
+            stream_view_type: Some(StreamViewType::NewAndOldImages),
+        },
+        event_id: "test".to_string(),
+        event_name: event_name.to_string(),
+        event_source: "aws:dynamodb".to_string(),
+        event_version: "1.1".to_string(),
+        event_source_arn: "test".to_string(),
+        user_identity: None,
+    }
+}
+
+fn insert(image: HashMap<String, AttributeValue>) -> EventRecord {
+    record("INSERT", image, HashMap::new())
+}
+
+fn now() -> i64 {
+    Utc::now().timestamp()
+}
+
+#[tokio::test]
+async fn search_finds_inserted_emails() {
+    let mut index = TestIndex::new(Partitioning::default());
+    let result = index
+        .write(vec![
+            insert(email("a", now(), "quarterly report", "alice@example.com")),
+            insert(email("b", now(), "lunch plans", "bob@example.org")),
+            insert(email("c", now(), "report draft", "carol@example.com")),
+        ])
+        .await;
+    assert_eq!(result["created"], 3);
+    assert_eq!(result["sequence_number"], "3");
+
+    assert_eq!(index.search_ids("subject:report").await, vec!["a", "c"]);
+    assert_eq!(index.search_ids("subject:lunch").await, vec!["b"]);
+
+    let response = index
+        .read(json!({ "query": "subject:report", "mode": "count" }))
+        .await;
+    assert_eq!(response["query_num_docs"], 2);
+    assert_eq!(response["index_num_docs"], 3);
+    assert_eq!(response["version"], "3");
+}
+
+#[tokio::test]
+async fn modify_and_remove_update_the_index() {
+    let mut index = TestIndex::new(Partitioning::default());
+    let timestamp = now();
+    index
+        .write(vec![
+            insert(email("a", timestamp, "old subject", "alice@example.com")),
+            insert(email("b", timestamp, "old subject", "bob@example.com")),
+        ])
+        .await;
+
+    let result = index
+        .write(vec![
+            record(
+                "MODIFY",
+                email("a", timestamp, "new subject", "alice@example.com"),
+                email("a", timestamp, "old subject", "alice@example.com"),
+            ),
+            record(
+                "REMOVE",
+                HashMap::new(),
+                HashMap::from([("id".to_string(), AttributeValue::String("b".to_string()))]),
+            ),
+        ])
+        .await;
+    assert_eq!(result["updated"], 1);
+    assert_eq!(result["deleted"], 1);
+
+    assert_eq!(index.search_ids("subject:new").await, vec!["a"]);
+    assert!(index.search_ids("subject:old").await.is_empty());
+}
+
+#[tokio::test]
+async fn out_of_order_changes_never_overwrite_newer_ones() {
+    let mut index = TestIndex::new(Partitioning::default());
+    let timestamp = now();
+    let mut older = insert(email("a", timestamp, "old subject", "alice@example.com"));
+    older.change.approximate_creation_date_time = Utc::now() - chrono::Duration::seconds(2);
+    let mut newer = record(
+        "MODIFY",
+        email("a", timestamp, "new subject", "alice@example.com"),
+        email("a", timestamp, "old subject", "alice@example.com"),
+    );
+    newer.change.approximate_creation_date_time = Utc::now() - chrono::Duration::seconds(1);
+
+    // NOTE: Kinesis can deliver changes of a batch in the wrong order, or deliver them again later
+    let result = index.write(vec![newer.clone(), older.clone()]).await;
+    assert_eq!(result["updated"], 1);
+    assert_eq!(result["stale"], 1);
+    let result = index.write(vec![older]).await;
+    assert_eq!(result["stale"], 1);
+    assert_eq!(index.search_ids("subject:new").await, vec!["a"]);
+    assert!(index.search_ids("subject:old").await.is_empty());
+
+    // NOTE: Removals leave a tombstone, so replaying an older change doesn't bring the email back
+    index
+        .write(vec![record(
+            "REMOVE",
+            HashMap::new(),
+            email("a", timestamp, "new subject", "alice@example.com"),
+        )])
+        .await;
+    let result = index.write(vec![newer]).await;
+    assert_eq!(result["stale"], 1);
+    assert!(index.search_ids("subject:new").await.is_empty());
+    let response = index
+        .read(json!({ "query": "*", "mode": "count", "include_expired": true }))
+        .await;
+    assert_eq!(response["query_num_docs"], 0);
+    assert_eq!(response["index_num_docs"], 0);
+
+    // NOTE: Tombstones outlive purges for as long as streams keep older changes
+    assert_eq!(index.purge_expired().await["purged"], 0);
+}
+
+#[tokio::test]
+async fn ttl_removals_are_counted_as_expired() {
+    let mut index = TestIndex::new(Partitioning::default());
+    index
+        .write(vec![insert(email(
+            "a",
+            now(),
+            "hello",
+            "alice@example.com",
+        ))])
+        .await;
+
+    let mut removal = record(
+        "REMOVE",
+        HashMap::new(),
+        email("a", now(), "hello", "alice@example.com"),
+    );
+    removal.user_identity = Some(UserIdentity {
+        type_: "Service".to_string(),
+        principal_id: "dynamodb.amazonaws.com".to_string(),
+    });
+    let result = index.write(vec![removal]).await;
+
+    assert_eq!(result["expired"], 1);
+    assert_eq!(result["deleted"], 0);
+    assert!(index.search_ids("subject:hello").await.is_empty());
+}
+
+#[tokio::test]
+async fn purge_deletes_expired_emails() {
+    let mut index = TestIndex::new(Partitioning::default());
+    index
+        .write(vec![
+            insert(with_ttl(
+                email("a", now(), "hello", "alice@example.com"),
+                now() - DAY,
+            )),
+            insert(with_ttl(
+                email("b", now(), "hello", "bob@example.com"),
+                now() + DAY,
+            )),
+            insert(email("c", now(), "hello", "carol@example.com")),
+        ])
+        .await;
+
+    let response = index
+        .read(json!({ "query": "subject:hello", "mode": "ids", "include_expired": true }))
+        .await;
+    assert_eq!(response["hits"].as_array().unwrap().len(), 3);
+    assert_eq!(index.search_ids("subject:hello").await, vec!["b", "c"]);
+
+    let result = index.purge_expired().await;
+    assert_eq!(result["purged"], 1);
+
+    let response = index
+        .read(json!({ "query": "subject:hello", "mode": "ids", "include_expired": true }))
+        .await;
+    assert_eq!(response["hits"].as_array().unwrap().len(), 2);
+}
+
+#[tokio::test]
+async fn documents_indexed_without_a_ttl_never_expire() {
+    let mut index = TestIndex::new(Partitioning::default());
+    index
+        .write(vec![insert(with_ttl(
+            email("a", now(), "hello", "alice@example.com"),
+            now() - DAY,
+        ))])
+        .await;
+
+    // NOTE: Documents indexed before the ttl field existed have no ttl value at all
+    let email_index_schema = schema(&index.ram_storage, index.partitioning);
+    let fields = &email_index_schema.fields;
+    let mut index_writer = email_index_schema
+        .ensure_index()
+        .unwrap()
+        .writer(15_000_000)
+        .unwrap();
+    index_writer
+        .add_document(doc!(
+            fields.id => "b",
+            fields.timestamp => now(),
+            fields.subject => "hello",
+        ))
+        .unwrap();
+    index_writer.commit().unwrap();
+
+    assert_eq!(index.search_ids("subject:hello").await, vec!["b"]);
+}
+
+#[tokio::test]
+async fn out_of_range_timestamps_are_errors() {
+    let mut index = TestIndex::new(Partitioning::default());
+    index
+        .write(vec![insert(email(
+            "a",
+            now(),
+            "hello",
+            "alice@example.com",
+        ))])
+        .await;
+
+    let email_index_schema = schema(&index.ram_storage, index.partitioning);
+    let fields = &email_index_schema.fields;
+    let mut index_writer = email_index_schema
+        .ensure_index()
+        .unwrap()
+        .writer(15_000_000)
+        .unwrap();
+    index_writer
+        .add_document(doc!(
+            fields.id => "b",
+            fields.timestamp => i64::MAX,
+            fields.subject => "hello",
+        ))
+        .unwrap();
+    index_writer.commit().unwrap();
+
+    let reader = Arc::new(EmailIndexReader::new(email_index_schema).unwrap());
+    let request = json!({
+        "query": "subject:hello",
+        "mode": "count",
+        "aggregations": { "date_histogram": { "interval": "month" } },
+    });
+    let error = reader
+        .handle(ReaderRequest::parse(&request.to_string()).unwrap())
+        .await
+        .err()
+        .unwrap();
+    assert!(error.to_string().contains("out of range"));
+
+    let writer = EmailIndexWriter::new(schema(
+        &Arc::new(RamStorage::default()),
+        Partitioning {
+            interval: Some(PartitionInterval::Month),
+            ..Partitioning::default()
+        },
+    ))
+    .unwrap();
+    let records = vec![insert(email("c", i64::MAX, "hello", "alice@example.com"))];
+    assert!(writer
+        .handle(WriterRequest::Stream(Event { records }))
+        .await
+        .is_err());
+}
+
+async fn aggregate(index: &TestIndex, aggregations: Value) -> Value {
+    let response = index
+        .read(json!({ "query": "subject:hello", "mode": "count", "aggregations": aggregations }))
+        .await;
+    assert_eq!(response["error"], Value::Null);
+    response["aggregations"].clone()
+}
+
+fn buckets(aggregations: &Value) -> Vec<(i64, u64)> {
+    aggregations["date_histogram"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|bucket| {
+            (
+                bucket["key"].as_i64().unwrap(),
+                bucket["doc_count"].as_u64().unwrap(),
+            )
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn aggregations_bucket_on_interval_boundaries_across_partitions() {
+    // NOTE: 2024-01-01 was a Monday
+    let monday = 1_704_067_200;
+    let hour = 60 * 60;
+    let mut index = TestIndex::new(Partitioning {
+        interval: Some(PartitionInterval::Month),
+        ..Partitioning::default()
+    });
+    index
+        .write(vec![
+            insert(email("a", monday, "hello", "alice@example.com")),
+            insert(email(
+                "b",
+                monday + hour - 1,
+                "hello",
+                "Alice <alice@example.com>",
+            )),
+            insert(email("c", monday + hour, "hello", "bob@example.com")),
+            insert(email("d", monday + 7 * DAY - 1, "hello", "alice@other.org")),
+            insert(email("e", monday + 7 * DAY, "hello", "carol@example.com")),
+            insert(email("f", monday + 31 * DAY, "hello", "carol@example.com")),
+        ])
+        .await;
+
+    let aggregations = aggregate(&index, json!({ "date_histogram": { "interval": "hour" } })).await;
+    assert_eq!(
+        buckets(&aggregations),
+        vec![
+            (monday, 2),
+            (monday + hour, 1),
+            (monday + 7 * DAY - hour, 1),
+            (monday + 7 * DAY, 1),
+            (monday + 31 * DAY, 1),
+        ]
+    );
+
+    let aggregations = aggregate(&index, json!({ "date_histogram": { "interval": "day" } })).await;
+    assert_eq!(
+        buckets(&aggregations),
+        vec![
+            (monday, 3),
+            (monday + 6 * DAY, 1),
+            (monday + 7 * DAY, 1),
+            (monday + 31 * DAY, 1),
+        ]
+    );
+
+    // NOTE: Weeks start on Monday, so the Sunday closes the first week and February 1st falls in the week of the
+    // 29th of January
+    let aggregations = aggregate(&index, json!({ "date_histogram": { "interval": "week" } })).await;
+    assert_eq!(
+        buckets(&aggregations),
+        vec![(monday, 4), (monday + 7 * DAY, 1), (monday + 28 * DAY, 1)]
+    );
+
+    let aggregations =
+        aggregate(&index, json!({ "date_histogram": { "interval": "month" } })).await;
+    assert_eq!(
+        buckets(&aggregations),
+        vec![(monday, 5), (monday + 31 * DAY, 1)]
+    );
+
+    // NOTE: Recipients are counted by address, whatever the display name, and ties are ordered by key
+    let aggregations = aggregate(
+        &index,
+        json!({ "terms": { "field": "recipient", "size": 3 } }),
+    )
+    .await;
+    assert_eq!(
+        aggregations["terms"],
+        json!([
+            { "key": "alice@example.com", "doc_count": 2 },
+            { "key": "carol@example.com", "doc_count": 2 },
+            { "key": "alice@other.org", "doc_count": 1 },
+        ])
+    );
+
+    let aggregations = aggregate(&index, json!({ "terms": { "field": "domain" } })).await;
+    assert_eq!(
+        aggregations["terms"],
+        json!([
+            { "key": "example.com", "doc_count": 5 },
+            { "key": "other.org", "doc_count": 1 },
+        ])
+    );
+}
+
+#[tokio::test]
+async fn indexes_with_an_older_schema_must_be_rebuilt() {
+    let ram_storage = Arc::new(RamStorage::default());
+    let mut builder = Schema::builder();
+    builder.add_text_field("id", STRING | STORED);
+    builder.add_i64_field("timestamp", INDEXED);
+    Index::create(
+        ram_storage.directory(Path::new("index")),
+        builder.build(),
+        IndexSettings::default(),
+    )
+    .unwrap();
+
+    let error = EmailIndexWriter::new(schema(&ram_storage, Partitioning::default()))
+        .err()
+        .unwrap();
+    assert!(format!("{error:#}").contains("--reindex"));
+}
+
+#[tokio::test]
+async fn suggest_and_contacts_come_from_indexed_emails() {
+    let mut index = TestIndex::new(Partitioning::default());
+    index
+        .write(vec![
+            insert(email("a", now(), "invoice", "Alice <alice@example.com>")),
+            insert(email("b", now(), "invitation", "alan@example.com")),
+        ])
+        .await;
+
+    let response = index
+        .read(json!({ "operation": "suggest", "prefix": "inv" }))
+        .await;
+    let terms: Vec<&str> = response["suggestions"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|suggestion| suggestion["term"].as_str().unwrap())
+        .collect();
+    assert!(terms.contains(&"invoice"));
+    assert!(terms.contains(&"invitation"));
+
+    let response = index
+        .read(json!({ "operation": "contacts", "prefix": "al" }))
+        .await;
+    // NOTE: The total counts every contact, including the sender
+    assert_eq!(response["total_contacts"], 3);
+    assert_eq!(response["contacts"].as_array().unwrap().len(), 2);
+}
+
+#[tokio::test]
+async fn recipient_suggestions_are_whole_addresses() {
+    let mut index = TestIndex::new(Partitioning::default());
+    index
+        .write(vec![
+            insert(email("a", now(), "invoice", "Alice <alice@example.com>")),
+            insert(email("b", now(), "invitation", "alice@example.com")),
+            insert(email("c", now(), "report", "alan@example.org")),
+        ])
+        .await;
+
+    let response = index
+        .read(json!({ "operation": "suggest", "prefix": "Al", "fields": ["to"] }))
+        .await;
+    let suggestions: Vec<(&str, u64)> = response["suggestions"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|suggestion| {
+            (
+                suggestion["term"].as_str().unwrap(),
+                suggestion["doc_freq"].as_u64().unwrap(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        suggestions,
+        vec![("alice@example.com", 2), ("alan@example.org", 1)]
+    );
+
+    let response = index
+        .read(json!({ "operation": "suggest", "prefix": "exa", "fields": ["to"] }))
+        .await;
+    assert_eq!(response["suggestions"], json!([]));
+
+    let response = index
+        .read(json!({ "operation": "suggest", "prefix": "a", "fields": ["body"] }))
+        .await;
+    assert_eq!(response["error"], "body does not support suggestions");
+
+    let response = index
+        .read(json!({ "operation": "suggest", "prefix": "a" }))
+        .await;
+    assert_eq!(response["error"], "prefix must be at least 2 characters");
+
+    // NOTE: The old document stays in its segment until a merge, but is no longer counted
+    index
+        .write(vec![record(
+            "MODIFY",
+            email("c", now(), "report", "bob@example.org"),
+            email("c", now(), "report", "alan@example.org"),
+        )])
+        .await;
+    let response = index
+        .read(json!({ "operation": "suggest", "prefix": "al", "fields": ["to"] }))
+        .await;
+    assert_eq!(
+        response["suggestions"],
+        json!([{ "field": "to", "term": "alice@example.com", "doc_freq": 2 }])
+    );
+}
+
+#[tokio::test]
+async fn searches_wait_for_an_email_version() {
+    let mut index = TestIndex::new(Partitioning {
+        shards: Some(2),
+        ..Partitioning::default()
+    });
+    let mut image = email("a", now(), "hello", "alice@example.com");
+    image.insert("version".to_string(), AttributeValue::Number(2.0));
+    index.write(vec![insert(image)]).await;
+
+    let response = index
+        .read(json!({
+            "query": "subject:hello",
+            "mode": "ids",
+            "min_version": { "id": "a", "version": 2 },
+        }))
+        .await;
+    assert_eq!(response["hits"].as_array().unwrap().len(), 1);
+
+    let response = index
+        .read(json!({ "query": "subject:hello", "mode": "ids", "min_version": { "id": "a" } }))
+        .await;
+    assert_eq!(response["hits"].as_array().unwrap().len(), 1);
+
+    // NOTE: Writes the index never catches up to are an error rather than stale results
+    let response = index
+        .read(json!({
+            "query": "subject:hello",
+            "mode": "ids",
+            "min_version": { "id": "a", "version": 3 },
+        }))
+        .await;
+    assert!(response["error"].as_str().unwrap().contains("timed out"));
+    assert_eq!(response["hits"], Value::Null);
+}
+
+async fn contact(index: &TestIndex, prefix: &str) -> Value {
+    let response = index
+        .read(json!({ "operation": "contacts", "prefix": prefix }))
+        .await;
+    response["contacts"][0].clone()
+}
+
+#[tokio::test]
+async fn contacts_follow_updates_and_removals() {
+    let mut index = TestIndex::new(Partitioning::default());
+    let a = email("a", now() - DAY, "hello", "Alice <alice@example.com>");
+    let b = email("b", now(), "hello", "Ally <alice@example.com>");
+    index
+        .write(vec![insert(a.clone()), insert(b.clone())])
+        .await;
+
+    let alice = contact(&index, "alice").await;
+    assert_eq!(alice["message_count"], 2);
+    assert_eq!(alice["name"], "Ally");
+    assert_eq!(contact(&index, "sender").await["message_count"], 2);
+
+    let c = email("b", now(), "hello", "bob@example.com");
+    index
+        .write(vec![
+            record("MODIFY", c, b),
+            record("REMOVE", HashMap::new(), a),
+        ])
+        .await;
+
+    assert_eq!(contact(&index, "alice").await, Value::Null);
+    assert_eq!(contact(&index, "bob").await["message_count"], 1);
+    assert_eq!(contact(&index, "sender").await["message_count"], 1);
+}
+
+#[tokio::test]
+async fn contacts_are_committed_by_the_writer_and_follow_purges() {
+    let mut index = TestIndex::new(Partitioning::default());
+    index
+        .write(vec![insert(with_ttl(
+            email("a", now(), "hello", "alice@example.com"),
+            now() - DAY,
+        ))])
+        .await;
+    index
+        .write(vec![insert(email("b", now(), "hello", "bob@example.com"))])
+        .await;
+
+    let email_index_schema = schema(&index.ram_storage, index.partitioning);
+    let key = &email_index_schema.partition_keys().unwrap()[0];
+    let commit_payload =
+        CommitPayload::load(&email_index_schema.open_partition(key).unwrap()).unwrap();
+    assert_eq!(commit_payload.contacts.unwrap().len(), 3);
+
+    // NOTE: Merges keep the payload of the commit they merge
+    index.optimize(1).await;
+    assert_eq!(contact(&index, "alice").await["message_count"], 1);
+    assert_eq!(contact(&index, "sender").await["message_count"], 2);
+
+    index.purge_expired().await;
+    assert_eq!(contact(&index, "alice").await, Value::Null);
+    assert_eq!(contact(&index, "sender").await["message_count"], 1);
+}
+
+#[tokio::test]
+async fn retried_batches_are_not_counted_twice() {
+    let mut index = TestIndex::new(Partitioning::default());
+    let records = vec![insert(email("a", now(), "hello", "alice@example.com"))];
+    index.write(records.clone()).await;
+    index.write(records).await;
+
+    assert_eq!(index.search_ids("subject:hello").await, vec!["a"]);
+    assert_eq!(contact(&index, "alice").await["message_count"], 1);
+}
+
+#[tokio::test]
+async fn status_and_stats_describe_the_index() {
+    let mut index = TestIndex::new(Partitioning::default());
+    index
+        .write(vec![
+            insert(email("a", now(), "hello", "alice@example.com")),
+            insert(email("b", now(), "hello", "bob@example.com")),
+        ])
+        .await;
+
+    let response = index.read(json!({ "operation": "status" })).await;
+    assert_eq!(response["index_num_docs"], 2);
+    assert_eq!(response["version"], "2");
+
+    let response = index.read(json!({ "operation": "stats" })).await;
+    assert_eq!(response["index_num_docs"], 2);
+    assert_eq!(
+        response["schema_fingerprint"],
+        response["index_schema_fingerprint"]
+    );
+    assert!(response["on_disk_bytes"].as_u64().unwrap() > 0);
+
+    // NOTE: Lag is measured up to the commit, so it reflects how late the record was indexed rather than how
+    // long the table has been idle
+    let mut late = insert(email("c", now(), "hello", "carol@example.com"));
+    late.change.approximate_creation_date_time =
+        Utc::now() - tantivy::chrono::Duration::minutes(10);
+    let mut index = TestIndex::new(Partitioning::default());
+    index.write(vec![late]).await;
+    let response = index.read(json!({ "operation": "status" })).await;
+    let lag_seconds = response["lag_seconds"].as_i64().unwrap();
+    assert!((600..660).contains(&lag_seconds));
+    assert!(response["commit_age_seconds"].as_i64().unwrap() < 60);
+}
+
+#[tokio::test]
+async fn audits_compare_the_table_with_the_emails_in_the_index() {
+    let mut index = TestIndex::new(Partitioning::default());
+    let timestamp = now();
+    index
+        .write(vec![
+            insert(email("a", timestamp, "hello", "alice@example.com")),
+            insert(email("b", timestamp, "hello", "bob@example.com")),
+            insert(email("c", timestamp, "hello", "carol@example.com")),
+        ])
+        .await;
+    index
+        .write(vec![record(
+            "REMOVE",
+            HashMap::new(),
+            email("c", timestamp, "hello", "carol@example.com"),
+        )])
+        .await;
+
+    // NOTE: The tombstone of c is not an email the table is missing
+    let index_timestamps =
+        AuditReport::index_timestamps(&schema(&index.ram_storage, index.partitioning)).unwrap();
+    assert_eq!(
+        index_timestamps,
+        HashMap::from([("a".to_string(), timestamp), ("b".to_string(), timestamp)])
+    );
+
+    let table_timestamps = HashMap::from([
+        ("a".to_string(), Some(timestamp + 1)),
+        ("d".to_string(), Some(timestamp)),
+    ]);
+    let report = AuditReport::new(&table_timestamps, &index_timestamps);
+    assert!(!report.is_clean());
+    assert_eq!(report.table_num_items, 2);
+    assert_eq!(report.index_num_docs, 2);
+    assert_eq!(report.missing_from_index, ["d".to_string()].into());
+    assert_eq!(report.missing_from_table, ["b".to_string()].into());
+    assert_eq!(report.timestamp_mismatch, ["a".to_string()].into());
+    assert_eq!(report.stale_ids(), vec!["a", "d"]);
+
+    let table_timestamps = HashMap::from([
+        ("a".to_string(), Some(timestamp)),
+        ("b".to_string(), Some(timestamp)),
+    ]);
+    assert!(AuditReport::new(&table_timestamps, &index_timestamps).is_clean());
+}
+
+#[tokio::test]
+async fn replayed_records_leave_the_index_position_alone() {
+    let mut index = TestIndex::new(Partitioning::default());
+    let mut live = insert(email("a", now(), "hello", "alice@example.com"));
+    live.change.approximate_creation_date_time = Utc::now() - chrono::Duration::hours(1);
+    let result = index.write(vec![live]).await;
+    let indexed_through = result["indexed_through"].as_i64().unwrap();
+
+    // NOTE: Audit repairs have no sequence number, and are read from the table long after the stream position
+    let repair = insert(email("b", now(), "hello", "bob@example.com"));
+    let result = index
+        .writer
+        .handle(WriterRequest::Stream(Event {
+            records: vec![repair],
+        }))
+        .await
+        .unwrap();
+    assert_eq!(result["created"], 1);
+    assert_eq!(result["indexed_through"], indexed_through);
+    assert_eq!(index.search_ids("subject:hello").await, vec!["a", "b"]);
+}
+
+#[tokio::test]
+async fn resetting_the_checkpoint_forgets_the_stream_position() {
+    let mut index = TestIndex::new(Partitioning::default());
+    index
+        .write(vec![insert(email(
+            "a",
+            now(),
+            "hello",
+            "alice@example.com",
+        ))])
+        .await;
+    let response = index.read(json!({ "operation": "status" })).await;
+    assert!(response["indexed_through"].is_i64());
+
+    // NOTE: Rolling back segments loses documents, so the index must not claim to be through any write
+    schema(&index.ram_storage, index.partitioning)
+        .reset_checkpoint()
+        .unwrap();
+    let response = index.read(json!({ "operation": "status" })).await;
+    assert_eq!(response["indexed_through"], Value::Null);
+    assert_eq!(response["version"], Value::Null);
+}
+
+#[tokio::test]
+async fn full_search_requires_a_table() {
+    let index = TestIndex::new(Partitioning::default());
+    let reader =
+        Arc::new(EmailIndexReader::new(schema(&index.ram_storage, index.partitioning)).unwrap());
+    let request = ReaderRequest::parse(r#"{ "query": "subject:hello", "mode": "full" }"#).unwrap();
+
+    assert!(reader.handle(request).await.is_err());
+}
+
+/// A stand-in for the email table that answers `BatchGetItem` with the attributes of each item
+/// its projection names, recording the requests it gets.
+async fn serve_table(items: Vec<Value>) -> (Client, Arc<Mutex<Vec<Value>>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let endpoint = format!("http://{}", listener.local_addr().unwrap());
+    let requests: Arc<Mutex<Vec<Value>>> = Arc::new(Mutex::new(vec![]));
+
+    let received = requests.clone();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut data: Vec<u8> = vec![];
+            let mut buffer = [0_u8; 4096];
+            let body = loop {
+                let read = stream.read(&mut buffer).await.unwrap();
+                data.extend_from_slice(&buffer[..read]);
+
+                let text = String::from_utf8_lossy(&data).to_string();
+                if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                    let content_length: usize = head
+                        .lines()
+                        .find_map(|line| {
+                            let (name, value) = line.split_once(':')?;
+                            name.eq_ignore_ascii_case("content-length")
+                                .then(|| value.trim().parse().unwrap())
+                        })
+                        .unwrap_or(0);
+                    if body.len() >= content_length {
+                        break body.to_string();
+                    }
+                }
+            };
+
+            let request: Value = serde_json::from_str(&body).unwrap();
+            let keys_and_attributes = &request["RequestItems"]["emails"];
+            let names: Option<Vec<&str>> = keys_and_attributes["ExpressionAttributeNames"]
+                .as_object()
+                .map(|names| names.values().map(|name| name.as_str().unwrap()).collect());
+            let responses: Vec<Value> = items
+                .iter()
+                .filter(|item| {
+                    keys_and_attributes["Keys"]
+                        .as_array()
+                        .unwrap()
+                        .iter()
+                        .any(|key| key["id"] == item["id"])
+                })
+                .map(|item| {
+                    let mut item = item.as_object().unwrap().clone();
+                    if let Some(names) = &names {
+                        item.retain(|name, _| names.contains(&name.as_str()));
+                    }
+                    Value::Object(item)
+                })
+                .collect();
+            received.lock().unwrap().push(request.clone());
+
+            let body =
+                json!({ "Responses": { "emails": responses }, "UnprocessedKeys": {} }).to_string();
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: application/x-amz-json-1.0\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            );
+            stream.write_all(response.as_bytes()).await.unwrap();
+        }
+    });
+
+    let config = aws_sdk_dynamodb::Config::builder()
+        .region(Region::new("us-east-1"))
+        .credentials_provider(Credentials::new("test", "test", None, None, "test"))
+        .endpoint_resolver(Endpoint::immutable(endpoint.parse().unwrap()))
+        .build();
+    (Client::from_conf(config), requests)
+}
+
+#[tokio::test]
+async fn full_searches_return_the_requested_fields() {
+    let mut index = TestIndex::new(Partitioning::default());
+    let timestamp = now();
+    index
+        .write(vec![insert(email(
+            "a",
+            timestamp,
+            "hello",
+            "alice@example.com",
+        ))])
+        .await;
+    let (ddb, requests) = serve_table(vec![json!({
+        "id": { "S": "a" },
+        "timestamp": { "S": timestamp.to_string() },
+        "subject": { "S": "hello" },
+        "body": { "S": "body of hello" },
+        "to": { "SS": ["alice@example.com"] },
+        "ttl": { "N": "4102444800" },
+    })])
+    .await;
+    let reader = Arc::new(
+        EmailIndexReader::new(schema(&index.ram_storage, index.partitioning))
+            .unwrap()
+            .with_table(ddb, "emails"),
+    );
+    let search = |fields: Value| {
+        let reader = reader.clone();
+        async move {
+            let request = json!({ "query": "subject:hello", "mode": "full", "fields": fields });
+            let request = ReaderRequest::parse(&request.to_string()).unwrap();
+            serde_json::to_value(reader.handle(request).await.unwrap()).unwrap()
+        }
+    };
+
+    // NOTE: timestamp, to and ttl are DynamoDB reserved words, which only work through placeholders
+    let response = search(json!(["subject", "timestamp", "to", "ttl"])).await;
+    assert_eq!(response["error"], Value::Null);
+    assert_eq!(
+        response["emails"],
+        json!([{
+            "id": "a",
+            "timestamp": timestamp,
+            "subject": "hello",
+            "to": ["alice@example.com"],
+            "ttl": 4102444800_i64,
+        }])
+    );
+    let request = requests.lock().unwrap()[0]["RequestItems"]["emails"].clone();
+    assert_eq!(
+        request["ProjectionExpression"],
+        "#id, #subject, #timestamp, #to, #ttl"
+    );
+    assert_eq!(request["ExpressionAttributeNames"]["#ttl"], "ttl");
+
+    let response = search(json!(["body"])).await;
+    assert_eq!(
+        response["emails"],
+        json!([{ "id": "a", "body": "body of hello" }])
+    );
+
+    // NOTE: Only attributes of an email can be requested, so nothing else reaches the projection expression
+    for field in ["from", "subject, body", "#id", ""] {
+        let response = search(json!(["subject", field])).await;
+        assert_eq!(
+            response["error"],
+            format!("{field} is not a valid field"),
+            "{field}"
+        );
+        assert_eq!(response["emails"], Value::Null);
+    }
+    assert_eq!(requests.lock().unwrap().len(), 2);
+}
+
+#[tokio::test]
+async fn hits_are_newest_first_across_partitions_unless_sorted_by_score() {
+    let mut index = TestIndex::new(Partitioning {
+        interval: Some(PartitionInterval::Month),
+        shards: Some(2),
+        ..Partitioning::default()
+    });
+    index
+        .write(vec![
+            insert(email("a", now() - 60 * DAY, "hello", "alice@example.com")),
+            insert(email("b", now(), "hello", "bob@example.com")),
+            insert(email(
+                "c",
+                now() - 30 * DAY,
+                "hello hello",
+                "carol@example.com",
+            )),
+            insert(email("d", now() - DAY, "goodbye", "dave@example.com")),
+        ])
+        .await;
+
+    let response = index
+        .read(json!({ "query": "subject:hello", "mode": "ids" }))
+        .await;
+    let ids: Vec<&str> = response["hits"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|hit| hit["id"].as_str().unwrap())
+        .collect();
+    assert_eq!(ids, vec!["b", "c", "a"]);
+
+    // NOTE: Each partition scores with its own statistics, so only check the order follows the scores
+    let response = index
+        .read(json!({ "query": "subject:hello", "mode": "ids", "sort": "score" }))
+        .await;
+    let scores: Vec<f64> = response["hits"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|hit| hit["score"].as_f64().unwrap())
+        .collect();
+    assert_eq!(scores.len(), 3);
+    assert!(scores.windows(2).all(|pair| pair[0] >= pair[1]));
+}
+
+#[tokio::test]
+async fn partitioned_and_sharded_indexes_search_every_partition() {
+    let partitioning = Partitioning {
+        interval: Some(PartitionInterval::Month),
+        retention_days: Some(90),
+        shards: Some(4),
+    };
+    let mut index = TestIndex::new(partitioning);
+
+    let mut records = vec![];
+    for i in 0..20 {
+        records.push(insert(email(
+            &format!("recent-{i:02}"),
+            now() - (i % 3) * 31 * DAY,
+            "hello",
+            "alice@example.com",
+        )));
+    }
+    // NOTE: Emails past the retention period are not indexed at all
+    records.push(insert(email(
+        "old",
+        now() - 365 * DAY,
+        "hello",
+        "alice@example.com",
+    )));
+    let result = index.write(records).await;
+    assert_eq!(result["created"], 20);
+    assert_eq!(result["skipped"], 1);
+
+    let partition_keys = schema(&index.ram_storage, partitioning)
+        .partition_keys()
+        .unwrap();
+    assert!(partition_keys.len() > 4);
+    assert!(partition_keys.iter().all(|key| key.contains(".s")));
+
+    let response = index
+        .read(json!({ "query": "subject:hello", "mode": "count" }))
+        .await;
+    assert_eq!(response["query_num_docs"], 20);
+    assert_eq!(index.search_ids("subject:hello").await.len(), 20);
+}
+
+#[tokio::test]
+async fn timestamp_ranges_only_search_matching_partitions() {
+    let partitioning = Partitioning {
+        interval: Some(PartitionInterval::Month),
+        retention_days: None,
+        shards: Some(2),
+    };
+    let mut index = TestIndex::new(partitioning);
+    let recent = now();
+    let older = recent - 62 * DAY;
+    index
+        .write(vec![
+            insert(email("recent", recent, "hello", "alice@example.com")),
+            insert(email("older", older, "hello", "bob@example.com")),
+        ])
+        .await;
+
+    let key = partitioning.key_for("older", older).unwrap();
+    assert!(partitioning.may_contain(&key, Some(older), Some(older + 1)));
+    assert!(!partitioning.may_contain(&key, Some(recent), None));
+    assert!(!partitioning.may_contain(&key, None, Some(older - 31 * DAY)));
+
+    let query = format!("subject:hello AND timestamp:[{recent} TO {recent}]");
+    assert_eq!(index.search_ids(&query).await, vec!["recent"]);
+    let query = format!("subject:hello AND timestamp:[* TO {}]", older + DAY);
+    assert_eq!(index.search_ids(&query).await, vec!["older"]);
+    let query = format!("timestamp:[{older} TO {older}] OR timestamp:[{recent} TO *]");
+    assert_eq!(index.search_ids(&query).await, vec!["older", "recent"]);
+
+    // NOTE: Removals with only the key look the email up in the partitions of its shard
+    index
+        .write(vec![record(
+            "REMOVE",
+            HashMap::new(),
+            HashMap::from([(
+                "id".to_string(),
+                AttributeValue::String("older".to_string()),
+            )]),
+        )])
+        .await;
+    assert_eq!(index.search_ids("subject:hello").await, vec!["recent"]);
+}
+
+#[tokio::test]
+async fn concurrent_writers_share_sharded_indexes() {
+    let partitioning = Partitioning {
+        interval: None,
+        retention_days: None,
+        shards: Some(2),
+    };
+    let index = TestIndex::new(partitioning);
+
+    // NOTE: Each writer waits for the other's lock on every shard, as concurrent stream batches would
+    let writers: Vec<_> = (0..2)
+        .map(|writer| {
+            let email_index_writer =
+                EmailIndexWriter::new(schema(&index.ram_storage, partitioning)).unwrap();
+            tokio::spawn(async move {
+                for batch in 0..3 {
+                    let records = (0..10)
+                        .map(|i| {
+                            let id = format!("{writer}-{batch}-{i}");
+                            let mut record =
+                                insert(email(&id, now(), "hello", "alice@example.com"));
+                            record.change.sequence_number = Some(format!("{writer}{batch}{i:02}"));
+                            record
+                        })
+                        .collect();
+                    email_index_writer
+                        .handle(WriterRequest::Stream(Event { records }))
+                        .await
+                        .unwrap();
+                }
+            })
+        })
+        .collect();
+    for writer in writers {
+        writer.await.unwrap();
+    }
+
+    assert_eq!(index.search_ids("subject:hello").await.len(), 60);
+
+    let checkpoint = schema(&index.ram_storage, partitioning)
+        .load_checkpoint()
+        .unwrap();
+    assert_eq!(checkpoint.shards.len(), 2);
+
+    // NOTE: Sequence numbers of different stream shards can't be compared, so there is no version
+    let response = index.read(json!({ "operation": "status" })).await;
+    assert_eq!(response["version"], Value::Null);
+    assert!(response["indexed_through"].as_i64().is_some());
+}
+
+#[tokio::test]
+async fn purge_drops_expired_partitions() {
+    let partitioning = Partitioning {
+        interval: Some(PartitionInterval::Week),
+        retention_days: Some(30),
+        shards: None,
+    };
+    let mut index = TestIndex::new(Partitioning {
+        retention_days: None,
+        ..partitioning
+    });
+    index
+        .write(vec![
+            insert(email("old", now() - 60 * DAY, "hello", "alice@example.com")),
+            insert(email("new", now(), "hello", "alice@example.com")),
+        ])
+        .await;
+
+    // NOTE: Enable retention after the fact, as if the old partition had aged out
+    let writer = EmailIndexWriter::new(schema(&index.ram_storage, partitioning)).unwrap();
+    let result = writer
+        .handle(WriterRequest::Command(WriterCommand::PurgeExpired))
+        .await
+        .unwrap();
+    assert_eq!(result["dropped_partitions"].as_array().unwrap().len(), 1);
+
+    assert_eq!(index.search_ids("subject:hello").await, vec!["new"]);
+}
+
+#[tokio::test]
+async fn indexes_in_a_directory_persist() {
+    let path = std::env::temp_dir().join(format!("email-index-{}", ulid::Ulid::new()));
+
+    let writer = EmailIndexWriter::new(EmailIndexSchema::in_dir(&path)).unwrap();
+    let mut records = vec![insert(email("a", now(), "hello", "alice@example.com"))];
+    records[0].change.sequence_number = Some("1".to_string());
+    writer
+        .handle(WriterRequest::Stream(Event { records }))
+        .await
+        .unwrap();
+    drop(writer);
+
+    let reader = Arc::new(EmailIndexReader::new(EmailIndexSchema::in_dir(&path)).unwrap());
+    let request = ReaderRequest::parse(r#"{ "query": "subject:hello", "mode": "count" }"#).unwrap();
+    let response = serde_json::to_value(reader.handle(request).await.unwrap()).unwrap();
+    assert_eq!(response["query_num_docs"], 1);
+
+    std::fs::remove_dir_all(&path).unwrap();
+}
+
+#[tokio::test]
+async fn snapshots_restore_into_a_new_generation() {
+    let partitioning = Partitioning {
+        interval: Some(PartitionInterval::Month),
+        retention_days: None,
+        shards: Some(2),
+    };
+    let mut index = TestIndex::new(partitioning);
+    index
+        .write(vec![
+            insert(email("a", now(), "hello", "alice@example.com")),
+            insert(email("b", now() - 40 * DAY, "hello", "bob@example.com")),
+            insert(email("c", now(), "goodbye", "carol@example.com")),
+        ])
+        .await;
+
+    let mut archive = vec![];
+    let manifest =
+        index_snapshot::export(&schema(&index.ram_storage, partitioning), &mut archive).unwrap();
+    // NOTE: Sequence numbers are only ordered within a shard, so a sharded index has no version
+    assert_eq!(manifest.commit_payload.sequence_number, None);
+    assert_eq!(manifest.checkpoint.shards.len(), 2);
+    assert_eq!(
+        manifest
+            .partitions
+            .iter()
+            .map(|partition| partition.num_docs)
+            .sum::<u64>(),
+        3
+    );
+
+    let restored = TestIndex::new(partitioning);
+    index_snapshot::restore(&schema(&restored.ram_storage, partitioning), &archive[..]).unwrap();
+
+    assert_eq!(restored.search_ids("subject:hello").await, vec!["a", "b"]);
+    let response = restored.read(json!({ "operation": "status" })).await;
+    assert_eq!(
+        response["indexed_through"],
+        json!(manifest.commit_payload.indexed_through)
+    );
+    let response = restored.read(json!({ "operation": "contacts" })).await;
+    assert_eq!(response["total_contacts"], 4);
+
+    // NOTE: Restoring over an index in use leaves it as it was, and switches to the snapshot
+    index
+        .write(vec![insert(email("d", now(), "hello", "dave@example.com"))])
+        .await;
+    index_snapshot::restore(&schema(&index.ram_storage, partitioning), &archive[..]).unwrap();
+    assert_eq!(index.search_ids("subject:hello").await, vec!["a", "b"]);
+
+    let previous = Arc::new(
+        EmailIndexReader::new(schema(&index.ram_storage, partitioning).with_generation(None))
+            .unwrap(),
+    );
+    let request = ReaderRequest::parse(r#"{ "query": "subject:hello", "mode": "count" }"#).unwrap();
+    let response = serde_json::to_value(previous.handle(request).await.unwrap()).unwrap();
+    assert_eq!(response["query_num_docs"], 3);
+}
+
+#[tokio::test]
+async fn readers_leave_creating_the_index_to_the_writer() {
+    let ram_storage = Arc::new(RamStorage::default());
+    let reader = Arc::new(
+        EmailIndexReader::new(schema(&ram_storage, Partitioning::default()))
+            .unwrap()
+            .with_reload_policy(ReloadPolicy::OnRequest)
+            .unwrap(),
+    );
+    let response = count(&reader, "subject:hello").await;
+    assert_eq!(response["error"], Value::Null);
+    assert_eq!(response["query_num_docs"], 0);
+    assert!(ram_storage.paths().is_empty());
+
+    let mut index = TestIndex {
+        writer: EmailIndexWriter::new(schema(&ram_storage, Partitioning::default())).unwrap(),
+        ram_storage,
+        partitioning: Partitioning::default(),
+        sequence_number: 0,
+    };
+    index
+        .write(vec![insert(email(
+            "a",
+            now(),
+            "hello",
+            "alice@example.com",
+        ))])
+        .await;
+    assert_eq!(count(&reader, "subject:hello").await["query_num_docs"], 1);
+}
+
+#[tokio::test]
+async fn warm_readers_and_writers_follow_a_restore() {
+    let mut index = TestIndex::new(Partitioning::default());
+    index
+        .write(vec![insert(email(
+            "a",
+            now(),
+            "hello",
+            "alice@example.com",
+        ))])
+        .await;
+    let mut archive = vec![];
+    index_snapshot::export(
+        &schema(&index.ram_storage, index.partitioning),
+        &mut archive,
+    )
+    .unwrap();
+    index
+        .write(vec![insert(email("b", now(), "hello", "bob@example.com"))])
+        .await;
+
+    let reader = Arc::new(
+        EmailIndexReader::new(schema(&index.ram_storage, index.partitioning))
+            .unwrap()
+            .with_reload_policy(ReloadPolicy::OnRequest)
+            .unwrap(),
+    );
+    assert_eq!(count(&reader, "subject:hello").await["query_num_docs"], 2);
+
+    index_snapshot::restore(
+        &schema(&index.ram_storage, index.partitioning),
+        &archive[..],
+    )
+    .unwrap();
+    assert_eq!(count(&reader, "subject:hello").await["query_num_docs"], 1);
+
+    // NOTE: The writer was created before the restore, and still writes to the restored generation
+    index
+        .write(vec![insert(email(
+            "c",
+            now(),
+            "hello",
+            "carol@example.com",
+        ))])
+        .await;
+    assert_eq!(count(&reader, "subject:hello").await["query_num_docs"], 2);
+    assert_eq!(index.search_ids("subject:hello").await, vec!["a", "c"]);
+}
+
+#[test]
+fn snapshot_headers_are_checked_before_reading_entries() {
+    let mut archive = vec![];
+    let mut encoder = GzEncoder::new(&mut archive, Compression::default());
+    encoder.write_all(&u32::MAX.to_le_bytes()).unwrap();
+    encoder.finish().unwrap();
+
+    let error = index_snapshot::read_manifest(&archive[..]).unwrap_err();
+    assert!(format!("{error:?}").contains("byte name"));
+}
+
+#[tokio::test]
+async fn optimize_merges_segments_and_vacuums_deletes() {
+    let mut index = TestIndex::new(Partitioning::default());
+    // NOTE: Two emails per commit, as tantivy drops segments whose documents are all deleted
+    for i in 0..4 {
+        index
+            .write(vec![
+                insert(email(
+                    &format!("a-{i}"),
+                    now(),
+                    "hello",
+                    "alice@example.com",
+                )),
+                insert(email(&format!("b-{i}"), now(), "hello", "bob@example.com")),
+            ])
+            .await;
+    }
+    index
+        .write(vec![record(
+            "REMOVE",
+            HashMap::new(),
+            HashMap::from([("id".to_string(), AttributeValue::String("a-0".to_string()))]),
+        )])
+        .await;
+
+    let result = index.optimize(1).await;
+    assert_eq!(result["partitions"][0]["segments_after"], 1);
+    assert_eq!(result["partitions"][0]["vacuumed_docs"], 1);
+
+    let response = index.read(json!({ "operation": "stats" })).await;
+    assert_eq!(response["segments"].as_array().unwrap().len(), 1);
+    assert_eq!(response["index_num_deleted_docs"], 0);
+    assert_eq!(index.search_ids("subject:hello").await.len(), 7);
+
+    // NOTE: The commit payload survives merges, so the stream position is kept
+    let response = index.read(json!({ "operation": "status" })).await;
+    assert_eq!(response["version"], "9");
+
+    let result = index.optimize(1).await;
+    assert!(result["partitions"].as_array().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn shared_readers_reload_in_the_background() {
+    let mut index = TestIndex::new(Partitioning::default());
+    let reader = Arc::new(
+        EmailIndexReader::new(schema(&index.ram_storage, index.partitioning))
+            .unwrap()
+            .with_reload_policy(ReloadPolicy::Interval(Duration::from_millis(20)))
+            .unwrap(),
+    );
+    EmailIndexReader::spawn_reloader(&reader);
+
+    index
+        .write(vec![
+            insert(email("a", now(), "hello", "alice@example.com")),
+            insert(email("b", now(), "hello", "bob@example.com")),
+        ])
+        .await;
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    let searches = (0..8).map(|_| {
+        let reader = reader.clone();
+        tokio::spawn(async move {
+            let request =
+                ReaderRequest::parse(r#"{ "query": "subject:hello", "mode": "count" }"#).unwrap();
+            serde_json::to_value(reader.handle(request).await.unwrap()).unwrap()
+        })
+    });
+    for search in searches {
+        assert_eq!(search.await.unwrap()["query_num_docs"], 2);
+    }
+
+    // NOTE: Reloads without a commit keep the searchers, and so the generation
+    let first = count(&reader, "subject:hello").await;
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let unchanged = count(&reader, "subject:hello").await;
+    assert_eq!(
+        unchanged["searcher_generation"],
+        first["searcher_generation"]
+    );
+
+    index
+        .write(vec![insert(email(
+            "c",
+            now(),
+            "hello",
+            "carol@example.com",
+        ))])
+        .await;
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let second = count(&reader, "subject:hello").await;
+    assert_eq!(second["query_num_docs"], 3);
+    assert_eq!(
+        second["searcher_generation"].as_u64().unwrap(),
+        first["searcher_generation"].as_u64().unwrap() + 1
+    );
+}
+
+#[tokio::test]
+async fn on_request_readers_see_every_commit() {
+    let mut index = TestIndex::new(Partitioning::default());
+    let reader = Arc::new(
+        EmailIndexReader::new(schema(&index.ram_storage, index.partitioning))
+            .unwrap()
+            .with_reload_policy(ReloadPolicy::OnRequest)
+            .unwrap(),
+    );
+
+    index
+        .write(vec![insert(email(
+            "a",
+            now(),
+            "hello",
+            "alice@example.com",
+        ))])
+        .await;
+    let first = count(&reader, "subject:hello").await;
+    assert_eq!(first["query_num_docs"], 1);
+
+    let unchanged = count(&reader, "subject:hello").await;
+    assert_eq!(
+        unchanged["searcher_generation"],
+        first["searcher_generation"]
+    );
+
+    index
+        .write(vec![insert(email("b", now(), "hello", "bob@example.com"))])
+        .await;
+    let second = count(&reader, "subject:hello").await;
+    assert_eq!(second["query_num_docs"], 2);
+    assert!(
+        second["searcher_generation"].as_u64().unwrap()
+            > first["searcher_generation"].as_u64().unwrap()
+    );
+}
+
+async fn count(reader: &Arc<EmailIndexReader>, query: &str) -> Value {
+    let request = ReaderRequest::parse(&json!({ "query": query, "mode": "count" }).to_string());
+    serde_json::to_value(reader.handle(request.unwrap()).await.unwrap()).unwrap()
+}
+
+async fn batch(reader: &Arc<EmailIndexReader>, requests: Value) -> Value {
+    let request =
+        ReaderRequest::parse(&json!({ "operation": "batch", "requests": requests }).to_string());
+    serde_json::to_value(reader.handle(request.unwrap()).await.unwrap()).unwrap()
+}
+
+fn hit_ids(response: &Value) -> Vec<&str> {
+    let mut ids: Vec<&str> = response["hits"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|hit| hit["id"].as_str().unwrap())
+        .collect();
+    ids.sort();
+    ids
+}
+
+#[tokio::test]
+async fn batches_run_every_query_against_one_snapshot() {
+    let mut index = TestIndex::new(Partitioning::default());
+    index
+        .write(vec![
+            insert(email("a", now(), "hello", "alice@example.com")),
+            insert(email("b", now(), "invoice", "bob@example.com")),
+        ])
+        .await;
+
+    let reader = Arc::new(
+        EmailIndexReader::new(schema(&index.ram_storage, index.partitioning))
+            .unwrap()
+            .with_reload_policy(ReloadPolicy::OnRequest)
+            .unwrap(),
+    );
+    let requests = json!([
+        { "query": "subject:hello", "mode": "ids" },
+        { "query": "subject:invoice", "mode": "ids" },
+        { "query": "*", "mode": "count" },
+        { "mode": "count" },
+    ]);
+
+    let response = batch(&reader, requests.clone()).await;
+    assert_eq!(response["error"], Value::Null);
+    assert_eq!(response["index_num_docs"], 2);
+    let responses = response["responses"].as_array().unwrap();
+    assert_eq!(hit_ids(&responses[0]), vec!["a"]);
+    assert_eq!(hit_ids(&responses[1]), vec!["b"]);
+    assert_eq!(responses[2]["query_num_docs"], 2);
+    // NOTE: A failing query doesn't fail the rest of the batch
+    assert_eq!(responses[3]["error"], "query is required");
+    let generation = responses[0]["searcher_generation"].clone();
+    assert!(responses[..3]
+        .iter()
+        .all(|response| response["searcher_generation"] == generation));
+
+    index
+        .write(vec![
+            insert(email("c", now(), "hello", "carol@example.com")),
+            record(
+                "REMOVE",
+                HashMap::new(),
+                email("b", now(), "invoice", "bob@example.com"),
+            ),
+        ])
+        .await;
+
+    let response = batch(&reader, requests).await;
+    assert_eq!(response["index_num_docs"], 2);
+    let responses = response["responses"].as_array().unwrap();
+    assert_eq!(hit_ids(&responses[0]), vec!["a", "c"]);
+    assert!(hit_ids(&responses[1]).is_empty());
+    assert_eq!(responses[2]["query_num_docs"], 2);
+    let next_generation = responses[0]["searcher_generation"].clone();
+    assert_ne!(next_generation, generation);
+    assert!(responses[..3]
+        .iter()
+        .all(|response| response["searcher_generation"] == next_generation));
+}
+
+#[tokio::test]
+async fn identical_searches_are_cached_until_the_next_commit() {
+    let mut index = TestIndex::new(Partitioning::default());
+    index
+        .write(vec![insert(email(
+            "a",
+            now(),
+            "hello",
+            "alice@example.com",
+        ))])
+        .await;
+    let reader = Arc::new(
+        EmailIndexReader::new(schema(&index.ram_storage, index.partitioning))
+            .unwrap()
+            .with_reload_policy(ReloadPolicy::OnRequest)
+            .unwrap(),
+    );
+
+    let first = count(&reader, "subject:hello").await;
+    assert_eq!(first["cached"], false);
+
+    let second = count(&reader, "  subject:hello ").await;
+    assert_eq!(second["cached"], true);
+    assert_eq!(second["query_num_docs"], 1);
+
+    index
+        .write(vec![insert(email("b", now(), "hello", "bob@example.com"))])
+        .await;
+    let third = count(&reader, "subject:hello").await;
+    assert_eq!(third["cached"], false);
+    assert_eq!(third["query_num_docs"], 2);
+
+    let request = ReaderRequest::parse(r#"{ "operation": "stats" }"#).unwrap();
+    let stats = serde_json::to_value(reader.handle(request).await.unwrap()).unwrap();
+    assert_eq!(stats["query_cache"]["hits"], 1);
+    assert_eq!(stats["query_cache"]["misses"], 2);
+}
+
+#[tokio::test]
+async fn interval_reloads_without_a_commit_keep_cached_searches() {
+    let mut index = TestIndex::new(Partitioning::default());
+    index
+        .write(vec![insert(email(
+            "a",
+            now(),
+            "hello",
+            "alice@example.com",
+        ))])
+        .await;
+    let reader = Arc::new(
+        EmailIndexReader::new(schema(&index.ram_storage, index.partitioning))
+            .unwrap()
+            .with_reload_policy(ReloadPolicy::Interval(Duration::from_millis(20)))
+            .unwrap(),
+    );
+    EmailIndexReader::spawn_reloader(&reader);
+
+    assert_eq!(count(&reader, "subject:hello").await["cached"], false);
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert_eq!(count(&reader, "subject:hello").await["cached"], true);
+
+    index
+        .write(vec![insert(email("b", now(), "hello", "bob@example.com"))])
+        .await;
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let response = count(&reader, "subject:hello").await;
+    assert_eq!(response["cached"], false);
+    assert_eq!(response["query_num_docs"], 2);
+}
+
+#[tokio::test]
+async fn function_url_requests_get_http_responses() {
+    let mut index = TestIndex::new(Partitioning::default());
+    index
+        .write(vec![insert(email(
+            "a",
+            now(),
+            "hello",
+            "alice@example.com",
+        ))])
+        .await;
+    let reader =
+        Arc::new(EmailIndexReader::new(schema(&index.ram_storage, index.partitioning)).unwrap());
+    let http = |event: Value| {
+        let request = HttpRequest::from_function_url(serde_json::from_value(event).unwrap());
+        let reader = &reader;
+        async move { reader.handle_http(&request).await }
+    };
+
+    let response = http(json!({
+        "requestContext": { "requestId": "get", "http": { "method": "GET" } },
+        "queryStringParameters": { "query": "subject:hello", "mode": "ids", "limit": "5" },
+        "isBase64Encoded": false
+    }))
+    .await;
+    assert_eq!(response.status_code, 200);
+    assert_eq!(response.headers["content-type"], "application/json");
+    assert_eq!(response.headers["x-request-id"], "get");
+    let body: Value = serde_json::from_str(&response.body).unwrap();
+    assert_eq!(body["hits"][0]["id"], "a");
+
+    let response = http(json!({
+        "requestContext": { "http": { "method": "POST" } },
+        "headers": { "Content-Type": "application/json" },
+        "body": base64::encode(r#"{ "query": "subject:hello", "mode": "count" }"#),
+        "isBase64Encoded": true
+    }))
+    .await;
+    assert_eq!(response.status_code, 200);
+    let body: Value = serde_json::from_str(&response.body).unwrap();
+    assert_eq!(body["query_num_docs"], 1);
+
+    let response = http(json!({
+        "requestContext": { "http": { "method": "POST" } },
+        "body": r#"{ "query": "subject:(hello" }"#
+    }))
+    .await;
+    assert_eq!(response.status_code, 400);
+
+    // NOTE: An index that is behind is the server's problem, so the client is told to retry
+    let response = http(json!({
+        "requestContext": { "http": { "method": "GET" } },
+        "queryStringParameters": { "query": "subject:hello", "mode": "ids", "min_version": "1:a" }
+    }))
+    .await;
+    assert_eq!(response.status_code, 503);
+    assert_eq!(response.headers["retry-after"], "1");
+
+    let response = http(json!({
+        "requestContext": { "http": { "method": "POST" } },
+        "body": "not json"
+    }))
+    .await;
+    assert_eq!(response.status_code, 400);
+
+    let response = http(json!({ "requestContext": { "http": { "method": "DELETE" } } })).await;
+    assert_eq!(response.status_code, 405);
+    assert_eq!(response.headers["allow"], "GET, POST");
+
+    let response = http(json!({
+        "requestContext": { "http": { "method": "GET" } },
+        "headers": { "accept": "text/html" },
+        "queryStringParameters": { "query": "subject:hello" }
+    }))
+    .await;
+    assert_eq!(response.status_code, 406);
+
+    let response = http(json!({
+        "requestContext": { "requestId": "full", "http": { "method": "GET" } },
+        "queryStringParameters": { "query": "subject:hello" }
+    }))
+    .await;
+    assert_eq!(response.status_code, 500);
+    let body: Value = serde_json::from_str(&response.body).unwrap();
+    assert_eq!(body["request_id"], "full");
+}
+
+fn event(name: &str) -> Value {
+    let path = format!("{}/tests/events/{name}.json", env!("CARGO_MANIFEST_DIR"));
+    serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap()
+}
+
+#[tokio::test]
+async fn kinesis_and_sqs_events_are_indexed() {
+    let index = TestIndex::new(Partitioning::default());
+
+    let request = WriterRequest::parse(event("kinesis"), None).await.unwrap();
+    let result = index.writer.handle(request).await.unwrap();
+    assert_eq!(result["created"], 2);
+    // NOTE: Kinesis sequence numbers don't compare with DynamoDB ones, so only the creation time advances
+    assert_eq!(result["sequence_number"], Value::Null);
+    assert_eq!(result["indexed_through"], 1649822460);
+    assert_eq!(
+        index.search_ids("subject:kinesis").await,
+        vec!["k-1", "k-2"]
+    );
+
+    let request = WriterRequest::parse(event("sqs"), None).await.unwrap();
+    let result = index.writer.handle(request).await.unwrap();
+    assert_eq!(result["created"], 2);
+    assert_eq!(
+        index.search_ids("subject:replayed").await,
+        vec!["s-1", "s-2"]
+    );
+}
+
+#[tokio::test]
+async fn failed_stream_batches_require_a_streams_client() {
+    let index = TestIndex::new(Partitioning::default());
+
+    let request = WriterRequest::parse(event("sqs_stream_failure"), None)
+        .await
+        .unwrap();
+    let result = index.writer.handle(request).await.unwrap();
+    assert_eq!(result["created"], 0);
+    assert_eq!(
+        result["batchItemFailures"],
+        json!([{ "itemIdentifier": "a8f5f167-f44f-4964-9fc8-3c0b2e8d5f11" }])
+    );
+}
+
+#[tokio::test]
+async fn sqs_messages_after_a_failed_one_are_retried() {
+    let index = TestIndex::new(Partitioning::default());
+
+    let mut sqs_event = event("sqs");
+    let records = sqs_event["Records"].as_array_mut().unwrap();
+    let mut invalid = records[0].clone();
+    invalid["messageId"] = json!("invalid");
+    invalid["body"] = json!("not json");
+    records.insert(1, invalid);
+
+    let request = WriterRequest::parse(sqs_event, None).await.unwrap();
+    let result = index.writer.handle(request).await.unwrap();
+    assert_eq!(result["created"], 1);
+    assert_eq!(
+        result["batchItemFailures"],
+        json!([
+            { "itemIdentifier": "invalid" },
+            { "itemIdentifier": "2e1424d4-f796-459a-8184-9c92662be6da" }
+        ])
+    );
+    assert_eq!(index.search_ids("subject:replayed").await, vec!["s-1"]);
+}
@@ -0,0 +1,110 @@
+use dynamodb_email_indexer::http_event::{HttpEvent, HttpEventSource};
+use dynamodb_email_indexer::http_response::HttpResponse;
+use dynamodb_email_indexer::reader_request::ReaderRequest;
+use dynamodb_email_indexer::search_request::{SearchMode, SearchRequest};
+use serde_json::{json, Value};
+
+fn parse(name: &str) -> HttpEvent {
+    let path = format!("{}/tests/events/{name}.json", env!("CARGO_MANIFEST_DIR"));
+    let event: Value = serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap();
+
+    HttpEvent::parse(event).unwrap()
+}
+
+fn search_request(http_event: &HttpEvent) -> SearchRequest {
+    match http_event.request.reader_request() {
+        Ok(ReaderRequest::Search(request)) => request,
+        _ => panic!("expected a search request"),
+    }
+}
+
+#[test]
+fn function_url_events_are_detected() {
+    let http_event = parse("function_url");
+    assert_eq!(http_event.source, HttpEventSource::FunctionUrl);
+    assert_eq!(
+        http_event.request.request_id.as_deref(),
+        Some("1b0e8f6c-3a52-4a77-9b3e-5d0a8c1e2f34")
+    );
+
+    let request = search_request(&http_event);
+    assert_eq!(request.query.as_deref(), Some("subject:hello"));
+    assert_eq!(request.mode, Some(SearchMode::Ids));
+    let min_version = request.min_version.unwrap();
+    assert_eq!(min_version.id, "a:1");
+    assert_eq!(min_version.version, Some(3));
+
+    let response = http_event.response(HttpResponse::error(400, "invalid query"));
+    assert_eq!(response["statusCode"], 400);
+    assert_eq!(response["headers"]["content-type"], "application/json");
+    assert_eq!(response["body"], r#"{"error":"invalid query"}"#);
+}
+
+#[test]
+fn api_gateway_rest_events_are_detected() {
+    let http_event = parse("api_gateway_rest");
+    assert_eq!(http_event.source, HttpEventSource::ApiGateway);
+    assert_eq!(http_event.request.method, "POST");
+    assert_eq!(
+        http_event.request.request_id.as_deref(),
+        Some("c6af9ac6-7b61-11e6-9a41-93e8deadbeef")
+    );
+
+    let request = search_request(&http_event);
+    assert_eq!(request.query.as_deref(), Some("subject:hello"));
+    assert_eq!(request.mode, Some(SearchMode::Count));
+
+    let response = http_event.response(HttpResponse::json(200, &json!({ "hits": [] })));
+    assert_eq!(response["statusCode"], 200);
+    assert_eq!(response["isBase64Encoded"], false);
+    assert!(response.get("statusDescription").is_none());
+}
+
+#[test]
+fn alb_events_are_detected_and_url_decoded() {
+    let http_event = parse("alb");
+    assert_eq!(
+        http_event.source,
+        HttpEventSource::Alb {
+            multi_value_headers: false
+        }
+    );
+    assert_eq!(
+        http_event.request.request_id.as_deref(),
+        Some("Root=1-62564a1c-7f0d7a6c4f2e5d4b1a0c9e8f")
+    );
+
+    let request = search_request(&http_event);
+    assert_eq!(request.query.as_deref(), Some("subject:(hello OR lunch)"));
+    assert_eq!(request.limit, Some(5));
+
+    let response = http_event.response(HttpResponse::json(200, &json!({})));
+    assert_eq!(response["statusDescription"], "200 OK");
+    assert_eq!(response["headers"]["content-type"], "application/json");
+}
+
+#[test]
+fn alb_multi_value_events_get_multi_value_responses() {
+    let http_event = parse("alb_multi_value");
+    assert_eq!(
+        http_event.source,
+        HttpEventSource::Alb {
+            multi_value_headers: true
+        }
+    );
+
+    let response = match http_event.request.reader_request() {
+        Err(response) => http_event.response(response),
+        Ok(_) => panic!("expected PUT to be rejected"),
+    };
+    assert_eq!(response["statusCode"], 405);
+    assert_eq!(response["statusDescription"], "405 Method Not Allowed");
+    assert_eq!(response["multiValueHeaders"]["allow"], json!(["GET, POST"]));
+    assert!(response.get("headers").is_none());
+}
+
+#[test]
+fn other_events_are_rejected() {
+    let event = json!({ "Records": [{ "eventSource": "aws:sqs" }] });
+    assert!(HttpEvent::parse(event).is_err());
+}
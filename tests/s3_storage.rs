@@ -0,0 +1,308 @@
+use anyhow::Result;
+use aws_lambda_events::dynamodb::{
+    attributes::AttributeValue, Event, EventRecord, StreamRecord, StreamViewType,
+};
+use dynamodb_email_indexer::email_index_reader::EmailIndexReader;
+use dynamodb_email_indexer::email_index_schema::EmailIndexSchema;
+use dynamodb_email_indexer::email_index_writer::EmailIndexWriter;
+use dynamodb_email_indexer::index_partitioning::{fnv1a, PartitionInterval, Partitioning};
+use dynamodb_email_indexer::index_storage::IndexStorage;
+use dynamodb_email_indexer::lock_table::LockTable;
+use dynamodb_email_indexer::object_store::{ObjectMeta, ObjectStore};
+use dynamodb_email_indexer::reader_request::ReaderRequest;
+use dynamodb_email_indexer::segment_cache::SegmentCache;
+use dynamodb_email_indexer::writer_request::WriterRequest;
+use serde_json::Value;
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tantivy::{
+    chrono::Utc, directory::error::LockError, doc, merge_policy::NoMergePolicy, TantivyError,
+};
+
+/// An in memory stand-in for an S3 bucket.
+#[derive(Default, Debug)]
+struct MemoryStore {
+    objects: Mutex<BTreeMap<String, Vec<u8>>>,
+}
+
+impl ObjectStore for MemoryStore {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        Ok(self.objects.lock().unwrap().get(key).cloned())
+    }
+
+    fn head(&self, key: &str) -> Result<Option<ObjectMeta>> {
+        Ok(self
+            .objects
+            .lock()
+            .unwrap()
+            .get(key)
+            .map(|data| ObjectMeta {
+                key: key.to_string(),
+                size: data.len() as u64,
+                etag: Some(fnv1a(data).to_string()),
+            }))
+    }
+
+    fn put(&self, key: &str, data: Vec<u8>) -> Result<()> {
+        self.objects.lock().unwrap().insert(key.to_string(), data);
+        Ok(())
+    }
+
+    fn delete(&self, key: &str) -> Result<()> {
+        self.objects.lock().unwrap().remove(key);
+        Ok(())
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<ObjectMeta>> {
+        let objects = self.objects.lock().unwrap();
+        Ok(objects
+            .iter()
+            .filter(|(key, _)| key.starts_with(prefix))
+            .map(|(key, data)| ObjectMeta {
+                key: key.clone(),
+                size: data.len() as u64,
+                etag: Some(fnv1a(data).to_string()),
+            })
+            .collect())
+    }
+
+    fn url(&self, key: &str) -> String {
+        format!("memory://{key}")
+    }
+}
+
+/// An in memory stand-in for the DynamoDB lock table.
+#[derive(Default, Debug)]
+struct MemoryLockTable {
+    leases: Mutex<HashMap<String, (String, Instant)>>,
+}
+
+impl LockTable for MemoryLockTable {
+    fn try_acquire(&self, key: &str, owner: &str, lease: Duration) -> Result<bool> {
+        let mut leases = self.leases.lock().unwrap();
+        match leases.get(key) {
+            Some((lease_owner, expires_at))
+                if lease_owner != owner && *expires_at > Instant::now() =>
+            {
+                Ok(false)
+            }
+            _ => {
+                leases.insert(key.to_string(), (owner.to_string(), Instant::now() + lease));
+                Ok(true)
+            }
+        }
+    }
+
+    fn release(&self, key: &str, owner: &str) -> Result<()> {
+        let mut leases = self.leases.lock().unwrap();
+        if leases
+            .get(key)
+            .is_some_and(|(lease_owner, _)| lease_owner == owner)
+        {
+            leases.remove(key);
+        }
+        Ok(())
+    }
+}
+
+/// The store and lock table shared by every "process", each with a cache of its own.
+#[derive(Default)]
+struct TestBucket {
+    store: Arc<MemoryStore>,
+    lock_table: Arc<MemoryLockTable>,
+}
+
+impl TestBucket {
+    fn storage(&self, cache_path: &Path, max_bytes: u64) -> IndexStorage {
+        IndexStorage::S3 {
+            store: self.store.clone(),
+            lock_table: Some(self.lock_table.clone()),
+            cache: Arc::new(SegmentCache::new(cache_path, max_bytes).unwrap()),
+        }
+    }
+}
+
+fn temp_dir() -> PathBuf {
+    std::env::temp_dir().join(format!("s3-storage-{}", ulid::Ulid::new()))
+}
+
+fn insert(id: &str, sequence_number: u64) -> EventRecord {
+    let new_image = HashMap::from([
+        ("id".to_string(), AttributeValue::String(id.to_string())),
+        (
+            "timestamp".to_string(),
+            AttributeValue::String(Utc::now().timestamp().to_string()),
+        ),
+        (
+            "subject".to_string(),
+            AttributeValue::String("hello".to_string()),
+        ),
+        (
+            "body".to_string(),
+            AttributeValue::String("body of hello".to_string()),
+        ),
+        (
+            "from".to_string(),
+            AttributeValue::String("Sender <sender@example.com>".to_string()),
+        ),
+        (
+            "to".to_string(),
+            AttributeValue::StringSet(vec!["alice@example.com".to_string()]),
+        ),
+    ]);
+
+    EventRecord {
+        aws_region: "us-east-1".to_string(),
+        change: StreamRecord {
+            approximate_creation_date_time: Utc::now(),
+            keys: HashMap::new(),
+            new_image,
+            old_image: HashMap::new(),
+            sequence_number: Some(sequence_number.to_string()),
+            size_bytes: 0,
+            stream_view_type: Some(StreamViewType::NewAndOldImages),
+        },
+        event_id: "test".to_string(),
+        event_name: "INSERT".to_string(),
+        event_source: "aws:dynamodb".to_string(),
+        event_version: "1.1".to_string(),
+        event_source_arn: "test".to_string(),
+        user_identity: None,
+    }
+}
+
+async fn count(email_index_schema: EmailIndexSchema) -> Value {
+    let reader = Arc::new(EmailIndexReader::new(email_index_schema).unwrap());
+    let request = ReaderRequest::parse(r#"{ "query": "subject:hello", "mode": "count" }"#).unwrap();
+    let response = serde_json::to_value(reader.handle(request).await.unwrap()).unwrap();
+
+    response["query_num_docs"].clone()
+}
+
+#[tokio::test]
+async fn partitioned_indexes_round_trip_through_the_store() {
+    let bucket = TestBucket::default();
+    let cache_path = temp_dir();
+    let partitioning = Partitioning {
+        interval: Some(PartitionInterval::Month),
+        ..Partitioning::default()
+    };
+    let schema = |name: &str| {
+        EmailIndexSchema::new()
+            .with_partitioning(partitioning)
+            .with_storage(bucket.storage(&cache_path.join(name), u64::MAX))
+    };
+
+    let writer = EmailIndexWriter::new(schema("writer")).unwrap();
+    let records = vec![insert("a", 1), insert("b", 2)];
+    writer
+        .handle(WriterRequest::Stream(Event { records }))
+        .await
+        .unwrap();
+
+    // NOTE: A separate cache makes the reader download every segment, like a cold lambda would
+    assert_eq!(count(schema("reader")).await, 2);
+    let keys = schema("reader").partition_keys().unwrap();
+    assert_eq!(keys.len(), 1);
+    // NOTE: The admin tool lists the files of a partition to find the ones no commit refers to
+    let files = schema("reader").partition_files(&keys[0]).unwrap();
+    assert!(files.contains(&PathBuf::from("meta.json")));
+    assert!(files
+        .iter()
+        .any(|file| file.extension().is_some_and(|ext| ext == "idx")));
+    assert!(bucket.store.head("checkpoint.json").unwrap().is_some());
+
+    // NOTE: The writer's lease is given up once the batch is committed
+    assert!(bucket.lock_table.leases.lock().unwrap().is_empty());
+
+    std::fs::remove_dir_all(&cache_path).unwrap();
+}
+
+#[test]
+fn writers_in_different_processes_exclude_each_other() {
+    let bucket = TestBucket::default();
+    let cache_path = temp_dir();
+    let first = EmailIndexSchema::new().with_storage(bucket.storage(&cache_path.join("first"), 0));
+    let second =
+        EmailIndexSchema::new().with_storage(bucket.storage(&cache_path.join("second"), 0));
+
+    let index_writer = first.ensure_index().unwrap().writer(50_000_000).unwrap();
+    let index = second.ensure_index().unwrap();
+    assert!(matches!(
+        index.writer(50_000_000),
+        Err(TantivyError::LockFailure(LockError::LockBusy, _))
+    ));
+
+    drop(index_writer);
+    assert!(index.writer(50_000_000).is_ok());
+
+    // NOTE: Without a lock table there is no way to exclude other writers, so writing is refused
+    let unlocked = EmailIndexSchema::new().with_storage(IndexStorage::S3 {
+        store: bucket.store.clone(),
+        lock_table: None,
+        cache: Arc::new(SegmentCache::new(&cache_path.join("unlocked"), 0).unwrap()),
+    });
+    assert!(unlocked.ensure_index().unwrap().writer(50_000_000).is_err());
+
+    std::fs::remove_dir_all(&cache_path).unwrap();
+}
+
+#[tokio::test]
+async fn the_segment_cache_stays_under_its_limit() {
+    let bucket = TestBucket::default();
+    let cache_path = temp_dir();
+
+    let email_index_schema =
+        EmailIndexSchema::new().with_storage(bucket.storage(&cache_path.join("writer"), u64::MAX));
+    let fields = email_index_schema.fields.clone();
+    let index = email_index_schema.ensure_index().unwrap();
+    let mut index_writer = index.writer(50_000_000).unwrap();
+    index_writer.set_merge_policy(Box::new(NoMergePolicy));
+    for i in 0..5 {
+        index_writer
+            .add_document(doc!(
+                fields.id => format!("{i}"),
+                fields.timestamp => Utc::now().timestamp(),
+                fields.subject => "hello",
+            ))
+            .unwrap();
+        index_writer.commit().unwrap();
+    }
+    drop(index_writer);
+
+    let objects = bucket.store.list("index/").unwrap();
+    let total_bytes: u64 = objects.iter().map(|object| object.size).sum();
+    let largest_bytes = objects.iter().map(|object| object.size).max().unwrap();
+    let max_bytes = largest_bytes.max(total_bytes / 3);
+    assert!(max_bytes < total_bytes);
+
+    let storage = bucket.storage(&cache_path.join("reader"), max_bytes);
+    let cache = match &storage {
+        IndexStorage::S3 { cache, .. } => cache.clone(),
+        _ => unreachable!(),
+    };
+    let email_index_schema = EmailIndexSchema::new().with_storage(storage);
+
+    // NOTE: Evicted files stay readable by the searchers that have them open, and are downloaded again
+    // by the next reader
+    assert_eq!(count(email_index_schema.clone()).await, 5);
+    assert!(cache.size_bytes() <= max_bytes);
+    assert_eq!(count(email_index_schema).await, 5);
+    assert!(cache.size_bytes() <= max_bytes);
+
+    let cached_bytes: u64 = std::fs::read_dir(cache_path.join("reader/index"))
+        .unwrap()
+        .map(|entry| entry.unwrap().metadata().unwrap().len())
+        .sum();
+    assert!(cached_bytes <= max_bytes);
+
+    // NOTE: A new process in the same sandbox picks up the files that are still cached
+    let cache = SegmentCache::new(&cache_path.join("reader"), max_bytes).unwrap();
+    assert_eq!(cache.size_bytes(), cached_bytes);
+
+    std::fs::remove_dir_all(&cache_path).unwrap();
+}